@@ -0,0 +1,500 @@
+use std::{collections::{BTreeSet, HashMap, HashSet}, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::grammar::*;
+
+
+/// static properties of every production which bound what a term can match
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Analysis {
+    /// first production for every lhs, the one the engines use
+    lookup: HashMap<String, usize>,
+    /// shortest yield in tokens, `usize::MAX` if the production derives no terminal string
+    pub min_len: Vec<usize>,
+    /// longest yield in tokens, `usize::MAX` if unbounded
+    pub max_len: Vec<usize>,
+    /// terminals a yield can start with
+    pub first: Vec<HashSet<String>>,
+    /// terminals a yield can end with
+    pub last: Vec<HashSet<String>>,
+}
+
+impl Analysis {
+    pub fn new(grammar: &Grammar) -> Self {
+        let mut lookup = HashMap::new();
+        for (i, p) in grammar.productions.iter().enumerate() {
+            lookup.entry(p.lhs.clone()).or_insert(i);
+        }
+        let len = grammar.productions.len();
+        let mut result = Self {
+            lookup,
+            min_len: vec![usize::MAX; len],
+            max_len: vec![0; len],
+            first: vec![HashSet::new(); len],
+            last: vec![HashSet::new(); len],
+        };
+        result.compute_min_len(grammar);
+        result.compute_max_len(grammar);
+        result.compute_edges(grammar);
+        result
+    }
+
+    pub fn production(&self, name: &str) -> Option<usize> {
+        self.lookup.get(name).copied()
+    }
+
+    /// shortest and longest yield of `term`, `usize::MAX` meaning none and unbounded respectively
+    pub fn bounds(&self, term: &Term) -> (usize, usize) {
+        match term {
+            Term::Terminal(_) => (1, 1),
+            Term::Nonterminal(n) => match self.production(n) {
+                Some(i) => (self.min_len[i], self.max_len[i]),
+                None => (usize::MAX, 0),
+            },
+        }
+    }
+
+    pub fn is_nullable(&self, term: &Term) -> bool {
+        self.bounds(term).0 == 0
+    }
+
+    /// `term` can derive a string starting with the terminal `name`
+    pub fn can_start(&self, term: &Term, name: &str) -> bool {
+        match term {
+            Term::Terminal(t) => t == name,
+            Term::Nonterminal(n) => self.production(n).is_some_and(|i| self.first[i].contains(name)),
+        }
+    }
+
+    /// `term` can derive a string ending with the terminal `name`
+    pub fn can_end(&self, term: &Term, name: &str) -> bool {
+        match term {
+            Term::Terminal(t) => t == name,
+            Term::Nonterminal(n) => self.production(n).is_some_and(|i| self.last[i].contains(name)),
+        }
+    }
+
+    /// every term of the expression derives some terminal string
+    fn is_productive(&self, expression: &Expression) -> bool {
+        expression.terms.iter().all(|t| self.bounds(t).0 != usize::MAX)
+    }
+
+    fn compute_min_len(&mut self, grammar: &Grammar) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, p) in grammar.productions.iter().enumerate() {
+                let min = p.rhs
+                    .iter()
+                    .map(|e| e.terms.iter().fold(0usize, |sum, t| sum.saturating_add(self.bounds(t).0)))
+                    .min()
+                    .unwrap_or(usize::MAX);
+                if min < self.min_len[i] {
+                    self.min_len[i] = min;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    /// longest yields only grow, so a value still growing after every finite one has settled is unbounded
+    fn compute_max_len(&mut self, grammar: &Grammar) {
+        let len = grammar.productions.len();
+        let mut round = 0;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            round += 1;
+            for (i, p) in grammar.productions.iter().enumerate() {
+                if self.min_len[i] == usize::MAX || self.max_len[i] == usize::MAX {
+                    continue;
+                }
+                let max = p.rhs
+                    .iter()
+                    .filter(|e| self.is_productive(e))
+                    .map(|e| e.terms.iter().fold(0usize, |sum, t| sum.saturating_add(self.bounds(t).1)))
+                    .max()
+                    .unwrap_or(0);
+                if max > self.max_len[i] {
+                    // a derivation without repeated nonterminals is at most `len` levels deep
+                    self.max_len[i] = if round > len { usize::MAX } else { max };
+                    changed = true;
+                }
+            }
+        }
+        for i in 0..len {
+            if self.min_len[i] == usize::MAX {
+                self.max_len[i] = 0;
+            }
+        }
+    }
+
+    fn compute_edges(&mut self, grammar: &Grammar) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, p) in grammar.productions.iter().enumerate() {
+                let productive: Vec<_> = p.rhs.iter().filter(|e| self.is_productive(e)).collect();
+                for e in productive {
+                    let first = self.edge(e.terms.iter(), |s, i| &s.first[i]);
+                    let last = self.edge(e.terms.iter().rev(), |s, i| &s.last[i]);
+                    for t in first {
+                        changed |= self.first[i].insert(t);
+                    }
+                    for t in last {
+                        changed |= self.last[i].insert(t);
+                    }
+                }
+            }
+        }
+    }
+
+    /// terminals of the terms up to and including the first non nullable one
+    fn edge<'a>(&self, terms: impl Iterator<Item = &'a Term>, set: impl Fn(&Self, usize) -> &HashSet<String>) -> Vec<String> {
+        let mut result = vec![];
+        for t in terms {
+            match t {
+                Term::Terminal(t) => result.push(t.clone()),
+                Term::Nonterminal(n) => if let Some(j) = self.production(n) {
+                    result.extend(set(self, j).iter().cloned());
+                },
+            }
+            if !self.is_nullable(t) {
+                break;
+            }
+        }
+        result
+    }
+}
+
+
+/// FIRST, FOLLOW and nullable sets of a nonterminal
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NonterminalSets {
+    pub name: String,
+    /// derives the empty string
+    pub nullable: bool,
+    /// terminals a derived string can start with
+    pub first: BTreeSet<String>,
+    /// terminals which can come right after it
+    pub follow: BTreeSet<String>,
+    /// it can end the input, `$` in FOLLOW
+    pub at_end: bool,
+}
+
+/// sets of every nonterminal in the order of the productions, see `Grammar::sets`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GrammarSets {
+    pub start: Option<String>,
+    pub nonterminals: Vec<NonterminalSets>,
+}
+
+impl GrammarSets {
+    pub fn get(&self, name: &str) -> Option<&NonterminalSets> {
+        self.nonterminals.iter().find(|n| n.name == name)
+    }
+}
+
+fn format_set<'a>(terminals: impl Iterator<Item = &'a String>) -> String {
+    terminals.map(|t| format!("\"{}\"", t)).collect::<Vec<_>>().join(" ")
+}
+
+impl Display for GrammarSets {
+    /// a table with a row per nonterminal
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let header = [ "nonterminal", "nullable", "first", "follow" ].map(String::from);
+        let rows: Vec<[String; 4]> = self.nonterminals.iter().map(|n| [
+            format!("<{}>", n.name),
+            if n.nullable { "yes" } else { "no" }.to_string(),
+            format_set(n.first.iter()),
+            format_set(n.follow.iter()) + if n.at_end { if n.follow.is_empty() { "$" } else { " $" } } else { "" },
+        ]).collect();
+        let mut widths = header.clone().map(|h| h.chars().count());
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        for (i, row) in std::iter::once(&header).chain(&rows).enumerate() {
+            let line = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join(" | ");
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            f.write_str(line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// a rhs symbol which may be optional
+type Symbol<'a> = (&'a Term, bool);
+
+/// first production for every lhs with its expressions as symbols
+struct Rules<'a> {
+    names: Vec<&'a str>,
+    lookup: HashMap<&'a str, usize>,
+    rhs: Vec<Vec<Vec<Symbol<'a>>>>,
+}
+
+impl<'a> Rules<'a> {
+    fn new(productions: impl Iterator<Item = (&'a str, Vec<Vec<Symbol<'a>>>)>) -> Self {
+        let mut result = Rules { names: vec![], lookup: HashMap::new(), rhs: vec![] };
+        for (lhs, rhs) in productions {
+            if !result.lookup.contains_key(lhs) {
+                result.lookup.insert(lhs, result.names.len());
+                result.names.push(lhs);
+                result.rhs.push(rhs);
+            }
+        }
+        result
+    }
+
+    fn sets(&self, start: Option<&str>) -> GrammarSets {
+        let len = self.names.len();
+        let index = |term: &Term| match term {
+            Term::Terminal(_) => None,
+            Term::Nonterminal(n) => self.lookup.get(n.as_str()).copied(),
+        };
+
+        let mut nullable = vec![false; len];
+        let is_nullable = |nullable: &[bool], (term, optional): &Symbol| *optional || index(term).is_some_and(|i| nullable[i]);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in 0..len {
+                if !nullable[i] && self.rhs[i].iter().any(|e| e.iter().all(|s| is_nullable(&nullable, s))) {
+                    nullable[i] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut first = vec![BTreeSet::<String>::new(); len];
+        let first_of = |first: &[BTreeSet<String>], symbols: &[Symbol]| {
+            let mut result = BTreeSet::new();
+            for symbol in symbols {
+                match (symbol.0, index(symbol.0)) {
+                    (Term::Terminal(t), _) => { result.insert(t.clone()); },
+                    (_, Some(j)) => result.extend(first[j].iter().cloned()),
+                    (_, None) => {},
+                }
+                if !is_nullable(&nullable, symbol) {
+                    return (result, false)
+                }
+            }
+            (result, true)
+        };
+        changed = true;
+        while changed {
+            changed = false;
+            for i in 0..len {
+                for e in &self.rhs[i] {
+                    let (terminals, _) = first_of(&first, e);
+                    for t in terminals {
+                        changed |= first[i].insert(t);
+                    }
+                }
+            }
+        }
+
+        let mut follow = vec![BTreeSet::<String>::new(); len];
+        let mut at_end = vec![false; len];
+        if let Some(&start) = start.and_then(|s| self.lookup.get(s)) {
+            at_end[start] = true;
+        }
+        changed = true;
+        while changed {
+            changed = false;
+            for i in 0..len {
+                for e in &self.rhs[i] {
+                    for (k, symbol) in e.iter().enumerate() {
+                        let Some(j) = index(symbol.0) else { continue };
+                        let (terminals, rest_nullable) = first_of(&first, &e[k + 1..]);
+                        for t in terminals {
+                            changed |= follow[j].insert(t);
+                        }
+                        if rest_nullable {
+                            let inherited: Vec<_> = follow[i].iter().cloned().collect();
+                            for t in inherited {
+                                changed |= follow[j].insert(t);
+                            }
+                            if at_end[i] && !at_end[j] {
+                                at_end[j] = true;
+                                changed = true;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        GrammarSets {
+            start: start.map(String::from),
+            nonterminals: (0..len).map(|i| NonterminalSets {
+                name: self.names[i].to_string(),
+                nullable: nullable[i],
+                first: std::mem::take(&mut first[i]),
+                follow: std::mem::take(&mut follow[i]),
+                at_end: at_end[i],
+            }).collect(),
+        }
+    }
+}
+
+impl Grammar {
+    /// FIRST, FOLLOW and nullable sets of every nonterminal, the first production being the start one
+    pub fn sets(&self) -> GrammarSets {
+        self.sets_of(self.productions.first().map(|p| p.lhs.as_str()))
+    }
+
+    /// same as `sets` with FOLLOW computed for the input parsed as `start`
+    pub fn sets_from(&self, start: &str) -> GrammarSets {
+        self.sets_of(Some(start))
+    }
+
+    fn sets_of(&self, start: Option<&str>) -> GrammarSets {
+        Rules::new(self.productions.iter().map(|p| (
+            p.lhs.as_str(),
+            p.rhs.iter().map(|e| e.terms.iter().map(|t| (t, false)).collect()).collect(),
+        ))).sets(start)
+    }
+}
+
+impl ExtGrammar {
+    /// same as `Grammar::sets` without flattening, optional terms are nullable
+    pub fn sets(&self) -> GrammarSets {
+        self.sets_of(self.productions.first().map(|p| p.lhs.as_str()))
+    }
+
+    pub fn sets_from(&self, start: &str) -> GrammarSets {
+        self.sets_of(Some(start))
+    }
+
+    fn sets_of(&self, start: Option<&str>) -> GrammarSets {
+        Rules::new(self.productions.iter().map(|p| (
+            p.lhs.as_str(),
+            p.rhs.iter().map(|e| e.terms.iter().map(|t| (&t.term, t.is_optional)).collect()).collect(),
+        ))).sets(start)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use trim_margin::MarginTrimmable;
+
+    use crate::grammar::{ExtGrammar, Grammar, Optionals};
+
+    use super::{Analysis, GrammarSets};
+
+    fn grammar(text: &str) -> Grammar {
+        let g: ExtGrammar = text.try_into().unwrap();
+        g.flatten_with(Optionals::Nullable)
+    }
+
+    fn set<S: FromIterator<String>>(names: &[&str]) -> S {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn len_test() {
+        let g = grammar(r#"
+            <s>    ::= <pair> | <list>
+            <pair> ::= "(" <atom> "," <atom>? ")"
+            <atom> ::= "a" | "b" "c"
+            <list> ::= "x" | "x" <list>
+            <loop> ::= <loop> "y"
+            <unit> ::= <unit> | "u"
+        "#);
+        let a = Analysis::new(&g);
+        let len = |name: &str| {
+            let i = a.production(name).unwrap();
+            (a.min_len[i], a.max_len[i])
+        };
+        assert_eq!(len("s"), (1, usize::MAX));
+        assert_eq!(len("pair"), (4, 7));
+        assert_eq!(len("atom"), (1, 2));
+        assert_eq!(len("list"), (1, usize::MAX));
+        assert_eq!(len("loop"), (usize::MAX, 0));
+        assert_eq!(len("unit"), (1, 1));
+        assert_eq!(len("atom?"), (0, 2));
+    }
+
+    #[test]
+    fn edge_test() {
+        let g = grammar(r#"
+            <s>    ::= <sign>? <num> <unit>?
+            <sign> ::= "+" | "-"
+            <num>  ::= "d" | <num> "d" | "0" "x" <num>
+            <unit> ::= "m" | "s"
+        "#);
+        let a = Analysis::new(&g);
+        let s = a.production("s").unwrap();
+        assert_eq!(a.first[s], set(&[ "+", "-", "d", "0" ]));
+        assert_eq!(a.last[s], set(&[ "d", "m", "s" ]));
+        let num = a.production("num").unwrap();
+        assert_eq!(a.first[num], set(&[ "d", "0" ]));
+        assert_eq!(a.last[num], set(&[ "d" ]));
+    }
+
+    #[test]
+    fn sets_test() {
+        let text = r#"
+            <expr>   ::= <term> <expr_r>
+            <expr_r> ::= "+" <term> <expr_r> | ε
+            <term>   ::= <factor> <term_r>
+            <term_r> ::= "*" <factor> <term_r> | ε
+            <factor> ::= "(" <expr> ")" | "id"
+        "#;
+        let g = grammar(text);
+        let sets = g.sets();
+        let row = |name: &str| {
+            let n = sets.get(name).unwrap();
+            (n.nullable, n.first.clone(), n.follow.clone(), n.at_end)
+        };
+        assert_eq!(row("expr"), (false, set(&[ "(", "id" ]), set(&[ ")" ]), true));
+        assert_eq!(row("expr_r"), (true, set(&[ "+" ]), set(&[ ")" ]), true));
+        assert_eq!(row("term"), (false, set(&[ "(", "id" ]), set(&[ "+", ")" ]), true));
+        assert_eq!(row("term_r"), (true, set(&[ "*" ]), set(&[ "+", ")" ]), true));
+        assert_eq!(row("factor"), (false, set(&[ "(", "id" ]), set(&[ "*", "+", ")" ]), true));
+
+        assert_eq!(format!("{}", sets), r#"
+            |nonterminal | nullable | first    | follow
+            |<expr>      | no       | "(" "id" | ")" $
+            |<expr_r>    | yes      | "+"      | ")" $
+            |<term>      | no       | "(" "id" | ")" "+" $
+            |<term_r>    | yes      | "*"      | ")" "+" $
+            |<factor>    | no       | "(" "id" | ")" "*" "+" $
+        "#.trim_margin().unwrap());
+
+        let from_factor = g.sets_from("factor");
+        assert!(from_factor.get("factor").unwrap().at_end);
+        assert!(!from_factor.get("expr").unwrap().at_end);
+
+        let json = serde_json::to_string(&sets).unwrap();
+        assert_eq!(serde_json::from_str::<GrammarSets>(&json).unwrap(), sets);
+    }
+
+    #[test]
+    fn ext_sets_test() {
+        let text = r#"
+            <s> ::= <a>? "x" <b>?
+            <a> ::= "a"
+            <b> ::= "b" <s>?
+        "#;
+        let ext: ExtGrammar = text.try_into().unwrap();
+        let sets = ext.sets();
+        assert_eq!(sets, { let g: ExtGrammar = text.try_into().unwrap(); g.flatten() }.sets());
+        let s = sets.get("s").unwrap();
+        assert_eq!(s.first, set(&[ "a", "x" ]));
+        assert_eq!(s.follow, set(&[]));
+        assert_eq!(sets.get("a").unwrap().follow, set(&[ "x" ]));
+        assert!(sets.get("b").unwrap().at_end);
+    }
+}
@@ -110,7 +110,7 @@ macro_rules! assert_contains_tree {
 
         let expected: Result<String, Error> = Ok(String::from(expected_tree_str.trim_margin().unwrap()) + "\n");
 
-        fn is_same_with_expected(actual: Result<String, String>, exp: &Result<String, String>) -> bool {
+        fn is_same_with_expected(actual: Result<String, Error>, exp: &Result<String, Error>) -> bool {
             if $expected_tree == "any" {
                 actual.is_ok()
             } else {
@@ -0,0 +1,264 @@
+use std::{iter::Peekable, str::Chars};
+
+use crate::grammar::{Term, ParseError, Location};
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Lexeme {
+    /// `<name>`, `"quoted"`, `'quoted'` or a bare word. a char class is the nonterminal `<[a-z]>`
+    Term(Term),
+    /// `::=`
+    Define,
+    /// `(` or `[`
+    Open(char),
+    /// `)` or `]`
+    Close(char),
+    Or,
+    /// `?`, `*` or `+`
+    Operator(char),
+    /// `ε`, `""` or `''`, the empty string
+    Empty,
+    Newline,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Located {
+    pub lexeme: Lexeme,
+    pub location: Location,
+}
+
+const DEFINE: &str = "::=";
+const EMPTY: &str = "ε";
+
+fn is_special(c: char) -> bool {
+    c.is_whitespace() || "()[]|?*+;#\"'<".contains(c)
+}
+
+/// inclusive char ranges of a char class body like `a-zA-Z_`, written as the nonterminal `<[a-zA-Z_]>`.
+/// `[...]` alone is an optional group
+pub(crate) fn char_class(body: &str) -> Option<Vec<(char, char)>> {
+    let chars: Vec<char> = body.chars().collect();
+    if chars.is_empty() || chars.contains(&']') {
+        return None
+    }
+    let mut ranges = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        if i + 2 < chars.len() && chars[i + 1] == '-' {
+            if chars[i] > chars[i + 2] {
+                return None
+            }
+            ranges.push((chars[i], chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((chars[i], chars[i]));
+            i += 1;
+        }
+    }
+    Some(ranges)
+}
+
+struct Lexer<'s> {
+    chars: Peekable<Chars<'s>>,
+    rest: &'s str,
+    location: Location,
+}
+
+impl<'s> Lexer<'s> {
+    fn new(text: &'s str) -> Self {
+        Self { chars: text.chars().peekable(), rest: text, location: Location { line: 1, column: 1 } }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.rest = &self.rest[c.len_utf8()..];
+        if c == '\n' {
+            self.location.line += 1;
+            self.location.column = 1;
+        } else {
+            self.location.column += 1;
+        }
+        Some(c)
+    }
+
+    fn quoted(&mut self, quote: char, begin: Location) -> Result<String, ParseError> {
+        let mut result = String::new();
+        loop {
+            let location = self.location;
+            match self.bump() {
+                None | Some('\n') => return Err(ParseError::UnterminatedString(begin)),
+                Some(c) if c == quote => return Ok(result),
+                Some('\\') => match self.bump() {
+                    Some('n') => result.push('\n'),
+                    Some('t') => result.push('\t'),
+                    Some('r') => result.push('\r'),
+                    Some(c @ ('\\' | '"' | '\'')) => result.push(c),
+                    Some(c) => return Err(ParseError::UnknownEscape(location, c)),
+                    None => return Err(ParseError::UnterminatedString(begin)),
+                },
+                Some(c) => result.push(c),
+            }
+        }
+    }
+
+    fn nonterminal(&mut self, begin: Location) -> Result<String, ParseError> {
+        let mut result = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err(ParseError::UnterminatedNonterminal(begin)),
+                Some('>') => return Ok(result),
+                Some(c) => result.push(c),
+            }
+        }
+    }
+
+    fn next(&mut self) -> Option<Result<Located, ParseError>> {
+        loop {
+            match self.peek()? {
+                '\n' => break,
+                ';' | '#' => while self.peek().is_some_and(|c| c != '\n') {
+                    self.bump();
+                },
+                c if c.is_whitespace() => {
+                    self.bump();
+                },
+                _ => break,
+            }
+        }
+
+        let location = self.location;
+        if self.rest.starts_with(DEFINE) {
+            for _ in 0..DEFINE.len() {
+                self.bump();
+            }
+            return Some(Ok(Located { lexeme: Lexeme::Define, location }))
+        }
+
+        let lexeme = match self.bump()? {
+            '\n' => Ok(Lexeme::Newline),
+            c @ ('(' | '[') => Ok(Lexeme::Open(c)),
+            c @ (')' | ']') => Ok(Lexeme::Close(c)),
+            '|' => Ok(Lexeme::Or),
+            c @ ('?' | '*' | '+') => Ok(Lexeme::Operator(c)),
+            c @ ('"' | '\'') => self.quoted(c, location).map(|s| if s.is_empty() {
+                Lexeme::Empty
+            } else {
+                Lexeme::Term(Term::Terminal(s))
+            }),
+            '<' => self.nonterminal(location).map(|s| Lexeme::Term(Term::Nonterminal(s))),
+            c => {
+                let mut word = String::from(c);
+                while let Some(c) = self.peek() {
+                    if is_special(c) || self.rest.starts_with(DEFINE) {
+                        break;
+                    }
+                    word.push(c);
+                    self.bump();
+                }
+                Ok(if word == EMPTY { Lexeme::Empty } else { Lexeme::Term(Term::Terminal(word)) })
+            },
+        };
+        Some(lexeme.map(|lexeme| Located { lexeme, location }))
+    }
+}
+
+/// splits bnf text into lexemes. comments are dropped, line breaks are kept as `Lexeme::Newline`
+pub(crate) fn lex(text: &str) -> Result<Vec<Located>, ParseError> {
+    let mut lexer = Lexer::new(text);
+    let mut result = vec![];
+    while let Some(lexeme) = lexer.next() {
+        result.push(lexeme?);
+    }
+    Ok(result)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::{Term, ParseError, Location};
+
+    use super::{lex, char_class, Lexeme};
+
+    fn lexemes(text: &str) -> Vec<Lexeme> {
+        lex(text).unwrap().into_iter().map(|l| l.lexeme).collect()
+    }
+
+    fn terminal(s: &str) -> Lexeme {
+        Lexeme::Term(Term::Terminal(s.to_string()))
+    }
+
+    #[test]
+    fn term_test() {
+        assert_eq!(lexemes("goga"), vec![ terminal("goga") ]);
+        assert_eq!(lexemes("<goga>"), vec![ Lexeme::Term(Term::Nonterminal("goga".to_string())) ]);
+        assert_eq!(lexemes("<a>::=b"), vec![
+            Lexeme::Term(Term::Nonterminal("a".to_string())),
+            Lexeme::Define,
+            terminal("b"),
+        ]);
+    }
+
+    #[test]
+    fn quoted_test() {
+        assert_eq!(lexemes(r#""|" " " "\"" '\'' "\\\n\t" "a;b#c""#), vec![
+            terminal("|"),
+            terminal(" "),
+            terminal("\""),
+            terminal("'"),
+            terminal("\\\n\t"),
+            terminal("a;b#c"),
+        ]);
+    }
+
+    #[test]
+    fn empty_test() {
+        assert_eq!(lexemes(r#"ε "" '' "ε" εx"#), vec![
+            Lexeme::Empty,
+            Lexeme::Empty,
+            Lexeme::Empty,
+            terminal("ε"),
+            terminal("εx"),
+        ]);
+    }
+
+    #[test]
+    fn comment_test() {
+        assert_eq!(lexemes("a ; comment \"\n# another\nb"), vec![
+            terminal("a"),
+            Lexeme::Newline,
+            Lexeme::Newline,
+            terminal("b"),
+        ]);
+    }
+
+    #[test]
+    fn char_class_test() {
+        let nonterminal = |s: &str| Lexeme::Term(Term::Nonterminal(s.to_string()));
+        assert_eq!(lexemes("<[a-z]> <[a-zA-Z_]>+ [a-z] [else-if]"), vec![
+            nonterminal("[a-z]"),
+            nonterminal("[a-zA-Z_]"),
+            Lexeme::Operator('+'),
+            Lexeme::Open('['),
+            terminal("a-z"),
+            Lexeme::Close(']'),
+            Lexeme::Open('['),
+            terminal("else-if"),
+            Lexeme::Close(']'),
+        ]);
+        assert_eq!(char_class("0-9_-"), Some(vec![ ('0', '9'), ('_', '_'), ('-', '-') ]));
+        assert_eq!(char_class("+*/"), Some(vec![ ('+', '+'), ('*', '*'), ('/', '/') ]));
+        assert_eq!(char_class("z-a"), None);
+        assert_eq!(char_class(""), None);
+    }
+
+    #[test]
+    fn error_test() {
+        assert_eq!(lex("<a> ::= \"x"), Err(ParseError::UnterminatedString(Location { line: 1, column: 9 })));
+        assert_eq!(lex("<a> ::=\n  \"\\q\""), Err(ParseError::UnknownEscape(Location { line: 2, column: 4 }, 'q')));
+        assert_eq!(lex("\n<a ::= b"), Err(ParseError::UnterminatedNonterminal(Location { line: 2, column: 1 })));
+    }
+}
@@ -0,0 +1,757 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::grammar::*;
+use crate::ctx::*;
+use crate::error::{Error, SyntaxError};
+use crate::earley::{production_lookup, nullable_productions};
+use crate::parse::{ParseTreeIter, splice_synthetic, parse_failed};
+use crate::sppf::{Forest, ForestNode, NodeId, Symbol};
+
+
+/// expression of an original production with some of its nullable nonterminals left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    production: usize,
+    expression: usize,
+    /// positions of the terms which are still there
+    kept: Vec<usize>,
+}
+
+/// what a derivation by an expression of the CNF grammar stands for in the original grammar
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Origin {
+    /// derivation of the kept terms of the rule
+    Rule(usize),
+    /// unit rule of the lhs, its only kept nonterminal derives the same tokens
+    Unit(usize),
+}
+
+/// what a production of the CNF grammar stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// original production
+    Nonterminal(usize),
+    /// `<"x"> ::= "x"`, a terminal in an expression of two terms
+    Terminal,
+    /// first `r` kept terms of a rule
+    Prefix(usize, usize),
+}
+
+/// grammar in Chomsky normal form together with what is needed to map its derivations back
+#[derive(Debug)]
+pub struct ChomskyNormalForm {
+    /// every expression is two nonterminals or a single terminal. original nonterminals keep their names,
+    /// `<"x">` derives the terminal `"x"` and `<a.n>` a prefix of a long expression of `<a>`
+    pub grammar: Grammar,
+    rules: Vec<Rule>,
+    kinds: Vec<Kind>,
+    /// indexed like `grammar.productions[i].rhs[j]`
+    origins: Vec<Vec<Vec<Origin>>>,
+    /// production of `grammar` for every original production
+    productions: Vec<Option<usize>>,
+    /// `prefixes[rule][r]` derives the first `r` kept terms of the rule
+    prefixes: Vec<Vec<usize>>,
+    /// original productions deriving the empty string, which `grammar` can not express
+    nullable: Vec<bool>,
+}
+
+struct Builder<'g> {
+    lookup: HashMap<&'g str, usize>,
+    names: HashSet<String>,
+    productions: Vec<Production>,
+    kinds: Vec<Kind>,
+    origins: Vec<Vec<Vec<Origin>>>,
+    wrappers: HashMap<String, usize>,
+    cnf: Vec<Option<usize>>,
+    synthetic: HashSet<String>,
+}
+
+impl Builder<'_> {
+    fn push(&mut self, lhs: String, kind: Kind, synthetic: bool) -> usize {
+        self.names.insert(lhs.clone());
+        if synthetic {
+            self.synthetic.insert(lhs.clone());
+        }
+        self.productions.push(Production { lhs, rhs: vec![] });
+        self.kinds.push(kind);
+        self.origins.push(vec![]);
+        self.productions.len() - 1
+    }
+
+    fn fresh(&self, lhs: &str) -> String {
+        (1..).map(|n| format!("{}.{}", lhs, n)).find(|name| !self.names.contains(name)).unwrap()
+    }
+
+    /// production standing for a term of an expression of two terms
+    fn term(&mut self, term: &Term) -> Term {
+        let production = match term {
+            Term::Nonterminal(n) => self.cnf[self.lookup[n.as_str()]].unwrap(),
+            Term::Terminal(t) => match self.wrappers.get(t) {
+                Some(&w) => w,
+                None => {
+                    let w = self.push(format!("\"{}\"", t), Kind::Terminal, true);
+                    self.add(w, vec![ Term::Terminal(t.clone()) ], None);
+                    self.wrappers.insert(t.clone(), w);
+                    w
+                },
+            },
+        };
+        Term::Nonterminal(self.productions[production].lhs.clone())
+    }
+
+    /// adds the expression unless `production` has it and records where it comes from
+    fn add(&mut self, production: usize, terms: Vec<Term>, origin: Option<Origin>) {
+        let rhs = &mut self.productions[production].rhs;
+        let e = match rhs.iter().position(|e| e.terms == terms) {
+            Some(e) => e,
+            None => {
+                rhs.push(Expression { terms });
+                self.origins[production].push(vec![]);
+                rhs.len() - 1
+            },
+        };
+        let origins = &mut self.origins[production][e];
+        if let Some(origin) = origin.filter(|o| !origins.contains(o)) {
+            origins.push(origin);
+        }
+    }
+}
+
+impl Grammar {
+    /// equivalent grammar in Chomsky normal form: empty expressions are removed by adding every
+    /// variant without the nullable nonterminals, unit rules `<a> ::= <b>` by copying the expressions
+    /// of `<b>` into `<a>`, and longer expressions are split into left nested pairs.
+    /// the number of variants grows exponentially with the nullable nonterminals of an expression
+    pub fn to_cnf(&self) -> ChomskyNormalForm {
+        let lookup = production_lookup(self);
+        let nullable = nullable_productions(self, &lookup);
+        let is_nullable = |term: &Term| match term {
+            Term::Terminal(_) => false,
+            Term::Nonterminal(n) => lookup.get(n.as_str()).is_some_and(|&p| nullable[p]),
+        };
+
+        let mut rules = vec![];
+        let mut rules_of = vec![ vec![]; self.productions.len() ];
+        for (p, production) in self.productions.iter().enumerate() {
+            if lookup[production.lhs.as_str()] != p {
+                continue;
+            }
+            for (e, expression) in production.rhs.iter().enumerate() {
+                let terms = &expression.terms;
+                if terms.iter().any(|t| matches!(t, Term::Nonterminal(n) if !lookup.contains_key(n.as_str()))) {
+                    continue;
+                }
+                let optional: Vec<usize> = (0..terms.len()).filter(|&i| is_nullable(&terms[i])).collect();
+                for mask in 0..1usize << optional.len() {
+                    let kept: Vec<usize> = (0..terms.len())
+                        .filter(|i| optional.iter().position(|o| o == i).is_none_or(|bit| mask & (1 << bit) == 0))
+                        .collect();
+                    if !kept.is_empty() {
+                        rules_of[p].push(rules.len());
+                        rules.push(Rule { production: p, expression: e, kept });
+                    }
+                }
+            }
+        }
+        let unit_target = |rule: &Rule| match &self.productions[rule.production].rhs[rule.expression].terms[..] {
+            terms if rule.kept.len() == 1 => match &terms[rule.kept[0]] {
+                Term::Nonterminal(n) => Some(lookup[n.as_str()]),
+                Term::Terminal(_) => None,
+            },
+            _ => None,
+        };
+        // productions reachable by unit rules, the production itself included
+        let units: Vec<Vec<usize>> = (0..self.productions.len()).map(|p| {
+            let mut reached = vec![ p ];
+            let mut i = 0;
+            while i < reached.len() {
+                for &r in &rules_of[reached[i]] {
+                    if let Some(target) = unit_target(&rules[r]) {
+                        if !reached.contains(&target) {
+                            reached.push(target);
+                        }
+                    }
+                }
+                i += 1;
+            }
+            reached
+        }).collect();
+
+        let mut builder = Builder {
+            lookup: lookup.clone(),
+            names: self.productions.iter().map(|p| p.lhs.clone()).collect(),
+            productions: vec![],
+            kinds: vec![],
+            origins: vec![],
+            wrappers: HashMap::new(),
+            cnf: vec![ None; self.productions.len() ],
+            synthetic: HashSet::new(),
+        };
+        for (p, production) in self.productions.iter().enumerate() {
+            if lookup[production.lhs.as_str()] == p {
+                builder.cnf[p] = Some(builder.push(production.lhs.clone(), Kind::Nonterminal(p), self.synthetic.contains(&production.lhs)));
+            }
+        }
+
+        let mut prefixes = vec![ vec![]; rules.len() ];
+        let mut tops: Vec<Option<Vec<Term>>> = vec![ None; rules.len() ];
+        for (r, rule) in rules.iter().enumerate() {
+            if unit_target(rule).is_some() {
+                continue;
+            }
+            let terms = &self.productions[rule.production].rhs[rule.expression].terms;
+            if rule.kept.len() == 1 {
+                tops[r] = Some(vec![ terms[rule.kept[0]].clone() ]);
+                continue;
+            }
+            let mut left = builder.term(&terms[rule.kept[0]]);
+            for len in 2..rule.kept.len() {
+                let right = builder.term(&terms[rule.kept[len - 1]]);
+                let prefix = builder.push(builder.fresh(&self.productions[rule.production].lhs), Kind::Prefix(r, len), true);
+                builder.add(prefix, vec![ left, right ], Some(Origin::Rule(r)));
+                prefixes[r].resize(len + 1, usize::MAX);
+                prefixes[r][len] = prefix;
+                left = Term::Nonterminal(builder.productions[prefix].lhs.clone());
+            }
+            tops[r] = Some(vec![ left, builder.term(&terms[*rule.kept.last().unwrap()]) ]);
+        }
+
+        for p in 0..self.productions.len() {
+            let Some(a) = builder.cnf[p] else {
+                continue
+            };
+            for &r in &rules_of[p] {
+                match unit_target(&rules[r]) {
+                    None => builder.add(a, tops[r].clone().unwrap(), Some(Origin::Rule(r))),
+                    Some(target) => {
+                        for &b in &units[target] {
+                            for &final_rule in &rules_of[b] {
+                                if let Some(top) = &tops[final_rule] {
+                                    builder.add(a, top.clone(), Some(Origin::Unit(r)));
+                                }
+                            }
+                        }
+                    },
+                }
+            }
+        }
+
+        ChomskyNormalForm {
+            grammar: Grammar { productions: builder.productions, synthetic: builder.synthetic },
+            rules,
+            kinds: builder.kinds,
+            origins: builder.origins,
+            productions: builder.cnf,
+            prefixes,
+            nullable,
+        }
+    }
+}
+
+/// CYK table: which productions of the CNF grammar derive tokens `i..j` of the range
+struct Table {
+    n: usize,
+    words: usize,
+    bits: Vec<u64>,
+}
+
+impl Table {
+    fn new(n: usize, productions: usize) -> Self {
+        let words = productions.div_ceil(64);
+        Self { n, words, bits: vec![ 0; (n + 1) * (n + 1) * words ] }
+    }
+
+    fn index(&self, i: usize, j: usize, a: usize) -> (usize, u64) {
+        ((i * (self.n + 1) + j) * self.words + a / 64, 1 << (a % 64))
+    }
+
+    fn get(&self, i: usize, j: usize, a: usize) -> bool {
+        let (word, bit) = self.index(i, j, a);
+        self.bits[word] & bit != 0
+    }
+
+    fn set(&mut self, i: usize, j: usize, a: usize) {
+        let (word, bit) = self.index(i, j, a);
+        self.bits[word] |= bit;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    /// original production deriving `i..j`
+    Nonterminal(usize, usize, usize),
+    Terminal(usize),
+    /// prefix production of the CNF grammar deriving `i..j`
+    Prefix(usize, usize, usize),
+}
+
+/// builds the packed forest of the original grammar from a filled table
+struct Unpacker<'c, 'tg, T> {
+    cnf: &'c ChomskyNormalForm,
+    ctx: &'c Ctx<'tg, 'tg, T>,
+    table: &'c Table,
+    lookup: HashMap<&'tg str, usize>,
+    cnf_lookup: HashMap<&'c str, usize>,
+    nodes: Vec<ForestNode>,
+    ids: HashMap<Key, NodeId>,
+    pending: Vec<(NodeId, Key)>,
+    packed: HashSet<(NodeId, usize, usize, Vec<NodeId>)>,
+}
+
+impl<T: Token> Unpacker<'_, '_, T> {
+    fn node(&mut self, key: Key) -> NodeId {
+        if let Some(&id) = self.ids.get(&key) {
+            return id
+        }
+        let begin = self.ctx.begin;
+        self.nodes.push(match key {
+            Key::Nonterminal(p, i, j) => ForestNode::Symbol { symbol: Symbol::Nonterminal(p), begin: begin + i, end: begin + j, packed: vec![] },
+            Key::Terminal(i) => ForestNode::Symbol { symbol: Symbol::Terminal(begin + i), begin: begin + i, end: begin + i + 1, packed: vec![] },
+            Key::Prefix(a, i, j) => {
+                let Kind::Prefix(r, len) = self.cnf.kinds[a] else {
+                    unreachable!()
+                };
+                let rule = &self.cnf.rules[r];
+                ForestNode::Intermediate {
+                    production: rule.production,
+                    expression: rule.expression,
+                    dot: rule.kept[len - 1] + 1,
+                    begin: begin + i,
+                    end: begin + j,
+                    packed: vec![],
+                }
+            },
+        });
+        let id = self.nodes.len() - 1;
+        self.ids.insert(key, id);
+        self.pending.push((id, key));
+        id
+    }
+
+    fn pack(&mut self, id: NodeId, production: usize, expression: usize, children: Vec<NodeId>) {
+        if !self.packed.insert((id, production, expression, children.clone())) {
+            return
+        }
+        self.nodes.push(ForestNode::Packed { production, expression, children });
+        let packed = self.nodes.len() - 1;
+        match &mut self.nodes[id] {
+            ForestNode::Symbol { packed: p, .. } | ForestNode::Intermediate { packed: p, .. } => p.push(packed),
+            ForestNode::Packed { .. } => unreachable!(),
+        }
+    }
+
+    fn terms(&self, rule: usize) -> &[Term] {
+        let rule = &self.cnf.rules[rule];
+        &self.ctx.grammar.productions[rule.production].rhs[rule.expression].terms
+    }
+
+    fn is_nullable(&self, term: &Term) -> bool {
+        match term {
+            Term::Terminal(_) => false,
+            Term::Nonterminal(n) => self.lookup.get(n.as_str()).is_some_and(|&p| self.cnf.nullable[p]),
+        }
+    }
+
+    fn term_node(&mut self, term: &Term, i: usize, j: usize) -> NodeId {
+        match term {
+            Term::Terminal(_) => self.node(Key::Terminal(i)),
+            Term::Nonterminal(n) => self.node(Key::Nonterminal(self.lookup[n.as_str()], i, j)),
+        }
+    }
+
+    /// empty derivations of the left out terms between positions `from` and `to` of the expression
+    fn gap(&mut self, rule: usize, from: usize, to: usize, at: usize) -> Vec<NodeId> {
+        let terms = self.terms(rule)[from..to].to_vec();
+        terms.iter().map(|t| self.term_node(t, at, at)).collect()
+    }
+
+    /// nodes of the terms of the rule up to its kept term `len`, the kept terms before it deriving `i..k`
+    fn prefix(&mut self, r: usize, len: usize, i: usize, k: usize, j: usize) -> Vec<NodeId> {
+        let kept = self.cnf.rules[r].kept.clone();
+        let terms = self.terms(r).to_vec();
+        let mut children = if len == 2 {
+            let mut children = self.gap(r, 0, kept[0], i);
+            children.push(self.term_node(&terms[kept[0]], i, k));
+            children
+        } else {
+            vec![ self.node(Key::Prefix(self.cnf.prefixes[r][len - 1], i, k)) ]
+        };
+        children.extend(self.gap(r, kept[len - 2] + 1, kept[len - 1], k));
+        children.push(self.term_node(&terms[kept[len - 1]], k, j));
+        children
+    }
+
+    /// nodes of all terms of the rule deriving `i..j`, split at `k` when more than one term is kept
+    fn expand(&mut self, r: usize, i: usize, j: usize, k: Option<usize>) -> Vec<NodeId> {
+        let kept = self.cnf.rules[r].kept.clone();
+        let len = self.terms(r).len();
+        let mut children = match k {
+            Some(k) if kept.len() > 1 => self.prefix(r, kept.len(), i, k, j),
+            _ => {
+                let term = self.terms(r)[kept[0]].clone();
+                let mut children = self.gap(r, 0, kept[0], i);
+                children.push(self.term_node(&term, i, j));
+                children
+            },
+        };
+        children.extend(self.gap(r, kept[kept.len() - 1] + 1, len, j));
+        children
+    }
+
+    /// splits of `i..j` matching an expression of the CNF grammar, `None` for a terminal
+    fn splits(&self, expression: &Expression, i: usize, j: usize) -> Vec<Option<usize>> {
+        match &expression.terms[..] {
+            [ Term::Terminal(t) ] => {
+                if j == i + 1 && self.ctx.tokens[self.ctx.begin + i].name() == t {
+                    vec![ None ]
+                } else {
+                    vec![]
+                }
+            },
+            [ Term::Nonterminal(left), Term::Nonterminal(right) ] => {
+                let (left, right) = (self.cnf_lookup[left.as_str()], self.cnf_lookup[right.as_str()]);
+                (i + 1..j).filter(|&k| self.table.get(i, k, left) && self.table.get(k, j, right)).map(Some).collect()
+            },
+            _ => unreachable!("expression is not in normal form"),
+        }
+    }
+
+    fn fill(&mut self, id: NodeId, key: Key) {
+        match key {
+            Key::Terminal(_) => (),
+            Key::Nonterminal(p, i, j) if i == j => {
+                let production = &self.ctx.grammar.productions[p];
+                for (e, expression) in production.rhs.iter().enumerate() {
+                    if expression.terms.iter().all(|t| self.is_nullable(t)) {
+                        let children = expression.terms.iter().map(|t| self.term_node(t, i, i)).collect();
+                        self.pack(id, p, e, children);
+                    }
+                }
+            },
+            Key::Nonterminal(p, i, j) => {
+                let cnf = self.cnf;
+                let a = cnf.productions[p].unwrap();
+                for (x, expression) in cnf.grammar.productions[a].rhs.iter().enumerate() {
+                    for k in self.splits(expression, i, j) {
+                        for &origin in &cnf.origins[a][x] {
+                            let r = match origin {
+                                Origin::Rule(r) | Origin::Unit(r) => r,
+                            };
+                            let children = match origin {
+                                Origin::Rule(_) => self.expand(r, i, j, k),
+                                Origin::Unit(_) => self.expand(r, i, j, None),
+                            };
+                            let rule = &cnf.rules[r];
+                            self.pack(id, rule.production, rule.expression, children);
+                        }
+                    }
+                }
+            },
+            Key::Prefix(a, i, j) => {
+                let cnf = self.cnf;
+                let Kind::Prefix(r, len) = cnf.kinds[a] else {
+                    unreachable!()
+                };
+                for k in self.splits(&cnf.grammar.productions[a].rhs[0], i, j) {
+                    let children = self.prefix(r, len, i, k.unwrap(), j);
+                    let rule = &cnf.rules[r];
+                    self.pack(id, rule.production, rule.expression, children);
+                }
+            },
+        }
+    }
+}
+
+impl ChomskyNormalForm {
+    /// whether the original nonterminal `name` derives the empty string
+    pub fn derives_empty(&self, name: &str) -> bool {
+        self.grammar.productions.iter().zip(&self.kinds).any(|(p, kind)| match kind {
+            Kind::Nonterminal(original) => p.lhs == name && self.nullable[*original],
+            _ => false,
+        })
+    }
+
+    /// CYK recognizer over the ctx range followed by the packed forest of all derivations of `start`.
+    /// `ctx.grammar` is the grammar `self` was built from
+    pub(crate) fn forest<'tg, T: Token>(&self, ctx: &Ctx<'tg, 'tg, T>, start: usize) -> Result<Forest<'tg, T>, Error> {
+        let lookup = production_lookup(ctx.grammar);
+        let cnf_lookup = production_lookup(&self.grammar);
+        let n = ctx.end - ctx.begin;
+        let mut table = Table::new(n, self.grammar.productions.len());
+        let mut unary = vec![];
+        let mut binary = vec![];
+        for (a, production) in self.grammar.productions.iter().enumerate() {
+            for expression in &production.rhs {
+                match &expression.terms[..] {
+                    [ Term::Terminal(t) ] => {
+                        unary.push((a, t.as_str()));
+                        for i in 0..n {
+                            if ctx.tokens[ctx.begin + i].name() == t {
+                                table.set(i, i + 1, a);
+                            }
+                        }
+                    },
+                    [ Term::Nonterminal(left), Term::Nonterminal(right) ] => binary.push((a, cnf_lookup[left.as_str()], cnf_lookup[right.as_str()])),
+                    _ => unreachable!("expression is not in normal form"),
+                }
+            }
+        }
+        for len in 2..=n {
+            for i in 0..=n - len {
+                let j = i + len;
+                for k in i + 1..j {
+                    for &(a, left, right) in &binary {
+                        if table.get(i, k, left) && table.get(k, j, right) {
+                            table.set(i, j, a);
+                        }
+                    }
+                }
+            }
+        }
+
+        let start = lookup[ctx.grammar.productions[start].lhs.as_str()];
+        let accepted = match n {
+            0 => self.nullable[start],
+            _ => self.productions[start].is_some_and(|a| table.get(0, n, a)),
+        };
+        if !accepted {
+            return Err(Error::Syntax(self.syntax_error(ctx, &table, &unary, &binary, start)))
+        }
+
+        let mut unpacker = Unpacker {
+            cnf: self,
+            ctx,
+            table: &table,
+            lookup,
+            cnf_lookup,
+            nodes: vec![],
+            ids: HashMap::new(),
+            pending: vec![],
+            packed: HashSet::new(),
+        };
+        let root = unpacker.node(Key::Nonterminal(start, 0, n));
+        while let Some((id, key)) = unpacker.pending.pop() {
+            unpacker.fill(id, key);
+        }
+        Ok(Forest { grammar: ctx.grammar, tokens: ctx.tokens, nodes: unpacker.nodes, root })
+    }
+
+    /// `result[i][a]` holds the terminals which can follow tokens `i..j` of the range in a derivation by the
+    /// production `a` of `self.grammar`, `i..j` being derived by a prefix of it
+    fn next_terminals<'c>(&self, table: &Table, unary: &[(usize, &'c str)], binary: &[(usize, usize, usize)], j: usize) -> Vec<Vec<BTreeSet<&'c str>>> {
+        let mut result = vec![ vec![ BTreeSet::new(); self.grammar.productions.len() ]; j + 1 ];
+        for i in (0..=j).rev() {
+            if i == j {
+                for &(a, t) in unary {
+                    result[i][a].insert(t);
+                }
+            }
+            // `<a> ::= <b> <c>` with `<b>` deriving `i..k` and `<c>` a prefix `k..j`, shorter ranges are done
+            for &(a, b, c) in binary {
+                for k in i + 1..=j {
+                    if table.get(i, k, b) {
+                        let (done, current) = result.split_at_mut(k);
+                        done[i][a].extend(current[0][c].iter().copied());
+                    }
+                }
+            }
+            // or `<b>` deriving a prefix `i..j` itself, which can go through any number of left corners
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for &(a, b, _) in binary {
+                    if a != b {
+                        let next: Vec<_> = result[i][b].difference(&result[i][a]).copied().collect();
+                        changed |= !next.is_empty();
+                        result[i][a].extend(next);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// the longest prefix of the range which starts a derivation of `start`, read from the filled table,
+    /// and the terminals which could have continued it
+    fn syntax_error<T: Token>(
+        &self,
+        ctx: &Ctx<T>,
+        table: &Table,
+        unary: &[(usize, &str)],
+        binary: &[(usize, usize, usize)],
+        start: usize
+    ) -> SyntaxError {
+        let n = ctx.end - ctx.begin;
+        let mut position = 0;
+        let mut next = self.next_terminals(table, unary, binary, 0);
+        if let Some(a) = self.productions[start] {
+            while position < n && next[0][a].contains(ctx.tokens[ctx.begin + position].name()) {
+                position += 1;
+                next = self.next_terminals(table, unary, binary, position);
+            }
+        }
+
+        // walk down from the start through the nonterminals still open at `position`
+        let mut production_stack = vec![];
+        let mut visited = HashSet::new();
+        let mut current = self.productions[start].map(|a| (a, 0));
+        while let Some((a, i)) = current {
+            if !visited.insert((a, i)) {
+                break;
+            }
+            if let Kind::Nonterminal(_) = self.kinds[a] {
+                production_stack.push(self.grammar.productions[a].lhs.clone());
+            }
+            // the rightmost open child first, it is the innermost one
+            let rules = || binary.iter().filter(|&&(x, _, _)| x == a);
+            current = rules()
+                .find_map(|&(_, b, c)| (i + 1..=position).rev().find(|&k| table.get(i, k, b) && !next[k][c].is_empty()).map(|k| (c, k)))
+                .or_else(|| rules().find(|&&(_, b, _)| !next[i][b].is_empty()).map(|&(_, b, _)| (b, i)));
+        }
+
+        SyntaxError {
+            position: ctx.begin + position,
+            expected: self.productions[start].map_or(BTreeSet::new(), |a| next[0][a].iter().map(|t| t.to_string()).collect()),
+            production_stack,
+            tokens: ctx.tokens.iter().map(|t| t.name().to_string()).collect(),
+        }
+    }
+}
+
+/// converts the grammar to Chomsky normal form and packs all derivations of the first production found by CYK
+pub fn parse_cyk_forest<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>) -> Result<Forest<'tg, T>, Error> {
+    parse_cyk_forest_from(ctx, 0)
+}
+
+pub(crate) fn parse_cyk_forest_from<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>, start: usize) -> Result<Forest<'tg, T>, Error> {
+    if ctx.grammar.productions.len() <= start {
+        return Err(Error::GrammarIsEmpty)
+    }
+    ctx.grammar.to_cnf().forest(&ctx, start)
+}
+
+pub(crate) fn parse_cyk_from<'tg, T: Token + 'tg>(ctx: Ctx<'tg, 'tg, T>, start: usize) -> ParseTreeIter<'tg, T> {
+    let ignore_errors = ctx.ignore_errors;
+    match parse_cyk_forest_from(ctx, start) {
+        Ok(forest) => splice_synthetic(forest.grammar, forest.into_trees()),
+        Err(err) => parse_failed(ignore_errors, err),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use trim_margin::MarginTrimmable;
+
+    use crate::grammar::{ExtGrammar, Grammar, Term};
+    use crate::parse::{make_ctx, parse_with, Engine};
+    use crate::sppf::{parse_forest, DerivationCount};
+    use crate::error::Error;
+
+    use super::parse_cyk_forest;
+
+    fn grammar(text: &str) -> Grammar {
+        let g: ExtGrammar = text.try_into().unwrap();
+        g.flatten()
+    }
+
+    fn tokens(t: &[&str]) -> Vec<String> {
+        t.iter().map(|t| t.to_string()).collect()
+    }
+
+    fn trees(g: &Grammar, t: &Vec<String>, engine: Engine) -> BTreeSet<String> {
+        parse_with(make_ctx(g, t, false, true), engine).map(|t| format!("{:#}", t.unwrap())).collect()
+    }
+
+    #[test]
+    fn cnf_test() {
+        let g = grammar(r#"
+            <s> ::= <a> "x" <b> | <b>
+            <a> ::= "a" | ε
+            <b> ::= "b" "c" "d"
+        "#);
+        let cnf = g.to_cnf();
+        assert_eq!(format!("{}", cnf.grammar), r#"
+            |<s> ::= <s.1> <b> | <"x"> <b> | <b.1> <"d">
+            |<a> ::= "a"
+            |<b> ::= <b.1> <"d">
+            |<"x"> ::= "x"
+            |<s.1> ::= <a> <"x">
+            |<"b"> ::= "b"
+            |<"c"> ::= "c"
+            |<b.1> ::= <"b"> <"c">
+            |<"d"> ::= "d""#.trim_margin().unwrap());
+        assert!(cnf.derives_empty("a"));
+        assert!(!cnf.derives_empty("s"));
+
+        let normal = |terms: &[Term]| matches!(terms, [ Term::Terminal(_) ] | [ Term::Nonterminal(_), Term::Nonterminal(_) ]);
+        let g = grammar(include_str!("../tests/superhard.bnf"));
+        assert!(g.to_cnf().grammar.productions.iter().flat_map(|p| &p.rhs).all(|e| normal(&e.terms)));
+    }
+
+    #[test]
+    fn parse_test() {
+        let cases: &[(&str, &[&str])] = &[
+            (r#"<e> ::= <e> "+" <e> | <e> "*" <e> | "n""#, &[ "n", "+", "n", "*", "n", "+", "n" ]),
+            (r#"
+                <s> ::= <a> <s> "b" | "x" <c> <c>
+                <a> ::= ε
+                <c> ::= ε | "c"
+            "#, &[ "x", "c", "b", "b" ]),
+            (r#"
+                <list> ::= "[" [<item> ("," <item>)*] "]"
+                <item> ::= "a"+ | <list> | <wrapped>
+                <wrapped> ::= <item>
+            "#, &[ "[", "a", "a", ",", "[", "]", "]" ]),
+        ];
+        for (text, t) in cases {
+            let g = grammar(text);
+            let t = tokens(t);
+            let cyk = parse_cyk_forest(make_ctx(&g, &t, false, true)).unwrap();
+            let earley = parse_forest(make_ctx(&g, &t, false, true)).unwrap();
+            assert_eq!(cyk.count_derivations(), earley.count_derivations());
+            if let DerivationCount::Finite(_) = cyk.count_derivations() {
+                assert_eq!(trees(&g, &t, Engine::Cyk), trees(&g, &t, Engine::Earley));
+            }
+        }
+    }
+
+    #[test]
+    fn empty_test() {
+        let g = grammar(r#"
+            <args> ::= ε | <arg> <args>
+            <arg>  ::= "a"
+        "#);
+        assert_eq!(trees(&g, &vec![], Engine::Cyk), trees(&g, &vec![], Engine::Earley));
+        assert_eq!(trees(&g, &tokens(&[ "a", "a" ]), Engine::Cyk), trees(&g, &tokens(&[ "a", "a" ]), Engine::Earley));
+    }
+
+    #[test]
+    fn error_test() {
+        // position and expected terminals come from the CYK table and agree with the Earley chart
+        let g = grammar(r#"
+            <s> ::= <e> | "let" <e> "=" <e> ";"
+            <e> ::= <e> "+" <e> | "n" | "(" <e> ")"
+        "#);
+        for (t, position, expected) in [
+            (&[ "n", "+", "+", "n" ][..], 2, &[ "(", "n" ][..]),
+            (&[ "n", "+" ], 2, &[ "(", "n" ]),
+            (&[ "let", "n", ";" ], 2, &[ "+", "=" ]),
+            (&[ "(", "n", "+", "n", "n" ], 4, &[ ")", "+" ]),
+            (&[ ")" ], 0, &[ "(", "let", "n" ]),
+        ] {
+            let t = tokens(t);
+            match (parse_cyk_forest(make_ctx(&g, &t, false, true)), parse_forest(make_ctx(&g, &t, false, true))) {
+                (Err(Error::Syntax(cyk)), Err(Error::Syntax(earley))) => {
+                    assert_eq!((cyk.position, &cyk.expected), (earley.position, &earley.expected));
+                    assert_eq!(cyk.position, position);
+                    assert_eq!(cyk.expected, expected.iter().map(|e| e.to_string()).collect());
+                    assert_eq!(cyk.production_stack[0], "s");
+                },
+                other => panic!("unexpected result {:?}", other.0.map(|f| f.nodes)),
+            }
+        }
+    }
+}
@@ -1,120 +1,170 @@
-use std::{collections::{VecDeque}};
+use std::iter::Fuse;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Combination {
     pub marks: Vec<usize>
 }
 
-struct Comb<T> {
-    values: Vec<T>
+/// lazy cartesian product. every inner iterator is pulled only as far as the combinations
+/// yielded so far need it, and the outer one stops at the first empty inner iterator
+///
+/// 1|2, 3|4, 5
+///
+/// (1, 3, 5),   (1, 4, 5),   (2, 3, 5),   (2, 4, 5)
+pub struct ExpandCombinations<O, I: Iterator> {
+    outer: O,
+    /// inner iterators pulled so far with the items they yielded
+    inner: Vec<(Fuse<I>, Vec<I::Item>)>,
+    /// item of every inner iterator in the current combination, the last one changes first
+    indices: Vec<usize>,
+    started: bool,
+    done: bool,
 }
 
-impl<T: Clone> Comb<T> {
-    fn clone_with(&self, v: T) -> Comb<T> {
-        let mut c = Comb { values: self.values.clone() };
-        c.values.push(v);
-        c
+impl<O: Iterator<Item = I>, I: Iterator> ExpandCombinations<O, I> {
+    pub fn new(outer: O) -> Self {
+        Self { outer, inner: vec![], indices: vec![], started: false, done: false }
+    }
+
+    fn start(&mut self) -> bool {
+        for inner in &mut self.outer {
+            let mut inner = inner.fuse();
+            match inner.next() {
+                Some(first) => self.inner.push((inner, vec![ first ])),
+                None => return false,
+            }
+        }
+        self.indices = vec![0; self.inner.len()];
+        !self.inner.is_empty()
+    }
+
+    fn advance(&mut self) -> bool {
+        for k in (0..self.inner.len()).rev() {
+            let (inner, items) = &mut self.inner[k];
+            let next = self.indices[k] + 1;
+            if next < items.len() || inner.next().map(|item| items.push(item)).is_some() {
+                self.indices[k] = next;
+                return true
+            }
+            self.indices[k] = 0;
+        }
+        false
     }
 }
 
-/// 1|2, 3|4, 5
-/// 
-/// initial:
-/// (1, ...)
-/// (1, ...), (2, ...)
-/// continuous:
-/// (2, ...),    (1, 3, ...), (1, 4, ...)
-/// (1, 3, ...), (1, 4, ...), (2, 3, ...), (2, 4, ...)
-/// (1, 4, ...), (2, 3, ...), (2, 4, ...), (1, 3, 5)
-/// (2, 3, ...), (2, 4, ...), (1, 3, 5),   (1, 4, 5)
-/// (2, 4, ...), (1, 3, 5),   (1, 4, 5),   (2, 3, 5)
-/// (1, 3, 5),   (1, 4, 5),   (2, 3, 5),   (2, 4, 5)
-/// result:
-/// (1, 3, 5),   (1, 4, 5),   (2, 3, 5),   (2, 4, 5)
+impl<O: Iterator<Item = I>, I: Iterator> Iterator for ExpandCombinations<O, I> where I::Item: Clone {
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None
+        }
+        let found = if self.started {
+            self.advance()
+        } else {
+            self.started = true;
+            self.start()
+        };
+        if !found {
+            self.done = true;
+            return None
+        }
+        Some(self.indices.iter().zip(&self.inner).map(|(&i, (_, items))| items[i].clone()).collect())
+    }
+}
+
+#[cfg(test)]
 pub fn expand_combinations<T>(input: Vec<Vec<T>>) -> Vec<Vec<T>> 
 where
     T: Clone,
 {
-    
+    ExpandCombinations::new(input.into_iter().map(|x| x.into_iter())).collect()
+}
 
-    let mut deq: VecDeque<Comb<T>> = VecDeque::new();
+pub fn expand_combinations_iter<T: Clone>(input: impl Iterator<Item = impl Iterator<Item = T>>) -> impl Iterator<Item = impl Iterator<Item = T>> {
+    ExpandCombinations::new(input).map(|x| x.into_iter())
+}
 
-    if input.len() > 0 {
-        for v in input[0].clone() {
-            deq.push_back(Comb { values: vec![ v ] });
-        }
+/// lazy split points of `begin..end` into `bounds.len()` consecutive ranges in lexicographic order.
+/// range `k` gets from `bounds[k].0` to `bounds[k].1` tokens, `usize::MAX` being unbounded
+pub struct Splits {
+    begin: usize,
+    end: usize,
+    bounds: Vec<(usize, usize)>,
+    /// shortest and longest total length of the ranges from `k` on
+    rest: Vec<(usize, usize)>,
+    /// `marks[k]` is where range `k + 1` begins
+    marks: Vec<usize>,
+    started: bool,
+    done: bool,
+}
 
-        while let Some(front) = deq.pop_front() {
-            if front.values.len() < input.len() {
-                for to_append in input[front.values.len()].clone() {
-                    deq.push_back(front.clone_with(to_append))
-                }            
-            } else {
-                deq.push_front(front);
-                break;
+impl Splits {
+    pub fn new(begin: usize, end: usize, bounds: Vec<(usize, usize)>) -> Self {
+        let mut rest = vec![(0usize, 0usize); bounds.len() + 1];
+        for k in (0..bounds.len()).rev() {
+            rest[k] = (rest[k + 1].0.saturating_add(bounds[k].0), rest[k + 1].1.saturating_add(bounds[k].1));
+        }
+        let len = end.saturating_sub(begin);
+        let done = bounds.is_empty() || end < begin || len < rest[0].0 || len > rest[0].1;
+        let mut result = Self { begin, end, bounds, rest, marks: vec![], started: false, done };
+        if !result.done {
+            let mut previous = begin;
+            for k in 0..result.bounds.len() - 1 {
+                previous = result.range(k, previous).start;
+                result.marks.push(previous);
             }
         }
-        deq.into_iter().map(|c| c.values).collect()
-    } else {
-        Vec::new()
+        result
     }
-}
 
-pub fn expand_combinations_iter<T: Clone>(input: impl Iterator<Item = impl Iterator<Item = T>>) -> impl Iterator<Item = impl Iterator<Item = T>> {
-    if true {
-        let r = expand_combinations(input.map(|x| x.collect::<Vec<_>>()).collect::<Vec<_>>())
-            .into_iter()
-            .map(|x|x.into_iter());
-        r
-    } else {
-        todo!()
-        //ExpandCombinationsOuterIter { input: input }
+    /// where range `k` begins
+    fn previous(&self, k: usize) -> usize {
+        if k == 0 { self.begin } else { self.marks[k - 1] }
+    }
+
+    /// positions mark `k` can take when range `k` begins at `previous`,
+    /// never empty as long as the ranges from `k` on can cover `previous..end`
+    fn range(&self, k: usize, previous: usize) -> std::ops::Range<usize> {
+        let (min, max) = self.bounds[k];
+        let (rest_min, rest_max) = self.rest[k + 1];
+        let low = previous.saturating_add(min).max(self.end.saturating_sub(rest_max));
+        let high = previous.saturating_add(max).min(self.end - rest_min);
+        low..(high + 1)
     }
 }
 
-pub fn generate_combinations(begin: usize, end: usize, count: usize) -> Vec<Combination> {
+impl Iterator for Splits {
+    type Item = Combination;
 
-    if end - begin < count {
-        vec![]
-    } else if count == 0 {
-        vec![ Combination { marks: vec![] } ]
-    } else {                
-        let mut marks: Vec<usize> = Vec::new();
-        for i in 0..count {
-            marks.push(begin + i);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None
         }
-
-        let mut result: Vec<Combination> = Vec::new();
-        
-        loop {
-            if marks[marks.len() - 1] < end {
-                //println!("m: {:?}", marks.clone());
-                result.push(Combination { marks: marks.clone() });
-            }
-            
-            let mut moved: bool = false;
-            for i in (0..count).rev() {
-                if marks[i] < end {
-                    marks[i] += 1;
-
-                    for (offset, j) in ((i + 1)..count).enumerate() {
-                        marks[j] = marks[i] + offset + 1;
-                    }
-
-                    moved = true;
-                    break;
-                } else {
-                    //marks[i] += 1;
-                }
-            }
-            if !moved {
-                break;
+        if self.started {
+            // the rightmost mark which can still move, the ones after it restart as close behind it as possible
+            let Some(k) = (0..self.marks.len())
+                .rev()
+                .find(|&k| self.marks[k] + 1 < self.range(k, self.previous(k)).end)
+            else {
+                self.done = true;
+                return None
+            };
+            self.marks[k] += 1;
+            for j in (k + 1)..self.marks.len() {
+                self.marks[j] = self.range(j, self.marks[j - 1]).start;
             }
         }
-        result            
+        self.started = true;
+        Some(Combination { marks: self.marks.clone() })
     }
 }
 
+/// `count` strictly increasing marks in `begin..end`
+pub fn generate_combinations(begin: usize, end: usize, count: usize) -> Splits {
+    Splits::new(begin, end, std::iter::once((0, usize::MAX)).chain(std::iter::repeat_n((1, usize::MAX), count)).collect())
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -123,7 +173,7 @@ mod tests {
     #[test]
     fn generate_combinations_test() {
         assert_eq!(
-            generate_combinations(2, 6, 3),
+            generate_combinations(2, 6, 3).collect::<Vec<_>>(),
             vec![
                 Combination { marks: vec![2, 3, 4] }, 
                 Combination { marks: vec![2, 3, 5] }, 
@@ -136,11 +186,47 @@ mod tests {
     #[test]
     fn generate_combinations_invalid_test() {
         assert_eq!(
-            generate_combinations(2, 4, 3),
+            generate_combinations(2, 4, 3).collect::<Vec<_>>(),
             vec![]
         )
     }
 
+    #[test]
+    fn splits_test() {
+        let nullable = |n: &[bool]| n.iter().map(|&n| (if n { 0 } else { 1 }, usize::MAX)).collect::<Vec<_>>();
+        assert_eq!(
+            Splits::new(2, 6, nullable(&[ false, false, false ])).collect::<Vec<_>>(),
+            vec![
+                Combination { marks: vec![3, 4] }, 
+                Combination { marks: vec![3, 5] }, 
+                Combination { marks: vec![4, 5] }, 
+            ]
+        );
+        assert_eq!(
+            Splits::new(0, 2, nullable(&[ true, false, true ])).collect::<Vec<_>>(),
+            vec![
+                Combination { marks: vec![0, 1] }, 
+                Combination { marks: vec![0, 2] }, 
+                Combination { marks: vec![1, 2] }, 
+            ]
+        );
+        assert_eq!(
+            Splits::new(3, 3, nullable(&[ true, true ])).collect::<Vec<_>>(),
+            vec![ Combination { marks: vec![3] } ]
+        );
+        assert_eq!(Splits::new(3, 3, nullable(&[ true, false ])).next(), None);
+
+        // lengths 1..=2, exactly 1 and 2..=3
+        assert_eq!(
+            Splits::new(0, 5, vec![ (1, 2), (1, 1), (2, 3) ]).collect::<Vec<_>>(),
+            vec![
+                Combination { marks: vec![1, 2] }, 
+                Combination { marks: vec![2, 3] }, 
+            ]
+        );
+        assert_eq!(Splits::new(0, 7, vec![ (1, 2), (1, 1), (2, 3) ]).next(), None);
+    }
+
     #[test]
     fn expand_combinations_test() {
         // 1|2, 3|4, 5
@@ -182,4 +268,29 @@ mod tests {
             ]
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn lazy_test() {
+        assert_eq!(
+            generate_combinations(0, usize::MAX / 2, 3).nth(2),
+            Some(Combination { marks: vec![ 0, 1, 4 ] })
+        );
+
+        let pulled = std::cell::Cell::new(0);
+        let counted = |v: Vec<usize>| v.into_iter().inspect(|_| pulled.set(pulled.get() + 1));
+        let mut expand = ExpandCombinations::new(vec![ counted(vec![ 1, 2, 3 ]), counted(vec![ 4, 5, 6 ]) ].into_iter());
+        assert_eq!(expand.next(), Some(vec![ 1, 4 ]));
+        assert_eq!(pulled.get(), 2);
+        assert_eq!(expand.next(), Some(vec![ 1, 5 ]));
+        assert_eq!(pulled.get(), 3);
+
+        // the inner iterators after an empty one are never created
+        let created = std::cell::Cell::new(0);
+        let outer = (0..3).map(|i| {
+            created.set(created.get() + 1);
+            if i == 1 { vec![] } else { vec![ i ] }.into_iter()
+        });
+        assert_eq!(ExpandCombinations::new(outer).next(), None);
+        assert_eq!(created.get(), 2);
+    }
+}
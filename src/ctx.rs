@@ -1,14 +1,25 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::fmt::Debug;
-use std::mem::MaybeUninit;
 use std::ops::Range;
+use std::rc::Rc;
 
-use crate::Production;
+use crate::{Production, Term};
+use crate::analysis::Analysis;
+use crate::error::Error;
+use crate::lexer::Span;
+use crate::tree::ParseTreeNode;
 use crate::{combination::*, grammar::Grammar};
 
 
 pub trait Token: Debug + Display + Clone {
     fn name(&self) -> &str;
+
+    /// where the token is in the source text, unknown by default
+    fn span(&self) -> Option<Span> {
+        None
+    }
 }
 
 impl Token for String {
@@ -17,63 +28,164 @@ impl Token for String {
     }
 }
 
-#[derive(Clone, Copy)]
-pub(crate) struct Arr<T> {
-    arr: [T; 128],
-    len: usize
+/// persistent stack of the productions being expanded with the token range of each expansion.
+/// pushing shares the tail with the parent ctx, so cloning a ctx for every split only bumps a reference count
+#[derive(Clone, Default)]
+pub(crate) struct ProdStack<'g> {
+    top: Option<Rc<Frame<'g>>>,
 }
 
-impl<T> Default for Arr<T> {
-    fn default() -> Self {
-        Self { 
-            arr: unsafe { MaybeUninit::uninit().assume_init() }, 
-            len: 0 
-        }
-    }
+struct Frame<'g> {
+    production: &'g Production,
+    range: Range<usize>,
+    below: ProdStack<'g>,
+    len: usize,
 }
 
-impl<T> Arr<T> {
-    pub fn with(mut self, v: T) -> Result<Self, String> {
-        if self.len < 128 {
-            self.arr[self.len] = v;
-            self.len += 1;
-            Ok(self)
-        } else {
-            Err("max capacity exceeded".to_string())
-        }
+impl<'g> ProdStack<'g> {
+    pub fn with(&self, production: &'g Production, range: Range<usize>) -> Self {
+        Self { top: Some(Rc::new(Frame { production, range, below: self.clone(), len: self.len() + 1 })) }
     }
 
     pub fn len(&self) -> usize {
-        self.len
+        self.top.as_ref().map_or(0, |frame| frame.len)
     }
 
-    pub fn back(&self) -> Option<&T> {
-        if self.len > 0 {
-            Some(&self.arr[self.len])
-        } else {
-            None
+    /// last pushed production
+    pub fn back(&self) -> Option<&'g Production> {
+        self.top.as_ref().map(|frame| frame.production)
+    }
+
+    /// expansions from the last pushed one down to the first one
+    pub fn iter(&self) -> impl Iterator<Item = (&'g Production, &Range<usize>)> + '_ {
+        std::iter::successors(self.top.as_deref(), |frame| frame.below.top.as_deref())
+            .map(|frame| (frame.production, &frame.range))
+    }
+
+    /// expansions on the same token range as the last pushed one. a term only gets the whole range
+    /// of its expression when the other terms are empty, so only these can lead back to the top
+    pub fn same_range(&self) -> impl Iterator<Item = (&'g Production, &Range<usize>)> + '_ {
+        let range = self.top.as_ref().map(|frame| frame.range.clone());
+        self.iter().take_while(move |(_, r)| Some(*r) == range.as_ref())
+    }
+
+    /// the last pushed production already is being expanded on the same range, so any derivation
+    /// from here is a cycle `<a> =>+ <a>` which adds nothing but infinitely many trees
+    pub fn is_cycle(&self) -> bool {
+        match self.back() {
+            Some(top) => self.same_range().skip(1).any(|(p, _)| std::ptr::eq(p, top)),
+            None => false,
         }
-    } 
-    pub fn head(&self) -> &[T] {
-        if self.len > 0 {
-            &self.arr[0..self.len - 1]
-        } else {
-            &[]
+    }
+}
+
+impl<'g> Drop for ProdStack<'g> {
+    // unlinks uniquely owned frames one by one instead of recursively
+    fn drop(&mut self) {
+        let mut top = self.top.take();
+        while let Some(frame) = top {
+            match Rc::try_unwrap(frame) {
+                Ok(mut frame) => top = frame.below.top.take(),
+                Err(_) => break,
+            }
         }
-    } 
+    }
 }
 
-impl<'g> Display for Arr<&'g Production> {
+impl<'g> Display for ProdStack<'g> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut dbg_list = f.debug_list();
-        for i in 0..self.len {
-            dbg_list.entry(&self.arr[i].lhs);
+        let mut frames: Vec<_> = self.iter().map(|(p, r)| format!("{} {:?}", p.lhs, r)).collect();
+        frames.reverse();
+        f.debug_list().entries(frames).finish()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/// production, token range and the expansions on the same range the result was computed under.
+/// those are the only ones the cycle guard could have cut from it
+pub(crate) type MemoKey = (*const Production, usize, usize, Vec<*const Production>);
+
+type MemoNodes<'t, 'g, T> = Box<dyn Iterator<Item = Result<ParseTreeNode<'t, 'g, T>, Error>> + 't>;
+
+/// results of a memo key pulled from `source` only as far as the furthest reader got
+pub(crate) struct Replay<'t, 'g, T> {
+    nodes: Vec<Result<ParseTreeNode<'t, 'g, T>, Error>>,
+    /// `None` once exhausted
+    source: Option<MemoNodes<'t, 'g, T>>,
+}
+
+/// reads a memo entry from its first result on, every reader sees the same sequence
+pub(crate) struct ReplayIter<'t, 'g, T> {
+    entry: Rc<RefCell<Replay<'t, 'g, T>>>,
+    index: usize,
+}
+
+impl<'t, 'g, T: Clone> Iterator for ReplayIter<'t, 'g, T> {
+    type Item = Result<ParseTreeNode<'t, 'g, T>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(node) = self.entry.borrow().nodes.get(self.index) {
+            self.index += 1;
+            return Some(node.clone())
         }
-        dbg_list.finish()
+        // the source is taken out while pulling, as it recurses into other entries
+        let mut source = self.entry.borrow_mut().source.take()?;
+        let node = source.next()?;
+        let mut entry = self.entry.borrow_mut();
+        entry.nodes.push(node.clone());
+        entry.source = Some(source);
+        self.index += 1;
+        Some(node)
     }
 }
 
-#[derive(Clone, Copy)]
+/// sub-range results of `do_term` shared by every ctx of a single parse
+pub(crate) struct Memo<'t, 'g, T> {
+    table: HashMap<MemoKey, Rc<RefCell<Replay<'t, 'g, T>>>>,
+    stats: MemoStats,
+    /// some branch was cut by `Ctx::max_depth`
+    pub depth_exceeded: bool,
+    /// see `Ctx::analysis`
+    analysis: Option<Rc<Analysis>>,
+}
+
+impl<'t, 'g, T> Default for Memo<'t, 'g, T> {
+    fn default() -> Self {
+        Self { table: HashMap::new(), stats: Default::default(), depth_exceeded: false, analysis: None }
+    }
+}
+
+impl<'t, 'g, T> Memo<'t, 'g, T> {
+    pub fn get(&mut self, key: &MemoKey) -> Option<ReplayIter<'t, 'g, T>> {
+        let result = self.table.get(key).map(|entry| ReplayIter { entry: entry.clone(), index: 0 });
+        if result.is_some() {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+        }
+        result
+    }
+
+    /// stores the results of `source` as they are read through the returned iterator
+    pub fn insert(&mut self, key: MemoKey, source: MemoNodes<'t, 'g, T>) -> ReplayIter<'t, 'g, T> {
+        let entry = Rc::new(RefCell::new(Replay { nodes: vec![], source: Some(source) }));
+        self.table.insert(key, entry.clone());
+        ReplayIter { entry, index: 0 }
+    }
+
+    /// unread sources hold ctxs which hold the memo, taking the entries out breaks that cycle.
+    /// they are returned to be dropped after the memo is released
+    pub fn take_entries(&mut self) -> Vec<Rc<RefCell<Replay<'t, 'g, T>>>> {
+        self.table.drain().map(|(_, entry)| entry).collect()
+    }
+}
+
+#[derive(Clone)]
 pub struct Ctx<'t, 'g, T> {
     pub begin: usize,
     pub end: usize,
@@ -82,15 +194,13 @@ pub struct Ctx<'t, 'g, T> {
     pub level: usize,
     pub logs_enabled: bool,
     pub ignore_errors: bool,
-    pub(crate) prod_stack: Arr<&'g Production> // needed to avoid production recursion
+    /// derivation depth at which the backtracking engine gives up with `Error::MaxLevelReached`, unlimited if `None`
+    pub max_depth: Option<usize>,
+    pub(crate) prod_stack: ProdStack<'g>, // needed to cut cyclic derivations
+    pub(crate) memo: Rc<RefCell<Memo<'t, 'g, T>>>
 }
 
 impl<'t, 'g, T> Ctx<'t, 'g, T> {
-    pub fn reset_stack(mut self) -> Self {
-        self.prod_stack = Default::default();
-        self
-    }
-    
     pub fn next_level(&self, production: &'g Production) -> Ctx<'t, 'g, T> {
         Ctx {
             begin: self.begin, 
@@ -100,7 +210,9 @@ impl<'t, 'g, T> Ctx<'t, 'g, T> {
             level: self.level + 1,
             logs_enabled: self.logs_enabled,
             ignore_errors: self.ignore_errors,
-            prod_stack: self.prod_stack.with(production).unwrap()
+            max_depth: self.max_depth,
+            prod_stack: self.prod_stack.with(production, self.begin..self.end),
+            memo: self.memo.clone()
         }
     }
 
@@ -113,81 +225,85 @@ impl<'t, 'g, T> Ctx<'t, 'g, T> {
             level: self.level,
             logs_enabled: self.logs_enabled,
             ignore_errors: self.ignore_errors,
-            prod_stack: self.prod_stack
+            max_depth: self.max_depth,
+            prod_stack: self.prod_stack.clone(),
+            memo: self.memo.clone()
         }
     }
 
+    /// ranges between the marks of the combination, the first one starting at `begin` and the last one ending at `end`
     pub fn split(&self, combination: Combination) -> Vec<Ctx<'t, 'g, T>> {
-        let mut result: Vec<Ctx<T>> = Vec::with_capacity(combination.marks.len());
-
-        if combination.marks.len() > 0 {
-            if combination.marks[0] > self.begin {
-                result.push(Ctx {
-                    begin: self.begin,
-                    end: combination.marks[0],
-                    tokens: self.tokens,
-                    grammar: self.grammar,
-                    level: self.level,
-                    logs_enabled: self.logs_enabled,
-                    ignore_errors: self.ignore_errors,
-                    prod_stack: self.prod_stack
-                })
-            }
-        } else {
-            result.push(Ctx {
-                begin: self.begin,
-                end: self.end,
-                tokens: self.tokens,
-                grammar: self.grammar,
-                level: self.level,
-                logs_enabled: self.logs_enabled,
-                ignore_errors: self.ignore_errors,
-                prod_stack: self.prod_stack
-            })
-        }
-
-        for i in 0..combination.marks.len() {
-
-            if i + 1 < combination.marks.len() {
-                result.push(Ctx {
-                    begin: combination.marks[i],
-                    end: combination.marks[i + 1],
-                    tokens: self.tokens,
-                    grammar: self.grammar,
-                    level: self.level,
-                    logs_enabled: self.logs_enabled,
-                    ignore_errors: self.ignore_errors,
-                    prod_stack: self.prod_stack
-                })
-            } else if combination.marks[i] < self.end {
-                result.push(Ctx {
-                    begin: combination.marks[i],
-                    end: self.end,
-                    tokens: self.tokens,
-                    grammar: self.grammar,
-                    level: self.level,
-                    logs_enabled: self.logs_enabled,
-                    ignore_errors: self.ignore_errors,
-                    prod_stack: self.prod_stack
-                })
-            }
-        }
-        result
+        std::iter::once(self.begin)
+            .chain(combination.marks.iter().copied())
+            .zip(combination.marks.iter().copied().chain(std::iter::once(self.end)))
+            .map(|(begin, end)| self.at(begin..end))
+            .collect()
     }
 
-    pub fn combinations(&self, subctx_count: usize) -> Vec<Combination> {
+    pub fn combinations(&self, subctx_count: usize) -> Splits {
         generate_combinations(self.begin + 1, self.end, subctx_count - 1)
     }
 
+    /// length bounds and first and last terminals of the grammar. computed once per parse
+    pub(crate) fn analysis(&self) -> Rc<Analysis> {
+        self.memo.borrow_mut().analysis.get_or_insert_with(|| Rc::new(Analysis::new(self.grammar))).clone()
+    }
+
     pub fn len(&self) -> usize {
         return self.end - self.begin
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.begin == self.end
+    }
+
+    pub(crate) fn memo_key(&self, production: &'g Production) -> MemoKey {
+        (
+            production as *const Production,
+            self.begin,
+            self.end,
+            self.prod_stack.same_range().map(|(p, _)| p as *const Production).collect()
+        )
+    }
+
+    /// memo hits and misses of the parse this ctx belongs to. all ctxs derived from the one
+    /// returned by `make_ctx` share the table, so keep a clone to read it after parsing
+    pub fn memo_stats(&self) -> MemoStats {
+        self.memo.borrow().stats
+    }
+
     pub fn front(&self) -> &'t T {
         &self.tokens[self.begin]
     }
 }
 
+impl<'t, 'g, T: Token> Ctx<'t, 'g, T> {
+    /// splits of the range between `terms` which `Analysis` does not rule out: every range fits
+    /// the length bounds of its term and starts and ends with tokens the term can start and end with
+    pub fn term_combinations<'a>(&self, terms: &'a [Term]) -> Box<dyn Iterator<Item = Combination> + 'a>
+    where
+        't: 'a,
+        T: 'a,
+    {
+        let analysis = self.analysis();
+        // an undefined nonterminal stays unbounded, so parsing it reports `ProductionNotFound`
+        let undefined = |analysis: &Analysis, term: &Term| matches!(term, Term::Nonterminal(n) if analysis.production(n).is_none());
+        let bounds = terms
+            .iter()
+            .map(|t| if undefined(&analysis, t) { (0, usize::MAX) } else { analysis.bounds(t) })
+            .collect();
+        let tokens = self.tokens;
+        let (begin, end) = (self.begin, self.end);
+        Box::new(Splits::new(begin, end, bounds).filter(move |combination| {
+            let starts = std::iter::once(begin).chain(combination.marks.iter().copied());
+            let ends = combination.marks.iter().copied().chain(std::iter::once(end));
+            terms.iter().zip(starts.zip(ends)).all(|(term, (b, e))| b == e || undefined(&analysis, term) || (
+                analysis.can_start(term, tokens[b].name()) && analysis.can_end(term, tokens[e - 1].name())
+            ))
+        }))
+    }
+}
+
 impl<'t, 'g, T> PartialEq for Ctx<'t, 'g, T> {
     fn eq(&self, other: &Self) -> bool {
         self.begin == other.begin && 
@@ -255,18 +371,84 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::grammar::Grammar;
-    use super::Ctx;
+    use crate::combination::Combination;
+    use crate::grammar::{ExtGrammar, Grammar, Production};
+    use super::{Ctx, ProdStack};
+
+    fn production(lhs: &str) -> Production {
+        Production { lhs: lhs.to_string(), rhs: vec![] }
+    }
+
+    #[test]
+    fn prod_stack_test() {
+        let (a, b, c) = (production("a"), production("b"), production("c"));
+        let empty = ProdStack::default();
+        assert_eq!(empty.len(), 0);
+        assert!(empty.back().is_none());
+        assert!(!empty.is_cycle());
+
+        let ab = empty.with(&a, 0..4).with(&b, 0..2);
+        let abc = ab.with(&c, 0..2);
+        let ad = empty.with(&a, 0..4).with(&c, 1..4);
+
+        assert_eq!(ab.len(), 2);
+        assert_eq!(ab.back().map(|p| p.lhs.as_str()), Some("b"));
+        assert_eq!(abc.same_range().map(|(p, _)| p.lhs.as_str()).collect::<Vec<_>>(), vec![ "c", "b" ]);
+        assert_eq!(format!("{}", abc), r#"["a 0..4", "b 0..2", "c 0..2"]"#);
+        assert_eq!(format!("{}", ad), r#"["a 0..4", "c 1..4"]"#);
+
+        // pushing onto a shared stack leaves the original intact
+        drop(ab);
+        assert_eq!(format!("{}", abc), r#"["a 0..4", "b 0..2", "c 0..2"]"#);
+
+        assert!(abc.with(&b, 0..2).is_cycle());
+        assert!(!abc.with(&b, 0..1).is_cycle());
+        assert!(!abc.with(&a, 0..2).is_cycle());
+    }
+
+    #[test]
+    fn prod_stack_drop_test() {
+        // deep enough to overflow a recursive drop, Miri checks a shorter stack in reasonable time
+        let len = if cfg!(miri) { 1_000 } else { 1_000_000 };
+        let a = production("a");
+        let mut stack = ProdStack::default();
+        for _ in 0..len {
+            stack = stack.with(&a, 0..1);
+        }
+        let shared = stack.clone();
+        drop(stack);
+        assert_eq!(shared.len(), len);
+    }
+
+    #[test]
+    fn term_combinations_test() {
+        let g: ExtGrammar = r#"
+            <call> ::= <name> "(" <args> ")"
+            <name> ::= "f" | "g"
+            <args> ::= "x" | "x" "," <args>
+        "#.try_into().unwrap();
+        let g = g.flatten();
+        let tokens: Vec<String> = [ "f", "(", "x", ",", "x", ",", "x", ")" ].iter().map(|t| t.to_string()).collect();
+        let ctx = Ctx { begin: 0, end: 8, tokens: &tokens, grammar: &g, level: 0, logs_enabled: false, ignore_errors: false, max_depth: None, prod_stack: Default::default(), memo: Default::default() };
+
+        // 35 splits of 8 tokens into 4 ranges, only one fits the first and last terminals
+        assert_eq!(ctx.combinations(4).count(), 35);
+        assert_eq!(
+            ctx.term_combinations(&g.productions[0].rhs[0].terms).collect::<Vec<_>>(),
+            vec![ Combination { marks: vec![ 1, 2, 7 ] } ]
+        );
+        // `x` covers exactly one token
+        assert_eq!(ctx.at(2..7).term_combinations(&g.productions[2].rhs[1].terms).count(), 1);
+    }
 
     #[test]
     fn split_ctx_test() {
         let tokens: Vec<String> = Vec::new();
-        let grammar = Grammar { productions: vec![] };
-        let ctx = Ctx { begin: 4, end: 9, tokens: &tokens, grammar: &grammar, level: 0, logs_enabled: true, ignore_errors: false, prod_stack: Default::default() };
+        let grammar = Grammar::default();
+        let ctx = Ctx { begin: 4, end: 9, tokens: &tokens, grammar: &grammar, level: 0, logs_enabled: true, ignore_errors: false, max_depth: None, prod_stack: Default::default(), memo: Default::default() };
 
         let combinations: Vec<_> = ctx
             .combinations(3)
-            .into_iter()
             .map(|combination| ctx.split(combination))
             .collect();
 
@@ -314,12 +496,11 @@ mod tests {
     #[test]
     fn split_ctx_test2() {
         let tokens: Vec<String> = Vec::new();
-        let grammar = Grammar { productions: vec![] };
-        let ctx = Ctx { begin: 0, end: 7, tokens: &tokens, grammar: &grammar, level: 0, logs_enabled: true, ignore_errors: false, prod_stack: Default::default() };
+        let grammar = Grammar::default();
+        let ctx = Ctx { begin: 0, end: 7, tokens: &tokens, grammar: &grammar, level: 0, logs_enabled: true, ignore_errors: false, max_depth: None, prod_stack: Default::default(), memo: Default::default() };
 
         let combinations: Vec<_> = ctx
             .combinations(4)
-            .into_iter()
             .map(|combination| ctx.split(combination))
             .collect();
 
@@ -353,12 +534,11 @@ mod tests {
     #[test]
     fn split_ctx_into_same_test() {
         let tokens: Vec<String> = Vec::new();
-        let grammar = Grammar { productions: vec![] };
-        let ctx = Ctx { begin: 0, end: 7, tokens: &tokens, grammar: &grammar, level: 0, logs_enabled: true, ignore_errors: false, prod_stack: Default::default() };
+        let grammar = Grammar::default();
+        let ctx = Ctx { begin: 0, end: 7, tokens: &tokens, grammar: &grammar, level: 0, logs_enabled: true, ignore_errors: false, max_depth: None, prod_stack: Default::default(), memo: Default::default() };
 
         let combinations: Vec<_> = ctx
             .combinations(1)
-            .into_iter()
             .map(|combination| ctx.split(combination))
             .collect();
 
@@ -0,0 +1,394 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::grammar::*;
+use crate::ctx::*;
+use crate::error::*;
+use crate::parse::{ParseTreeIter, splice_synthetic, parse_failed};
+use crate::sppf::parse_forest_from;
+
+
+/// dotted rule `<lhs> ::= a . b` started at token `origin`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Item {
+    pub production: usize,
+    pub expression: usize,
+    pub dot: usize,
+    pub origin: usize,
+}
+
+impl Item {
+    fn advance(self) -> Self {
+        Self { dot: self.dot + 1, ..self }
+    }
+}
+
+pub(crate) struct Chart<'tg, T> {
+    pub grammar: &'tg Grammar,
+    pub tokens: &'tg Vec<T>,
+    /// absolute index of the first token covered by `sets[0]`
+    pub begin: usize,
+    /// `sets[i]` holds items ending at token `begin + i` in the order they were added
+    pub sets: Vec<Vec<Item>>,
+    index: Vec<HashSet<Item>>,
+    lookup: HashMap<&'tg str, usize>,
+    nullable: Vec<bool>,
+    pub start: usize,
+}
+
+/// first production for every lhs, same as `find` in the backtracking engine
+pub(crate) fn production_lookup(grammar: &Grammar) -> HashMap<&str, usize> {
+    let mut lookup = HashMap::new();
+    for (i, p) in grammar.productions.iter().enumerate() {
+        lookup.entry(p.lhs.as_str()).or_insert(i);
+    }
+    lookup
+}
+
+pub(crate) fn nullable_productions(grammar: &Grammar, lookup: &HashMap<&str, usize>) -> Vec<bool> {
+    let mut nullable = vec![false; grammar.productions.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, p) in grammar.productions.iter().enumerate() {
+            if nullable[i] {
+                continue;
+            }
+            let is_nullable = p.rhs.iter().any(|e| e.terms.iter().all(|t| match t {
+                Term::Terminal(_) => false,
+                Term::Nonterminal(n) => lookup.get(n.as_str()).is_some_and(|&j| nullable[j]),
+            }));
+            if is_nullable {
+                nullable[i] = true;
+                changed = true;
+            }
+        }
+    }
+    nullable
+}
+
+impl<'tg, T: Token> Chart<'tg, T> {
+    pub fn build(ctx: &Ctx<'tg, 'tg, T>, start: usize) -> Self {
+        let lookup = production_lookup(ctx.grammar);
+        let nullable = nullable_productions(ctx.grammar, &lookup);
+        let len = ctx.len();
+        let mut chart = Chart {
+            grammar: ctx.grammar,
+            tokens: ctx.tokens,
+            begin: ctx.begin,
+            sets: vec![Vec::new(); len + 1],
+            index: vec![HashSet::new(); len + 1],
+            lookup,
+            nullable,
+            start,
+        };
+
+        for expression in 0..ctx.grammar.productions[start].rhs.len() {
+            chart.add(0, Item { production: start, expression, dot: 0, origin: 0 });
+        }
+
+        for i in 0..=len {
+            let mut k = 0;
+            while k < chart.sets[i].len() {
+                let item = chart.sets[i][k];
+                k += 1;
+                match chart.next_term(item) {
+                    None => chart.complete(i, item),
+                    Some(Term::Nonterminal(name)) => chart.predict(i, item, name),
+                    Some(Term::Terminal(terminal)) => {
+                        if i < len && chart.tokens[chart.begin + i].name() == terminal {
+                            chart.add(i + 1, item.advance());
+                        }
+                    },
+                }
+            }
+            if ctx.logs_enabled {
+                println!("S{:<3}{}", i, VecDisplay { v: chart.sets[i].iter().map(|item| chart.display(*item)).collect() });
+            }
+        }
+        chart
+    }
+
+    fn add(&mut self, i: usize, item: Item) {
+        if self.index[i].insert(item) {
+            self.sets[i].push(item);
+        }
+    }
+
+    fn predict(&mut self, i: usize, item: Item, name: &str) {
+        if let Some(&production) = self.lookup.get(name) {
+            for expression in 0..self.grammar.productions[production].rhs.len() {
+                self.add(i, Item { production, expression, dot: 0, origin: i });
+            }
+            // Aycock & Horspool: nullable nonterminals are skipped right away
+            if self.nullable[production] {
+                self.add(i, item.advance());
+            }
+        }
+    }
+
+    fn complete(&mut self, i: usize, item: Item) {
+        let lhs = &self.grammar.productions[item.production].lhs;
+        let mut k = 0;
+        while k < self.sets[item.origin].len() {
+            let waiting = self.sets[item.origin][k];
+            k += 1;
+            if let Some(Term::Nonterminal(name)) = self.next_term(waiting) {
+                if name == lhs {
+                    self.add(i, waiting.advance());
+                }
+            }
+        }
+    }
+
+    pub fn next_term(&self, item: Item) -> Option<&'tg Term> {
+        self.grammar.productions[item.production].rhs[item.expression].terms.get(item.dot)
+    }
+
+    pub fn terms(&self, production: usize, expression: usize) -> &'tg Vec<Term> {
+        &self.grammar.productions[production].rhs[expression].terms
+    }
+
+    pub fn production_of(&self, name: &str) -> Option<usize> {
+        self.lookup.get(name).copied()
+    }
+
+    /// `i` and `j` are relative to `begin`
+    pub fn contains(&self, j: usize, item: Item) -> bool {
+        self.index[j].contains(&item)
+    }
+
+    /// `<production>` derives tokens `i..j`
+    pub fn is_completed(&self, production: usize, i: usize, j: usize) -> bool {
+        let p = &self.grammar.productions[production];
+        (0..p.rhs.len()).any(|expression| self.contains(j, Item {
+            production,
+            expression,
+            dot: p.rhs[expression].terms.len(),
+            origin: i
+        }))
+    }
+
+    /// `term` derives tokens `i..j`
+    pub fn derives(&self, term: &Term, i: usize, j: usize) -> bool {
+        match term {
+            Term::Terminal(terminal) => j == i + 1 && self.tokens[self.begin + i].name() == terminal,
+            Term::Nonterminal(name) => self.production_of(name).is_some_and(|p| self.is_completed(p, i, j)),
+        }
+    }
+
+    /// last token position with live items
+    pub fn reached(&self) -> usize {
+        (0..self.sets.len()).rev().find(|&i| !self.sets[i].is_empty()).unwrap_or(0)
+    }
+
+    /// diagnostic for the furthest position reached by any item
+    pub fn syntax_error(&self) -> SyntaxError {
+        let reached = self.reached();
+        let items = &self.sets[reached];
+        let expects_terminal = |item: &&Item| matches!(self.next_term(**item), Some(Term::Terminal(_)));
+
+        let expected = items
+            .iter()
+            .filter_map(|item| match self.next_term(*item) {
+                Some(Term::Terminal(terminal)) => Some(terminal.clone()),
+                _ => None,
+            })
+            .collect();
+
+        // walk from an item stuck at `reached` through the items waiting for its lhs up to the start
+        let mut production_stack = vec![];
+        let mut visited = HashSet::new();
+        let mut current = items.iter().find(expects_terminal).or(items.first()).copied();
+        while let Some(item) = current {
+            if !visited.insert(item) {
+                break;
+            }
+            let lhs = &self.grammar.productions[item.production].lhs;
+            production_stack.push(lhs.clone());
+            current = self.sets[item.origin]
+                .iter()
+                .find(|waiting| matches!(self.next_term(**waiting), Some(Term::Nonterminal(name)) if name == lhs))
+                .copied();
+        }
+        production_stack.reverse();
+
+        SyntaxError {
+            position: self.begin + reached,
+            expected,
+            production_stack,
+            tokens: self.tokens.iter().map(|t| t.name().to_string()).collect(),
+        }
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        self.is_completed(self.start, 0, self.sets.len() - 1)
+    }
+
+    fn display(&self, item: Item) -> String {
+        let terms = self.terms(item.production, item.expression);
+        let mut result = format!("<{}> ::=", self.grammar.productions[item.production].lhs);
+        for (i, term) in terms.iter().enumerate() {
+            if i == item.dot {
+                result += " .";
+            }
+            result += &format!(" {}", term);
+        }
+        if item.dot == terms.len() {
+            result += " .";
+        }
+        result + &format!(" @{}", item.origin)
+    }
+}
+
+/// Earley recognizer over the whole ctx range followed by lazy extraction of all derivations from the packed forest
+pub fn parse_earley<'tg, T: Token + 'tg>(ctx: Ctx<'tg, 'tg, T>) -> ParseTreeIter<'tg, T> {
+    parse_earley_from(ctx, 0)
+}
+
+pub(crate) fn parse_earley_from<'tg, T: Token + 'tg>(ctx: Ctx<'tg, 'tg, T>, start: usize) -> ParseTreeIter<'tg, T> {
+    if ctx.logs_enabled {
+        println!("input: {:?} <- {:#?}", &ctx.tokens[ctx.begin..ctx.end], ctx.grammar);
+    }
+
+    let ignore_errors = ctx.ignore_errors;
+    match parse_forest_from(ctx, start) {
+        Ok(forest) => splice_synthetic(forest.grammar, forest.into_trees()),
+        Err(err) => parse_failed(ignore_errors, err),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use trim_margin::MarginTrimmable;
+
+    use crate::grammar::{Grammar, ExtGrammar};
+    use crate::parse::{make_ctx, parse};
+    use crate::error::{Error, SyntaxError};
+
+    use super::parse_earley;
+
+    fn grammar(text: &str) -> Grammar {
+        let g: ExtGrammar = text.trim_margin().unwrap().as_str().try_into().unwrap();
+        g.flatten()
+    }
+
+    fn tokens(t: &[&str]) -> Vec<String> {
+        t.iter().map(|x| String::from(*x)).collect()
+    }
+
+    fn earley_trees(g: &Grammar, t: &Vec<String>) -> Vec<String> {
+        parse_earley(make_ctx(g, t, false, true))
+            .map(|t| format!("{:#}", t.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn left_recursion_test() {
+        let g = grammar(r#"
+            |<b> ::= <a> | <b> "." <a>
+            |<a> ::= "N"
+        "#);
+        let t = tokens(&[ "N", ".", "N", ".", "N" ]);
+
+        assert_eq!(earley_trees(&g, &t), vec![r#"
+            |b
+            |`b
+            |``b
+            |```a
+            |````N
+            |``.
+            |``a
+            |```N
+            |`.
+            |`a
+            |``N
+        "#.trim_margin().unwrap() + "\n"]);
+    }
+
+    #[test]
+    fn optional_test() {
+        let g = grammar(r#"
+            |<namespace> ::= "N" "{" <block>? "}"
+            |<block> ::= <subs> | <subs> ";" <block>
+            |<subs>  ::= "W"
+        "#);
+
+        assert_eq!(earley_trees(&g, &tokens(&[ "N", "{", "}" ])), vec![r#"
+            |namespace
+            |`N
+            |`{
+            |`}
+        "#.trim_margin().unwrap() + "\n"]);
+        assert_eq!(earley_trees(&g, &tokens(&[ "N", "{", "W", ";", "W", "}" ])).len(), 1);
+    }
+
+    #[test]
+    fn same_trees_as_backtracking_test() {
+        let g = grammar(r#"
+            |<e> ::= <e> "+" <e> | <e> "*" <e> | "n"
+        "#);
+        let t = tokens(&[ "n", "+", "n", "*", "n", "+", "n" ]);
+
+        let mut expected: Vec<_> = parse(make_ctx(&g, &t, false, true))
+            .map(|t| format!("{:#}", t.unwrap()))
+            .collect();
+        let mut actual = earley_trees(&g, &t);
+        expected.sort();
+        actual.sort();
+
+        assert_eq!(actual.len(), 5);
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn unit_cycle_test() {
+        let g = grammar(r#"
+            |<a> ::= <b> | "x"
+            |<b> ::= <a>
+        "#);
+
+        assert_eq!(earley_trees(&g, &tokens(&[ "x" ])), vec![ "a\n`x\n".to_string() ]);
+    }
+
+    #[test]
+    fn no_parse_test() {
+        let g = grammar(r#"
+            |<a> ::= <b> <b>
+            |<b> ::= "x" "y" | "x" "z"
+        "#);
+        let t = tokens(&[ "x", "y", "x", "x" ]);
+
+        assert_eq!(parse_earley(make_ctx(&g, &t, false, true)).count(), 0);
+        let result: Vec<_> = parse_earley(make_ctx(&g, &t, false, false)).collect();
+        assert_eq!(result, vec![ Err(Error::Syntax(SyntaxError {
+            position: 3,
+            expected: [ "y".to_string(), "z".to_string() ].into(),
+            production_stack: vec![ "a".to_string(), "b".to_string() ],
+            tokens: t.clone(),
+        })) ]);
+    }
+
+    #[test]
+    fn long_input_test() {
+        let g = grammar(r#"
+            |<block> ::= <subs> | <subs> ";" <block>
+            |<subs>  ::= <lhs> "=" <rhs>
+            |<lhs>   ::= "ID"
+            |<rhs>   ::= <expr> | <rhs> "." <expr>
+            |<expr>  ::= "W"
+        "#);
+        let mut t = vec![];
+        for i in 0..100 {
+            if i > 0 {
+                t.push(";");
+            }
+            t.extend([ "ID", "=", "W", ".", "W" ]);
+        }
+
+        let t = tokens(&t);
+        let mut trees = parse_earley(make_ctx(&g, &t, false, true));
+        assert!(trees.next().unwrap().is_ok());
+        assert!(trees.next().is_none());
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+
+use crate::ll1::Ll1Conflict;
+use crate::lr::LrConflict;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    GrammarIsEmpty,
+    ProductionNotFound(String),
+    /// start nonterminal given in `ParseOptions` has no production
+    StartNotFound(String),
+    ProductionRecursion(String),
+    /// derivation deeper than `ParseOptions::max_depth`
+    MaxLevelReached {
+        limit: usize,
+    },
+    /// terminal tried against a single token with another name
+    UnexpectedToken {
+        position: usize,
+        expected: String,
+        found: String,
+    },
+    /// terminal tried against a range of `len` tokens
+    TokenCount {
+        position: usize,
+        len: usize,
+        terminal: String,
+    },
+    /// no derivation of the whole input exists
+    Syntax(SyntaxError),
+    /// the input has a derivation the backtracking engine did not find, the Earley engine does
+    NoTreeFound,
+    /// the LL(1) table has conflicts and the parse was not allowed to fall back to the Earley engine
+    Ll1Conflicts(Vec<Ll1Conflict>),
+    /// same for the LALR(1) or LR(1) automaton
+    LrConflicts(Vec<LrConflict>),
+}
+
+/// diagnostic for the furthest point any derivation of the input could reach
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    /// index of the first token no derivation could consume (`tokens.len()` for an unexpected end of input)
+    pub position: usize,
+    /// terminals which would have been accepted at `position`
+    pub expected: BTreeSet<String>,
+    /// lhs of the productions being parsed at `position`, outermost first
+    pub production_stack: Vec<String>,
+    /// names of all input tokens
+    pub tokens: Vec<String>,
+}
+
+impl SyntaxError {
+    pub fn found(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+}
+
+/// tokens shown around the caret on each side
+const DIAGNOSTIC_CONTEXT: usize = 8;
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("syntax error at token {}: ", self.position))?;
+        match self.expected.len() {
+            0 => f.write_str("nothing expected")?,
+            1 => f.write_fmt(format_args!("expected \"{}\"", self.expected.first().unwrap()))?,
+            _ => f.write_fmt(format_args!(
+                "expected one of {}",
+                self.expected.iter().map(|e| format!("\"{}\"", e)).collect::<Vec<_>>().join(", ")
+            ))?,
+        }
+        match self.found() {
+            Some(found) => f.write_fmt(format_args!(" but found \"{}\"\n", found))?,
+            None => f.write_str(" but found end of input\n")?,
+        }
+
+        let from = self.position.saturating_sub(DIAGNOSTIC_CONTEXT);
+        let to = (self.position + DIAGNOSTIC_CONTEXT + 1).min(self.tokens.len());
+        let mut line = String::from("    ");
+        let mut caret = 0;
+        if from > 0 {
+            line += "... ";
+        }
+        for (i, token) in self.tokens[from..to].iter().enumerate() {
+            if from + i == self.position {
+                caret = line.len();
+            }
+            line += token;
+            line += " ";
+        }
+        if self.position >= to {
+            caret = line.len();
+        }
+        if to < self.tokens.len() {
+            line += "...";
+        }
+        f.write_fmt(format_args!("{}\n{}^", line.trim_end(), " ".repeat(caret)))?;
+
+        if !self.production_stack.is_empty() {
+            f.write_fmt(format_args!(
+                "\n    while parsing {}",
+                self.production_stack.iter().map(|p| format!("<{}>", p)).collect::<Vec<_>>().join(" > ")
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::GrammarIsEmpty => f.write_str("grammar is empty"),
+            Error::ProductionNotFound(name) => f.write_fmt(format_args!("production '{}' not found", name)),
+            Error::StartNotFound(name) => f.write_fmt(format_args!("start nonterminal '{}' is not defined in the grammar", name)),
+            Error::ProductionRecursion(lhs) => f.write_fmt(format_args!("production recursion '{}'", lhs)),
+            Error::MaxLevelReached { limit } => f.write_fmt(format_args!("max derivation depth {} reached", limit)),
+            Error::UnexpectedToken { position, expected, found } =>
+                f.write_fmt(format_args!("front token '{}' at {} is not given terminal '{}'", found, position, expected)),
+            Error::TokenCount { position, len, terminal } =>
+                f.write_fmt(format_args!("terminal '{}' can not cover {} tokens at {}", terminal, len, position)),
+            Error::Syntax(err) => err.fmt(f),
+            Error::NoTreeFound => f.write_str("the input is valid but the backtracking engine found no tree"),
+            Error::Ll1Conflicts(conflicts) => f.write_fmt(format_args!(
+                "grammar is not LL(1): {}",
+                conflicts.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(", ")
+            )),
+            Error::LrConflicts(conflicts) => f.write_fmt(format_args!(
+                "automaton has conflicts: {}",
+                conflicts.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(", ")
+            )),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+
+#[cfg(test)]
+mod tests {
+    use trim_margin::MarginTrimmable;
+
+    use super::SyntaxError;
+
+    #[test]
+    fn display_test() {
+        let err = SyntaxError {
+            position: 2,
+            expected: [ "=".to_string(), ".".to_string() ].into(),
+            production_stack: vec![ "block".to_string(), "subs".to_string() ],
+            tokens: [ "ID", "=", "ID", "W" ].into_iter().map(String::from).collect(),
+        };
+        assert_eq!(format!("{}", err), r#"
+            |syntax error at token 2: expected one of ".", "=" but found "ID"
+            |    ID = ID W
+            |         ^
+            |    while parsing <block> > <subs>
+        "#.trim_margin().unwrap());
+    }
+
+    #[test]
+    fn end_of_input_display_test() {
+        let err = SyntaxError {
+            position: 12,
+            expected: [ ";".to_string() ].into(),
+            production_stack: vec![],
+            tokens: (0..12).map(|i| i.to_string()).collect(),
+        };
+        assert_eq!(format!("{}", err), r#"
+            |syntax error at token 12: expected ";" but found end of input
+            |    ... 4 5 6 7 8 9 10 11
+            |                          ^
+        "#.trim_margin().unwrap());
+    }
+}
@@ -1,6 +1,7 @@
-use std::{ffi::{c_char, CStr, c_void}, str::Utf8Error, slice, fmt::Display};
+use std::{ffi::{c_char, CStr, CString, c_void}, str::Utf8Error, slice, fmt::Display, ptr::null, panic::{catch_unwind, AssertUnwindSafe}};
 
-use crate::{Term, Expression, Production, Grammar, ctx::Token, OptTerm, make_ctx, parse, ExtExpression, ExtProduction, ExtGrammar};
+use crate::{Term, ctx::Token, OptTerm, make_ctx, parse_with, Engine, ExtExpression, ExtProduction, ExtGrammar, ParseTree, ParseTreeNode};
+use crate::stack;
 
 
 
@@ -12,6 +13,8 @@ pub struct parser300b_Term {
 }
 
 impl parser300b_Term {
+    /// # Safety
+    /// `value` must point to a NUL terminated string
     pub unsafe fn to_non_c(&self) -> Result<OptTerm, Utf8Error> {
         let value = CStr::from_ptr(self.value).to_str()?;
         if self.is_terminal {
@@ -29,6 +32,8 @@ pub struct parser300b_Expression {
 }
 
 impl parser300b_Expression {
+    /// # Safety
+    /// `terms` must point to `term_count` valid terms
     pub unsafe fn to_non_c(&self) -> Result<ExtExpression, Utf8Error> {        
         slice::from_raw_parts(self.terms, self.term_count)
             .iter()
@@ -46,6 +51,8 @@ pub struct parser300b_Production {
 }
 
 impl parser300b_Production {
+    /// # Safety
+    /// `lhs` must point to a NUL terminated string and `rhs` to `rhs_count` valid expressions
     pub unsafe fn to_non_c(&self) -> Result<ExtProduction, Utf8Error> {
         let lhs = CStr::from_ptr(self.lhs).to_str()?;
         slice::from_raw_parts(self.rhs, self.rhs_count)
@@ -63,12 +70,14 @@ pub struct parser300b_Grammar {
 }
 
 impl parser300b_Grammar {
+    /// # Safety
+    /// `productions` must point to `production_count` valid productions
     pub unsafe fn to_non_c(&self) -> Result<ExtGrammar, Utf8Error> {
         slice::from_raw_parts(self.productions, self.production_count)
             .iter()
             .map(|t| t.to_non_c())
             .collect::<Result<Vec<_>, _>>()
-            .map(|p| ExtGrammar { productions: p })
+            .map(|p| ExtGrammar { productions: p, synthetic: Default::default() })
     }
 }
 
@@ -106,24 +115,361 @@ impl<'n> Token for CToken<'n> {
     }
 }
 
+/// engine selection of `parser300b_parse_with`, see `Engine`
+#[repr(C)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum parser300b_Engine {
+    Backtracking,
+    Earley,
+    Ll1,
+    Lalr,
+    Lr1,
+    Glr,
+    Cyk,
+}
+
+impl From<parser300b_Engine> for Engine {
+    fn from(engine: parser300b_Engine) -> Self {
+        match engine {
+            parser300b_Engine::Backtracking => Engine::Backtracking,
+            parser300b_Engine::Earley => Engine::Earley,
+            parser300b_Engine::Ll1 => Engine::Ll1,
+            parser300b_Engine::Lalr => Engine::Lalr,
+            parser300b_Engine::Lr1 => Engine::Lr1,
+            parser300b_Engine::Glr => Engine::Glr,
+            parser300b_Engine::Cyk => Engine::Cyk,
+        }
+    }
+}
+
+/// C string of `s`, NUL chars can not be represented and are replaced with `\0`
+fn c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "\\0")).expect("NUL chars replaced")
+}
+
+#[allow(non_camel_case_types)]
+pub enum parser300b_Node {
+    Nonterminal {
+        lhs: CString,
+        children: Vec<parser300b_Node>,
+    },
+    Terminal {
+        name: CString,
+        index: usize,
+        data: *const c_void,
+    },
+}
+
+impl parser300b_Node {
+    fn from_tree(tree: &ParseTree<CToken>, tokens: &[CToken]) -> Self {
+        // recurses once per tree level
+        stack::grow(|| parser300b_Node::Nonterminal {
+            lhs: c_string(tree.lhs),
+            children: tree.rhs.iter().map(|node| match node {
+                ParseTreeNode::Terminal(token) => parser300b_Node::Terminal {
+                    name: c_string(token.name),
+                    index: unsafe { (*token as *const CToken).offset_from(tokens.as_ptr()) as usize },
+                    data: token.data,
+                },
+                ParseTreeNode::Nonterminal(tree) => parser300b_Node::from_tree(tree, tokens),
+            }).collect(),
+        })
+    }
+}
+
+// the generated drop would recurse once per tree level
+impl Drop for parser300b_Node {
+    fn drop(&mut self) {
+        if let parser300b_Node::Nonterminal { children, .. } = self {
+            let mut nodes = std::mem::take(children);
+            while let Some(mut node) = nodes.pop() {
+                if let parser300b_Node::Nonterminal { children, .. } = &mut node {
+                    nodes.append(children);
+                }
+            }
+        }
+    }
+}
+
+/// owns everything a parse produced, freed with `parser300b_Result_free`
+#[allow(non_camel_case_types)]
+pub struct parser300b_Result {
+    trees: Vec<parser300b_Node>,
+    errors: Vec<CString>,
+}
+
+unsafe fn to_non_c_tokens<'n>(tokens: *const parser300b_Token, token_count: usize) -> Result<Vec<CToken<'n>>, Utf8Error> {
+    if token_count == 0 {
+        return Ok(vec![])
+    }
+    slice::from_raw_parts(tokens, token_count)
+        .iter()
+        .map(|t| t.to_non_c())
+        .collect()
+}
+
+/// parses with the Earley engine and collects at most `max_trees` trees (0 for all of them)
+///
+/// # Safety
+/// `grammar` must be null or point to a valid grammar and `tokens` to `token_count` valid tokens
+#[no_mangle]
+pub unsafe extern "C" fn parser300b_parse(
+    grammar: *const parser300b_Grammar,
+    tokens: *const parser300b_Token,
+    token_count: usize,
+    max_trees: usize
+) -> *mut parser300b_Result {
+    parser300b_parse_with(grammar, tokens, token_count, max_trees, parser300b_Engine::Earley)
+}
+
+/// same as `parser300b_parse` with the given engine
+///
+/// # Safety
+/// same as `parser300b_parse`
 #[no_mangle]
-pub unsafe extern "C" fn parser300b_parse(grammar: *const parser300b_Grammar, tokens: *const parser300b_Token, token_count: usize) {
-    if !grammar.is_null() {
-        let grammar: &parser300b_Grammar = &*grammar;
-        grammar.to_non_c().and_then(|grammar| {
-            slice::from_raw_parts(tokens, token_count)
-                .iter()
-                .map(|t| t.to_non_c())
-                .collect::<Result<Vec<_>, _>>()
-                .and_then(|tokens| {
-                    println!("parser300b_parse: {:#?} <- {:#?}", tokens, grammar);
-                    let grammar = grammar.flatten();
-                    let ctx = make_ctx(&grammar, &tokens, false, true);
-                    let _ = parse(ctx); // TODO return result
-                    Ok(())
-                })
-            }).unwrap();                
+pub unsafe extern "C" fn parser300b_parse_with(
+    grammar: *const parser300b_Grammar,
+    tokens: *const parser300b_Token,
+    token_count: usize,
+    max_trees: usize,
+    engine: parser300b_Engine
+) -> *mut parser300b_Result {
+    let mut result = parser300b_Result { trees: vec![], errors: vec![] };
+
+    if grammar.is_null() {
+        result.errors.push(c_string("grammar is null"));
     } else {
-        panic!("grammar is null")
+        // a panic must not unwind into the caller
+        let parsed = catch_unwind(AssertUnwindSafe(|| parse_into(&mut result, &*grammar, tokens, token_count, max_trees, engine)));
+        if let Err(panic) = parsed {
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown cause");
+            result.errors.push(c_string(&format!("parser panicked: {}", message)));
+        }
+    }
+    Box::into_raw(Box::new(result))
+}
+
+unsafe fn parse_into(
+    result: &mut parser300b_Result,
+    grammar: &parser300b_Grammar,
+    tokens: *const parser300b_Token,
+    token_count: usize,
+    max_trees: usize,
+    engine: parser300b_Engine
+) {
+    match grammar.to_non_c().and_then(|g| to_non_c_tokens(tokens, token_count).map(|t| (g, t))) {
+        Ok((grammar, tokens)) => {
+            let grammar = grammar.flatten();
+            let ctx = make_ctx(&grammar, &tokens, false, false);
+            // the backtracking engine yields an error per failed branch, the diagnostic comes last
+            let mut error = None;
+            for tree in parse_with(ctx, engine.into()) {
+                match tree {
+                    Ok(tree) => {
+                        result.trees.push(parser300b_Node::from_tree(&tree, &tokens));
+                        if result.trees.len() == max_trees {
+                            break;
+                        }
+                    },
+                    Err(err) => error = Some(err),
+                }
+            }
+            if let Some(err) = error.filter(|_| result.trees.is_empty()) {
+                result.errors.push(c_string(&err.to_string()));
+            }
+        },
+        Err(err) => result.errors.push(c_string(&err.to_string())),
+    }
+}
+
+/// # Safety
+/// `result` must be null or returned by `parser300b_parse` and not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn parser300b_Result_free(result: *mut parser300b_Result) {
+    if !result.is_null() {
+        drop(Box::from_raw(result));
+    }
+}
+
+/// # Safety
+/// `result` must be returned by `parser300b_parse` and not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn parser300b_Result_tree_count(result: *const parser300b_Result) -> usize {
+    (&*result).trees.len()
+}
+
+/// null if `index` is out of range
+///
+/// # Safety
+/// `result` must be returned by `parser300b_parse` and not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn parser300b_Result_tree(result: *const parser300b_Result, index: usize) -> *const parser300b_Node {
+    (&*result).trees.get(index).map_or(null(), |t| t as *const _)
+}
+
+/// # Safety
+/// `result` must be returned by `parser300b_parse` and not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn parser300b_Result_error_count(result: *const parser300b_Result) -> usize {
+    (&*result).errors.len()
+}
+
+/// null if `index` is out of range
+///
+/// # Safety
+/// `result` must be returned by `parser300b_parse` and not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn parser300b_Result_error(result: *const parser300b_Result, index: usize) -> *const c_char {
+    (&*result).errors.get(index).map_or(null(), |e| e.as_ptr())
+}
+
+/// # Safety
+/// `node` must be a node of a result which is not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn parser300b_Node_is_terminal(node: *const parser300b_Node) -> bool {
+    matches!(&*node, parser300b_Node::Terminal { .. })
+}
+
+/// lhs of a nonterminal or token name of a terminal
+///
+/// # Safety
+/// `node` must be a node of a result which is not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn parser300b_Node_name(node: *const parser300b_Node) -> *const c_char {
+    match &*node {
+        parser300b_Node::Nonterminal { lhs, .. } => lhs.as_ptr(),
+        parser300b_Node::Terminal { name, .. } => name.as_ptr(),
+    }
+}
+
+/// 0 for terminals
+///
+/// # Safety
+/// `node` must be a node of a result which is not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn parser300b_Node_child_count(node: *const parser300b_Node) -> usize {
+    match &*node {
+        parser300b_Node::Nonterminal { children, .. } => children.len(),
+        parser300b_Node::Terminal { .. } => 0,
+    }
+}
+
+/// null if `index` is out of range or the node is a terminal
+///
+/// # Safety
+/// `node` must be a node of a result which is not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn parser300b_Node_child(node: *const parser300b_Node, index: usize) -> *const parser300b_Node {
+    match &*node {
+        parser300b_Node::Nonterminal { children, .. } => children.get(index).map_or(null(), |c| c as *const _),
+        parser300b_Node::Terminal { .. } => null(),
+    }
+}
+
+/// index of the token in the array passed to `parser300b_parse`, `SIZE_MAX` for nonterminals
+///
+/// # Safety
+/// `node` must be a node of a result which is not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn parser300b_Node_token_index(node: *const parser300b_Node) -> usize {
+    match &*node {
+        parser300b_Node::Nonterminal { .. } => usize::MAX,
+        parser300b_Node::Terminal { index, .. } => *index,
+    }
+}
+
+/// `data` of the token passed to `parser300b_parse`, null for nonterminals
+///
+/// # Safety
+/// `node` must be a node of a result which is not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn parser300b_Node_token_data(node: *const parser300b_Node) -> *const c_void {
+    match &*node {
+        parser300b_Node::Nonterminal { .. } => null(),
+        parser300b_Node::Terminal { data, .. } => *data,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CString, CStr, c_void};
+    use std::ptr::null;
+
+    use super::*;
+
+    fn term(value: &CString, is_terminal: bool) -> parser300b_Term {
+        parser300b_Term { value: value.as_ptr(), is_terminal, is_optional: false }
+    }
+
+    #[test]
+    fn parse_result_test() {
+        let (b, a, n, dot) = (CString::new("b").unwrap(), CString::new("a").unwrap(), CString::new("N").unwrap(), CString::new(".").unwrap());
+
+        let b_terms = [ term(&a, false) ];
+        let b_rec_terms = [ term(&b, false), term(&dot, true), term(&a, false) ];
+        let a_terms = [ term(&n, true) ];
+        let b_rhs = [
+            parser300b_Expression { terms: b_terms.as_ptr(), term_count: 1 },
+            parser300b_Expression { terms: b_rec_terms.as_ptr(), term_count: 3 },
+        ];
+        let a_rhs = [ parser300b_Expression { terms: a_terms.as_ptr(), term_count: 1 } ];
+        let productions = [
+            parser300b_Production { lhs: b.as_ptr(), rhs: b_rhs.as_ptr(), rhs_count: 2 },
+            parser300b_Production { lhs: a.as_ptr(), rhs: a_rhs.as_ptr(), rhs_count: 1 },
+        ];
+        let grammar = parser300b_Grammar { productions: productions.as_ptr(), production_count: 2 };
+
+        let data = [ 10, 11, 12 ];
+        let tokens = [
+            parser300b_Token { name: n.as_ptr(), data: &data[0] as *const i32 as *const c_void },
+            parser300b_Token { name: dot.as_ptr(), data: &data[1] as *const i32 as *const c_void },
+            parser300b_Token { name: n.as_ptr(), data: &data[2] as *const i32 as *const c_void },
+        ];
+
+        unsafe {
+            let result = parser300b_parse(&grammar, tokens.as_ptr(), tokens.len(), 0);
+            assert_eq!(parser300b_Result_tree_count(result), 1);
+            assert_eq!(parser300b_Result_error_count(result), 0);
+
+            let root = parser300b_Result_tree(result, 0);
+            assert_eq!(CStr::from_ptr(parser300b_Node_name(root)).to_str().unwrap(), "b");
+            assert_eq!(parser300b_Node_child_count(root), 3);
+            assert!(!parser300b_Node_is_terminal(root));
+
+            let dot_node = parser300b_Node_child(root, 1);
+            assert!(parser300b_Node_is_terminal(dot_node));
+            assert_eq!(parser300b_Node_token_index(dot_node), 1);
+            assert_eq!(*(parser300b_Node_token_data(dot_node) as *const i32), 11);
+            assert_eq!(parser300b_Node_child(root, 3), null());
+            parser300b_Result_free(result);
+
+            let result = parser300b_parse(&grammar, tokens.as_ptr(), 2, 0);
+            assert_eq!(parser300b_Result_tree_count(result), 0);
+            assert_eq!(parser300b_Result_error_count(result), 1);
+            assert!(CStr::from_ptr(parser300b_Result_error(result, 0)).to_str().unwrap().starts_with("syntax error at token 2"));
+            parser300b_Result_free(result);
+
+            for engine in [ parser300b_Engine::Backtracking, parser300b_Engine::Lalr, parser300b_Engine::Cyk ] {
+                let result = parser300b_parse_with(&grammar, tokens.as_ptr(), tokens.len(), 0, engine);
+                assert_eq!(parser300b_Result_tree_count(result), 1, "{:?}", engine);
+                parser300b_Result_free(result);
+
+                // only the diagnostic, not the failed branches of the backtracking engine
+                let result = parser300b_parse_with(&grammar, tokens.as_ptr(), 2, 0, engine);
+                assert_eq!(parser300b_Result_error_count(result), 1, "{:?}", engine);
+                parser300b_Result_free(result);
+            }
+        }
+    }
+
+    #[test]
+    fn c_string_test() {
+        assert_eq!(c_string("a\0b").to_str().unwrap(), "a\\0b");
     }
 }
@@ -0,0 +1,319 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ctx::*;
+use crate::error::Error;
+use crate::lr::{Action, LrMode, LrTable};
+use crate::parse::{ParseTreeIter, splice_synthetic, parse_failed};
+use crate::sppf::{Forest, ForestNode, NodeId, Symbol};
+
+
+/// vertex of the graph-structured stack
+struct Vertex {
+    state: usize,
+    /// token position the vertex was pushed at
+    level: usize,
+    /// previous vertices with the forest node of the symbol between them
+    edges: Vec<(usize, NodeId)>,
+}
+
+/// Tomita parser state: every LR stack alive at a position is a path in `gss`
+struct Glr<'a> {
+    table: &'a LrTable,
+    gss: Vec<Vertex>,
+    nodes: Vec<ForestNode>,
+    symbols: HashMap<(Symbol, usize, usize), NodeId>,
+    packed: HashSet<(NodeId, usize, Vec<NodeId>)>,
+}
+
+impl Glr<'_> {
+    fn vertex(&mut self, state: usize, level: usize) -> usize {
+        self.gss.push(Vertex { state, level, edges: vec![] });
+        self.gss.len() - 1
+    }
+
+    fn symbol(&mut self, symbol: Symbol, begin: usize, end: usize) -> NodeId {
+        *self.symbols.entry((symbol, begin, end)).or_insert_with(|| {
+            self.nodes.push(ForestNode::Symbol { symbol, begin, end, packed: vec![] });
+            self.nodes.len() - 1
+        })
+    }
+
+    /// adds a derivation by `rule` to a symbol node unless it is already there
+    fn pack(&mut self, symbol: NodeId, rule: usize, children: Vec<NodeId>) {
+        if !self.packed.insert((symbol, rule, children.clone())) {
+            return
+        }
+        let rule = &self.table.rules[rule];
+        self.nodes.push(ForestNode::Packed { production: rule.production, expression: rule.expression, children });
+        let id = self.nodes.len() - 1;
+        if let ForestNode::Symbol { packed, .. } = &mut self.nodes[symbol] {
+            packed.push(id);
+        }
+    }
+
+    /// vertices `len` edges below `vertex` with the labels of the edges in rhs order.
+    /// `via` restricts the first edge to the one with that index
+    fn paths(&self, vertex: usize, len: usize, via: Option<usize>) -> Vec<(usize, Vec<NodeId>)> {
+        if len == 0 {
+            return vec![ (vertex, vec![]) ]
+        }
+        let edges = &self.gss[vertex].edges;
+        let edges = match via {
+            Some(e) => &edges[e..=e],
+            None => &edges[..],
+        };
+        let mut result = vec![];
+        for &(previous, label) in edges {
+            for (bottom, mut labels) in self.paths(previous, len - 1, None) {
+                labels.push(label);
+                result.push((bottom, labels));
+            }
+        }
+        result
+    }
+
+    /// reductions of `vertex` on `lookahead`. empty ones are skipped when only paths through a new edge are wanted
+    fn queue(&self, vertex: usize, lookahead: usize, via: Option<usize>, skip_empty: bool, work: &mut Vec<(usize, usize, Option<usize>)>) {
+        for action in self.table.actions(self.gss[vertex].state, lookahead) {
+            if let Action::Reduce(r) = *action {
+                if !skip_empty || !self.table.rules[r].rhs.is_empty() {
+                    work.push((vertex, r, via));
+                }
+            }
+        }
+    }
+
+    /// applies every reduction possible at `position` until the frontier stops growing
+    fn reduce(&mut self, frontier: &mut HashMap<usize, usize>, position: usize, lookahead: usize) {
+        let mut work = vec![];
+        for &vertex in frontier.values() {
+            self.queue(vertex, lookahead, None, false, &mut work);
+        }
+        // an empty reduction puts vertices on top of the frontier, a new edge below them
+        // opens paths for their reductions too
+        let mut empty_edges = false;
+        while let Some((vertex, r, via)) = work.pop() {
+            let rule = &self.table.rules[r];
+            let (production, lhs, len) = (rule.production, rule.lhs, rule.rhs.len());
+            for (bottom, labels) in self.paths(vertex, len, via) {
+                let begin = self.gss[bottom].level;
+                let symbol = self.symbol(Symbol::Nonterminal(production), begin, position);
+                self.pack(symbol, r, labels);
+                let state = self.table.goto(self.gss[bottom].state, lhs);
+                empty_edges |= begin == position;
+                match frontier.get(&state) {
+                    Some(&top) if self.gss[top].edges.iter().any(|&(v, _)| v == bottom) => (),
+                    Some(&top) => {
+                        self.gss[top].edges.push((bottom, symbol));
+                        if empty_edges {
+                            for &v in frontier.values() {
+                                self.queue(v, lookahead, None, true, &mut work);
+                            }
+                        } else {
+                            self.queue(top, lookahead, Some(self.gss[top].edges.len() - 1), true, &mut work);
+                        }
+                    },
+                    None => {
+                        let top = self.vertex(state, position);
+                        self.gss[top].edges.push((bottom, symbol));
+                        frontier.insert(state, top);
+                        self.queue(top, lookahead, None, false, &mut work);
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// runs the GLR parser on the LALR(1) automaton and packs all derivations of the first production
+pub fn parse_glr_forest<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>) -> Result<Forest<'tg, T>, Error> {
+    parse_glr_forest_from(ctx, 0)
+}
+
+pub(crate) fn parse_glr_forest_from<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>, start: usize) -> Result<Forest<'tg, T>, Error> {
+    if ctx.grammar.productions.len() <= start {
+        return Err(Error::GrammarIsEmpty)
+    }
+    let table = LrTable::new(ctx.grammar, start, LrMode::Lalr);
+    parse_glr_forest_with(ctx, &table)
+}
+
+/// same as `parse_glr_forest` with an automaton of `ctx.grammar` built once for any number of inputs,
+/// either `LrMode` works. the forest is rooted at the start production of the automaton
+pub fn parse_glr_forest_with<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>, table: &LrTable) -> Result<Forest<'tg, T>, Error> {
+    let mut glr = Glr { table, gss: vec![], nodes: vec![], symbols: HashMap::new(), packed: HashSet::new() };
+    let mut frontier = HashMap::from([ (0, glr.vertex(0, ctx.begin)) ]);
+    let mut position = ctx.begin;
+    loop {
+        let states = |frontier: &HashMap<usize, usize>| {
+            let mut states: Vec<usize> = frontier.keys().copied().collect();
+            states.sort();
+            states
+        };
+        let Some(lookahead) = table.lookahead(&ctx, position) else {
+            return Err(table.syntax_error(&ctx, position, &states(&frontier), &states(&frontier)))
+        };
+        glr.reduce(&mut frontier, position, lookahead);
+
+        if position == ctx.end {
+            let accepted = frontier.values().any(|&v| table.actions(glr.gss[v].state, lookahead).contains(&Action::Accept));
+            let root = glr.symbols.get(&(Symbol::Nonterminal(table.rules[0].production), ctx.begin, ctx.end)).copied();
+            return match root {
+                Some(root) if accepted => Ok(Forest { grammar: ctx.grammar, tokens: ctx.tokens, nodes: glr.nodes, root }),
+                _ => Err(table.syntax_error(&ctx, position, &states(&frontier), &states(&frontier))),
+            }
+        }
+
+        let mut next = HashMap::new();
+        let label = glr.symbol(Symbol::Terminal(position), position, position + 1);
+        let mut vertices: Vec<usize> = frontier.values().copied().collect();
+        vertices.sort();
+        for vertex in vertices {
+            for action in table.actions(glr.gss[vertex].state, lookahead) {
+                if let Action::Shift(state) = *action {
+                    let top = match next.get(&state) {
+                        Some(&top) => top,
+                        None => {
+                            let top = glr.vertex(state, position + 1);
+                            next.insert(state, top);
+                            top
+                        },
+                    };
+                    glr.gss[top].edges.push((vertex, label));
+                }
+            }
+        }
+        if next.is_empty() {
+            return Err(table.syntax_error(&ctx, position, &states(&frontier), &states(&frontier)))
+        }
+        frontier = next;
+        position += 1;
+    }
+}
+
+/// all trees found by the GLR parser
+pub(crate) fn parse_glr_from<'tg, T: Token + 'tg>(ctx: Ctx<'tg, 'tg, T>, start: usize) -> ParseTreeIter<'tg, T> {
+    let ignore_errors = ctx.ignore_errors;
+    trees(parse_glr_forest_from(ctx, start), ignore_errors)
+}
+
+/// all trees found by the GLR parser on a prebuilt automaton, see `parse_glr_forest_with`
+pub fn parse_glr<'tg, T: Token + 'tg>(ctx: Ctx<'tg, 'tg, T>, table: &LrTable) -> ParseTreeIter<'tg, T> {
+    let ignore_errors = ctx.ignore_errors;
+    trees(parse_glr_forest_with(ctx, table), ignore_errors)
+}
+
+fn trees<'tg, T: Token + 'tg>(forest: Result<Forest<'tg, T>, Error>, ignore_errors: bool) -> ParseTreeIter<'tg, T> {
+    match forest {
+        Ok(forest) => splice_synthetic(forest.grammar, forest.into_trees()),
+        Err(err) => parse_failed(ignore_errors, err),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use crate::grammar::{ExtGrammar, Grammar};
+    use crate::parse::{make_ctx, parse_with, Engine};
+    use crate::sppf::{parse_forest, DerivationCount};
+    use crate::error::Error;
+
+    use crate::lr::LrMode;
+
+    use super::{parse_glr, parse_glr_forest};
+
+    fn grammar(text: &str) -> Grammar {
+        let g: ExtGrammar = text.try_into().unwrap();
+        g.flatten()
+    }
+
+    fn tokens(t: &[&str]) -> Vec<String> {
+        t.iter().map(|t| t.to_string()).collect()
+    }
+
+    fn trees(g: &Grammar, t: &Vec<String>, engine: Engine) -> BTreeSet<String> {
+        parse_with(make_ctx(g, t, false, true), engine).map(|t| format!("{:#}", t.unwrap())).collect()
+    }
+
+    #[test]
+    fn ambiguous_test() {
+        let g = grammar(r#"
+            <e> ::= <e> "+" <e> | <e> "*" <e> | "n"
+        "#);
+        let t = tokens(&[ "n", "+", "n", "*", "n", "+", "n" ]);
+        let forest = parse_glr_forest(make_ctx(&g, &t, false, true)).unwrap();
+        assert!(forest.is_ambiguous());
+        assert_eq!(forest.count_derivations(), DerivationCount::Finite(5));
+        assert_eq!(forest.count_derivations(), parse_forest(make_ctx(&g, &t, false, true)).unwrap().count_derivations());
+        assert_eq!(trees(&g, &t, Engine::Glr), trees(&g, &t, Engine::Earley));
+    }
+
+    #[test]
+    fn epsilon_test() {
+        // hidden left recursion and right nullable rules break naive Tomita parsers
+        let g = grammar(r#"
+            <s> ::= <a> <s> "b" | "x" <c> <c>
+            <a> ::= ε
+            <c> ::= ε | "c"
+        "#);
+        for (t, count) in [ (&[ "x" ][..], 1), (&[ "x", "b", "b" ], 1), (&[ "x", "c", "b" ], 2), (&[ "x", "c", "c", "b" ], 1) ] {
+            let t = tokens(t);
+            let forest = parse_glr_forest(make_ctx(&g, &t, false, true)).unwrap();
+            assert_eq!(forest.count_derivations(), DerivationCount::Finite(count));
+            assert_eq!(trees(&g, &t, Engine::Glr), trees(&g, &t, Engine::Earley));
+        }
+
+        let g = grammar(r#"
+            <list> ::= <item> <list> | ε
+            <item> ::= "a" <opt> | "b"
+            <opt>  ::= "," | ε
+        "#);
+        let t = tokens(&[ "a", ",", "b", "a" ]);
+        assert_eq!(trees(&g, &t, Engine::Glr), trees(&g, &t, Engine::Earley));
+        assert!(!parse_glr_forest(make_ctx(&g, &t, false, true)).unwrap().is_ambiguous());
+    }
+
+    #[test]
+    fn prebuilt_table_test() {
+        let g = grammar(r#"
+            <e> ::= <e> "+" <e> | <e> "*" <e> | "n"
+        "#);
+        for table in [ g.lr_table(LrMode::Lalr).unwrap(), g.lr_table(LrMode::Canonical).unwrap() ] {
+            for t in [ tokens(&[ "n" ]), tokens(&[ "n", "+", "n", "*", "n" ]), tokens(&[ "n", "*", "n", "*", "n", "+", "n" ]) ] {
+                let glr: BTreeSet<_> = parse_glr(make_ctx(&g, &t, false, true), &table).map(|t| format!("{:#}", t.unwrap())).collect();
+                assert_eq!(glr, trees(&g, &t, Engine::Earley));
+            }
+        }
+    }
+
+    #[test]
+    fn deterministic_test() {
+        let g = grammar(r#"
+            <list> ::= <list> "," "n" | "n"
+        "#);
+        let long: Vec<_> = std::iter::repeat_n([ "n", "," ], 20000).flatten().chain([ "n" ]).collect();
+        let long = tokens(&long);
+        let forest = parse_glr_forest(make_ctx(&g, &long, false, true)).unwrap();
+        assert!(!forest.is_ambiguous());
+        assert_eq!(forest.count_derivations(), DerivationCount::Finite(1));
+    }
+
+    #[test]
+    fn error_test() {
+        let g = grammar(r#"
+            <e> ::= <e> "+" <e> | "n"
+        "#);
+        let t = tokens(&[ "n", "+", "+", "n" ]);
+        match parse_glr_forest(make_ctx(&g, &t, false, true)) {
+            Err(Error::Syntax(err)) => {
+                assert_eq!(err.position, 2);
+                assert_eq!(err.expected, [ "n".to_string() ].into());
+            },
+            other => panic!("unexpected result {:?}", other.map(|f| f.nodes)),
+        }
+        let t = tokens(&[ "n", "+" ]);
+        assert!(matches!(parse_glr_forest(make_ctx(&g, &t, false, true)), Err(Error::Syntax(err)) if err.position == 2));
+    }
+}
@@ -1,9 +1,11 @@
 use std::{
     fmt::Display, 
-    str::Chars, path::MAIN_SEPARATOR
+    path::MAIN_SEPARATOR,
+    collections::{HashMap, HashSet}
 };
 
 use crate::combination::expand_combinations_iter;
+use crate::bnf_lexer::{lex, Lexeme, Located};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Term {
@@ -58,6 +60,9 @@ pub struct Expression {
 
 impl Display for Expression {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {        
+        if self.terms.is_empty() {
+            return f.write_str("ε")
+        }
         for (i, term) in self.terms.iter().enumerate() {
             if i < self.terms.len() - 1 {
                 f.write_fmt(format_args!("{} ", term))?;
@@ -76,6 +81,9 @@ pub struct ExtExpression {
 
 impl ExtExpression {
     pub fn flatten(self) -> Vec<Expression> {
+        if self.terms.is_empty() {
+            return vec![ Expression { terms: vec![] } ]
+        }
         expand_combinations_iter(self.terms.into_iter().map(|t| {
             if t.is_optional {
                 vec![ Some(t.term), None ].into_iter()                
@@ -122,14 +130,16 @@ impl ExtProduction {
                 .into_iter()
                 .map(|t|t.flatten())
                 .flatten()
-                .collect()
+                .collect(),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Default)]
 pub struct Grammar {
     pub productions: Vec<Production>,
+    /// lhs of the productions generated for ebnf groups and repetitions. their nodes are replaced by their children in parse trees
+    pub synthetic: HashSet<String>,
 }
 
 impl Display for Grammar {
@@ -145,101 +155,413 @@ impl Display for Grammar {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Default)]
 pub struct ExtGrammar {
     pub productions: Vec<ExtProduction>,
+    /// see `Grammar::synthetic`
+    pub synthetic: HashSet<String>,
+}
+
+/// how `ExtGrammar::flatten_with` gets rid of optional terms
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Optionals {
+    /// every combination of present and absent optional terms becomes an expression, `2^n` of them for `n` optionals
+    #[default]
+    Expand,
+    /// `x?` becomes a nonterminal `<x?> ::= x | ε`, hidden in parse trees like the ebnf productions
+    Nullable,
+    /// same as `Nullable` with `<x?>` kept in parse trees, an absent optional is a node without children
+    Explicit,
+}
+
+/// lhs of the production replacing the optional `term`
+fn optional_name(term: &Term) -> String {
+    match term {
+        Term::Terminal(t) => format!("\"{}\"?", t),
+        Term::Nonterminal(n) => format!("{}?", n),
+    }
 }
 
 impl ExtGrammar {
     /// removes all optional term conveting them into more expression valiants
     pub fn flatten(self) -> Grammar {
-        Grammar { 
-            productions: self.productions
-                .into_iter()
-                .map(|prod| prod.flatten())
-                .collect() 
+        self.flatten_with(Optionals::Expand)
+    }
+
+    pub fn flatten_with(self, optionals: Optionals) -> Grammar {
+        if optionals == Optionals::Expand {
+            return Grammar { 
+                productions: self.productions
+                    .into_iter()
+                    .map(|prod| prod.flatten())
+                    .collect(),
+                synthetic: self.synthetic,
+            }
         }
+
+        let mut synthetic = self.synthetic;
+        let mut generated: Vec<Production> = vec![];
+        let mut productions: Vec<Production> = self.productions
+            .into_iter()
+            .map(|prod| Production {
+                lhs: prod.lhs,
+                rhs: prod.rhs.into_iter().map(|e| Expression {
+                    terms: e.terms.into_iter().map(|t| {
+                        if !t.is_optional {
+                            return t.term
+                        }
+                        let lhs = optional_name(&t.term);
+                        if !generated.iter().any(|p| p.lhs == lhs) {
+                            generated.push(Production {
+                                lhs: lhs.clone(),
+                                rhs: vec![ Expression { terms: vec![ t.term ] }, Expression { terms: vec![] } ],
+                            });
+                            if optionals == Optionals::Nullable {
+                                synthetic.insert(lhs.clone());
+                            }
+                        }
+                        Term::Nonterminal(lhs)
+                    }).collect()
+                }).collect(),
+            })
+            .collect();
+        productions.extend(generated);
+        Grammar { productions, synthetic }
     }
 }
 
-#[derive(Debug, Clone)]
+/// 1-based position in the grammar text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}:{}", self.line, self.column))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum ParseError {
-    LhsNotFound(String),
-    WrongLhs(String),
-    RhsNotFound(String)
+    /// rhs without a preceding `<lhs> ::=`
+    LhsNotFound(Location),
+    WrongLhs(Location, String),
+    RhsNotFound(Location),
+    UnbalancedGroup(Location),
+    UnexpectedOperator(Location),
+    /// `::=` inside a rhs
+    UnexpectedDefine(Location),
+    UnterminatedString(Location),
+    UnterminatedNonterminal(Location),
+    UnknownEscape(Location, char),
+    /// alternative without terms which is not written as `ε`, located at the `::=`, `|` or bracket before it
+    EmptyAlternative(Location),
+}
+
+impl ParseError {
+    pub fn location(&self) -> Location {
+        match self {
+            ParseError::LhsNotFound(location) => *location,
+            ParseError::WrongLhs(location, _) => *location,
+            ParseError::RhsNotFound(location) => *location,
+            ParseError::UnbalancedGroup(location) => *location,
+            ParseError::UnexpectedOperator(location) => *location,
+            ParseError::UnexpectedDefine(location) => *location,
+            ParseError::UnterminatedString(location) => *location,
+            ParseError::UnterminatedNonterminal(location) => *location,
+            ParseError::UnknownEscape(location, _) => *location,
+            ParseError::EmptyAlternative(location) => *location,
+        }
+    }
 }
 
 impl Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}: ", self.location()))?;
         match self {
-            ParseError::LhsNotFound(line) => f.write_fmt(format_args!("lhs not found in '{}'", line)),
-            ParseError::WrongLhs(lhs) => f.write_fmt(format_args!("lhs must be '<***>' byt found '{}'", lhs)),
-            ParseError::RhsNotFound(line) => f.write_fmt(format_args!("rhs not found in '{}'", line)),
+            ParseError::LhsNotFound(_) => f.write_str("lhs not found"),
+            ParseError::WrongLhs(_, lhs) => f.write_fmt(format_args!("lhs must be '<***>' but found '{}'", lhs)),
+            ParseError::RhsNotFound(_) => f.write_str("'::=' not found"),
+            ParseError::UnbalancedGroup(_) => f.write_str("unbalanced bracket"),
+            ParseError::UnexpectedOperator(_) => f.write_str("operator without operand"),
+            ParseError::UnexpectedDefine(_) => f.write_str("unexpected '::=' in rhs"),
+            ParseError::UnterminatedString(_) => f.write_str("unterminated string"),
+            ParseError::UnterminatedNonterminal(_) => f.write_str("unterminated nonterminal"),
+            ParseError::UnknownEscape(_, c) => f.write_fmt(format_args!("unknown escape '\\{}'", c)),
+            ParseError::EmptyAlternative(_) => f.write_str("empty alternative, write 'ε' for an empty derivation"),
         }        
     }
 }
 
 impl std::error::Error for ParseError {}
 
-fn parse_term(term: Chars<'_>) -> Term {
-    let vec: Vec<_> = term.collect();
-    if vec.len() > 1 && vec[0] == '<' && vec[vec.len() - 1] == '>' {
-        Term::Nonterminal(String::from_iter(vec[1..vec.len() - 1].into_iter()))
-    } else if vec.len() > 1 && vec[0] == '"' && vec[vec.len() - 1] == '"' {
-        Term::Terminal(String::from_iter(vec[1..vec.len() - 1].into_iter()))
-    } else {
-        Term::Terminal(String::from_iter(vec.into_iter()))
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Term(Term),
+    Group(Vec<Vec<Item>>),
+}
+
+/// node with an optional `?`, `*` or `+`
+type Item = (Node, Option<char>);
+
+struct RhsParser<'l> {
+    lexemes: &'l [&'l Located],
+    pos: usize,
+}
+
+impl<'l> RhsParser<'l> {
+    fn lexeme(&self) -> Option<&'l Lexeme> {
+        self.lexemes.get(self.pos).map(|l| &l.lexeme)
+    }
+
+    fn parse(mut self, define: Location) -> Result<Vec<Vec<Item>>, ParseError> {
+        let result = self.alternatives(define)?;
+        match self.lexemes.get(self.pos) {
+            None => Ok(result),
+            Some(Located { lexeme: Lexeme::Define, location }) => Err(ParseError::UnexpectedDefine(*location)),
+            Some(Located { location, .. }) => Err(ParseError::UnbalancedGroup(*location)),
+        }
+    }
+
+    /// `location` of the lexeme before the first alternative
+    fn alternatives(&mut self, location: Location) -> Result<Vec<Vec<Item>>, ParseError> {
+        let mut result = vec![ self.alternative(location)? ];
+        while let Some(Located { lexeme: Lexeme::Or, location }) = self.lexemes.get(self.pos) {
+            self.pos += 1;
+            result.push(self.alternative(*location)?);
+        }
+        Ok(result)
+    }
+
+    /// sequence which may be empty only if it is written as `ε`, a stray `|` is an error
+    fn alternative(&mut self, location: Location) -> Result<Vec<Item>, ParseError> {
+        let begin = self.pos;
+        let sequence = self.sequence()?;
+        if sequence.is_empty() && self.pos == begin {
+            return Err(ParseError::EmptyAlternative(location))
+        }
+        Ok(sequence)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Item>, ParseError> {
+        let mut result = vec![];
+        while let Some(located) = self.lexemes.get(self.pos) {
+            let (node, mut operator) = match &located.lexeme {
+                Lexeme::Or | Lexeme::Close(_) | Lexeme::Define | Lexeme::Newline => break,
+                Lexeme::Operator(_) => return Err(ParseError::UnexpectedOperator(located.location)),
+                Lexeme::Empty => {
+                    self.pos += 1;
+                    continue;
+                },
+                Lexeme::Term(term) => {
+                    self.pos += 1;
+                    (Node::Term(term.clone()), None)
+                },
+                &Lexeme::Open(open) => {
+                    self.pos += 1;
+                    let alternatives = self.alternatives(located.location)?;
+                    let close = if open == '(' { ')' } else { ']' };
+                    if self.lexeme() != Some(&Lexeme::Close(close)) {
+                        return Err(ParseError::UnbalancedGroup(located.location))
+                    }
+                    self.pos += 1;
+                    (Node::Group(alternatives), if open == '[' { Some('?') } else { None })
+                },
+            };
+            if let Some(&Lexeme::Operator(op)) = self.lexeme() {
+                self.pos += 1;
+                // `[ x ]*` and `[ x ]+` are both zero or more
+                operator = match (operator, op) {
+                    (None, op) => Some(op),
+                    (Some(_), '?') => Some('?'),
+                    (Some(_), _) => Some('*'),
+                };
+            }
+            result.push((node, operator));
+        }
+        Ok(result)
+    }
+}
+
+/// turns groups and repetitions into fresh synthetic productions named `<lhs>:<n>`
+struct Desugar<'c> {
+    counters: &'c mut HashMap<String, usize>,
+    lhs: String,
+    generated: Vec<ExtProduction>,
+}
+
+impl<'c> Desugar<'c> {
+    fn fresh_name(&mut self) -> String {
+        let counter = self.counters.entry(self.lhs.clone()).or_insert(0);
+        *counter += 1;
+        format!("{}:{}", self.lhs, counter)
+    }
+
+    fn alternatives(&mut self, alternatives: Vec<Vec<Item>>) -> Vec<ExtExpression> {
+        alternatives
+            .into_iter()
+            .map(|sequence| {
+                let mut terms = vec![];
+                for item in sequence {
+                    self.item(item, &mut terms);
+                }
+                ExtExpression { terms }
+            })
+            .collect()
+    }
+
+    fn item(&mut self, item: Item, terms: &mut Vec<OptTerm>) {
+        match item {
+            (Node::Term(term), None) => terms.push(OptTerm::obl(term)),
+            (Node::Term(term), Some('?')) => terms.push(OptTerm::opt(term)),
+            (Node::Group(mut alternatives), None) if alternatives.len() == 1 => {
+                for item in alternatives.remove(0) {
+                    self.item(item, terms);
+                }
+            },
+            (Node::Group(alternatives), None) => terms.push(OptTerm::obl(self.group(alternatives))),
+            (Node::Group(mut alternatives), Some('?')) => {
+                if alternatives.len() == 1 && alternatives[0].len() == 1 && alternatives[0][0].1.is_none() {
+                    match alternatives[0].remove(0) {
+                        (Node::Term(term), _) => return terms.push(OptTerm::opt(term)),
+                        item => alternatives[0].push(item),
+                    }
+                }
+                terms.push(OptTerm::opt(self.group(alternatives)))
+            },
+            (node, repeat) => {
+                // a repeated nullable body matches nothing as well, `x+` is `x*` then
+                let (term, nullable) = self.repetition(node);
+                terms.push(if nullable || repeat == Some('*') { OptTerm::opt(term) } else { OptTerm::obl(term) })
+            },
+        }
+    }
+
+    fn group(&mut self, alternatives: Vec<Vec<Item>>) -> Term {
+        let lhs = self.fresh_name();
+        let rhs = self.alternatives(alternatives);
+        self.generated.push(ExtProduction { lhs: lhs.clone(), rhs });
+        Term::Nonterminal(lhs)
+    }
+
+    /// `x+` becomes `<r> ::= x | x <r>`. only the non-empty derivations of `x` are repeated,
+    /// an empty one would give the unit cycle `<r> ::= <r>`. returns whether `x` is nullable
+    fn repetition(&mut self, node: Node) -> (Term, bool) {
+        let lhs = self.fresh_name();
+        let body = match node {
+            Node::Term(term) => vec![ ExtExpression { terms: vec![ OptTerm::obl(term) ] } ],
+            Node::Group(alternatives) => self.alternatives(alternatives),
+        };
+        let nullable = body.iter().any(|e| e.terms.iter().all(|t| t.is_optional));
+        let mut once: Vec<_> = body.into_iter().flat_map(non_empty).collect();
+        if once.is_empty() {
+            // `( ε )+` matches the empty range only
+            once.push(ExtExpression { terms: vec![] });
+        }
+        let more: Vec<_> = once
+            .iter()
+            .filter(|e| !e.terms.is_empty())
+            .map(|e| {
+                let mut terms = e.terms.clone();
+                terms.push(OptTerm::obl(Term::Nonterminal(lhs.clone())));
+                ExtExpression { terms }
+            })
+            .collect();
+        self.generated.push(ExtProduction { lhs: lhs.clone(), rhs: once.into_iter().chain(more).collect() });
+        (Term::Nonterminal(lhs), nullable)
     }
 }
 
-fn parse_opt_term(term: Chars<'_>) -> OptTerm {
-    let vec: Vec<_> = term.clone().collect();
-    if vec.len() > 1 && vec[vec.len() - 1] == '?' {
-        OptTerm { term: parse_term(String::from_iter(vec[0..vec.len() - 1].into_iter()).chars()), is_optional: true }
-    } else {
-        OptTerm { term: parse_term(term), is_optional: false }
+/// the non-empty derivations of `expression`. if all its terms are optional
+/// it becomes one expression for each term that may come first
+fn non_empty(expression: ExtExpression) -> Vec<ExtExpression> {
+    if expression.terms.iter().any(|t| !t.is_optional) {
+        return vec![ expression ]
     }
+    (0..expression.terms.len())
+        .map(|first| {
+            let mut terms = expression.terms[first..].to_vec();
+            terms[0].is_optional = false;
+            ExtExpression { terms }
+        })
+        .collect()
 }
 
-fn parse_lhs(term: Chars<'_>) -> Result<String, ParseError> {
-    let vec: Vec<_> = term.collect();
-    if vec.len() > 1 && vec[0] == '<' && vec[vec.len() - 1] == '>' {
-        Ok(String::from_iter(vec[1..vec.len() - 1].into_iter()))
-    } else {
-        Err(ParseError::WrongLhs(String::from_iter(vec.into_iter())))
+fn is_production_start(lexemes: &[Located], i: usize) -> bool {
+    matches!(
+        (lexemes.get(i).map(|l| &l.lexeme), lexemes.get(i + 1).map(|l| &l.lexeme)),
+        (Some(Lexeme::Term(Term::Nonterminal(_))), Some(Lexeme::Define))
+    )
+}
+
+/// index of the lexeme ending the rhs starting at `begin`.
+/// a line break continues the rhs inside brackets, after `|` or before `|`
+fn rhs_end(lexemes: &[Located], begin: usize) -> usize {
+    let mut depth = 0usize;
+    let mut i = begin;
+    while i < lexemes.len() {
+        match lexemes[i].lexeme {
+            Lexeme::Open(_) => depth += 1,
+            Lexeme::Close(_) => depth = depth.saturating_sub(1),
+            Lexeme::Newline => {
+                let next = (i..lexemes.len()).find(|&j| lexemes[j].lexeme != Lexeme::Newline);
+                let Some(next) = next else { return i };
+                let previous = (begin..i).rev().find(|&j| lexemes[j].lexeme != Lexeme::Newline);
+                let continued = depth > 0
+                    || previous.is_some_and(|j| lexemes[j].lexeme == Lexeme::Or)
+                    || lexemes[next].lexeme == Lexeme::Or;
+                if !continued || is_production_start(lexemes, next) {
+                    return i
+                }
+                i = next;
+                continue;
+            },
+            _ => {},
+        }
+        i += 1;
     }
+    i
 }
 
 impl TryFrom<&str> for ExtGrammar {
     type Error = ParseError;    
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let mut result = ExtGrammar { productions: vec![] };
-        for line in value.lines() {
-            let line = line.trim();
-            if line.is_empty() { continue; }
-
-            let delim = "::=";
-            match line.find(delim) {
-                Some(split_pos) => {
-                    Ok(result.productions.push(ExtProduction {
-                        lhs: parse_lhs(line[0..split_pos].trim().chars())?,
-                        rhs: line[(split_pos + delim.len())..line.len()].split("|").map(|expr| {                             
-                                    ExtExpression {
-                                        terms: expr
-                                            .split(" ")
-                                            .map(str::trim)
-                                            .filter(|s| !s.is_empty())
-                                            .map(|term| parse_opt_term(term.chars()))
-                                            .collect()
-                                    }
-                                })
-                                    .collect()
-                    }))    
+        let lexemes = lex(value)?;
+        let mut result = ExtGrammar::default();
+        let mut generated = vec![];
+        let mut counters = HashMap::new();
+        let mut i = 0;
+        while i < lexemes.len() {
+            let lhs = match (&lexemes[i].lexeme, lexemes.get(i + 1).map(|l| &l.lexeme)) {
+                (Lexeme::Newline, _) => {
+                    i += 1;
+                    continue;
                 },
-                None => Err(ParseError::RhsNotFound(String::from(line))),
-            }?;
+                (Lexeme::Term(Term::Nonterminal(lhs)), Some(Lexeme::Define)) => lhs.clone(),
+                (Lexeme::Term(Term::Terminal(lhs)), Some(Lexeme::Define)) =>
+                    return Err(ParseError::WrongLhs(lexemes[i].location, lhs.clone())),
+                (Lexeme::Term(_), _) => return Err(ParseError::RhsNotFound(lexemes[i].location)),
+                _ => return Err(ParseError::LhsNotFound(lexemes[i].location)),
+            };
+
+            let end = rhs_end(&lexemes, i + 2);
+            let rhs: Vec<_> = lexemes[(i + 2)..end]
+                .iter()
+                .filter(|l| l.lexeme != Lexeme::Newline)
+                .collect();
+            let alternatives = RhsParser { lexemes: &rhs, pos: 0 }.parse(lexemes[i + 1].location)?;
+
+            let mut desugar = Desugar { counters: &mut counters, lhs: lhs.clone(), generated: vec![] };
+            let rhs = desugar.alternatives(alternatives);
+            generated.extend(desugar.generated);
+            result.productions.push(ExtProduction { lhs, rhs });
+            i = end;
         }
+        result.synthetic.extend(generated.iter().map(|p: &ExtProduction| p.lhs.clone()));
+        result.productions.extend(generated);
         Ok(result)
     }
 }
@@ -247,14 +569,15 @@ impl TryFrom<&str> for ExtGrammar {
 
 #[cfg(test)]
 mod tests {
-    use crate::grammar::{Term, OptTerm, ExtGrammar, ExtProduction, ExtExpression};
-
-    use super::parse_term;
+    use crate::grammar::{Term, OptTerm, ExtGrammar, ExtProduction, ExtExpression, ParseError, Location};
 
     #[test]
     fn parse_term_test() {
-        assert_eq!(parse_term("goga".chars()), Term::Terminal("goga".to_string()));
-        assert_eq!(parse_term("<goga>".chars()), Term::Nonterminal("goga".to_string()));
+        let grammar: ExtGrammar = "<a> ::= goga <goga>".try_into().unwrap();
+        assert_eq!(grammar.productions[0].rhs[0].terms, vec![
+            OptTerm::obl(Term::Terminal("goga".to_string())),
+            OptTerm::obl(Term::Nonterminal("goga".to_string())),
+        ]);
     }
 
     #[test]
@@ -321,6 +644,7 @@ mod tests {
                             ],
                         },
                     ],
+                    ..Default::default()
                 })
             },
             Err(err) => panic!("{}", err),
@@ -392,6 +716,7 @@ mod tests {
                             ],
                         },
                     ],
+                    ..Default::default()
                 })
             },
             Err(err) => panic!("{}", err),
@@ -399,7 +724,7 @@ mod tests {
         
     }
 
-    //#[test]
+    #[test]
     fn try_from_or_test() {
         let grammar: Result<ExtGrammar, _> = r#"
             <expr>     ::= <list> | <list> "|" <expr>
@@ -436,9 +761,175 @@ mod tests {
                             ],
                         },
                     ],
+                    ..Default::default()
                 })
             },
             Err(err) => panic!("{}", err),
         }
     }
+
+    #[test]
+    fn ebnf_repetition_test() {
+        let grammar: ExtGrammar = r#"
+            <list> ::= "[" <item> ("," <item>)* "]" | "[" "]"
+            <item> ::= "a"+ | "b"
+        "#.try_into().unwrap();
+
+        assert_eq!(grammar.productions.iter().map(|p| (p.lhs.as_str(), grammar.synthetic.contains(&p.lhs))).collect::<Vec<_>>(), vec![
+            ("list", false),
+            ("item", false),
+            ("list:1", true),
+            ("item:1", true),
+        ]);
+        assert_eq!(grammar.productions[2].rhs, vec![
+            ExtExpression { terms: vec![ OptTerm::obl(Term::Terminal(",".to_string())), OptTerm::obl(Term::Nonterminal("item".to_string())) ] },
+            ExtExpression { terms: vec![
+                OptTerm::obl(Term::Terminal(",".to_string())),
+                OptTerm::obl(Term::Nonterminal("item".to_string())),
+                OptTerm::obl(Term::Nonterminal("list:1".to_string())),
+            ] },
+        ]);
+        assert_eq!(grammar.productions[0].rhs[0].terms[2], OptTerm::opt(Term::Nonterminal("list:1".to_string())));
+        assert_eq!(grammar.productions[1].rhs[0].terms, vec![ OptTerm::obl(Term::Nonterminal("item:1".to_string())) ]);
+    }
+
+    #[test]
+    fn ebnf_group_test() {
+        let grammar: ExtGrammar = r#"
+            <stmt> ::= "let" ("mut")? <id> ["=" <id>] ("+" | "-") ("!")
+            <id>   ::= "x"
+        "#.try_into().unwrap();
+
+        let lhs: Vec<_> = grammar.productions.iter().map(|p| p.lhs.clone()).collect();
+        assert_eq!(lhs, vec![ "stmt", "id", "stmt:1", "stmt:2" ]);
+        assert_eq!(grammar.productions[0].rhs[0].terms, vec![
+            OptTerm::obl(Term::Terminal("let".to_string())),
+            OptTerm::opt(Term::Terminal("mut".to_string())),
+            OptTerm::obl(Term::Nonterminal("id".to_string())),
+            OptTerm::opt(Term::Nonterminal("stmt:1".to_string())),
+            OptTerm::obl(Term::Nonterminal("stmt:2".to_string())),
+            OptTerm::obl(Term::Terminal("!".to_string())),
+        ]);
+        assert_eq!(grammar.productions[3].rhs.len(), 2);
+    }
+
+    #[test]
+    fn nested_optional_group_test() {
+        for text in [ r#"<a> ::= [ ( "x" ) ]"#, r#"<a> ::= ( ( "x" ) )?"# ] {
+            let grammar: ExtGrammar = text.try_into().unwrap();
+            assert_eq!(grammar.productions[0].rhs[0].terms, vec![ OptTerm::opt(Term::Nonterminal("a:1".to_string())) ]);
+            assert_eq!(grammar.productions[1].rhs[0].terms, vec![ OptTerm::obl(Term::Terminal("x".to_string())) ]);
+            assert!(grammar.synthetic.contains("a:1"));
+        }
+    }
+
+    #[test]
+    fn nullable_repetition_test() {
+        let x = || OptTerm::obl(Term::Terminal("x".to_string()));
+        let y = || OptTerm::obl(Term::Terminal("y".to_string()));
+        let a1 = || Term::Nonterminal("a:1".to_string());
+
+        let grammar: ExtGrammar = r#"<a> ::= ( "x" | ε )*"#.try_into().unwrap();
+        assert_eq!(grammar.productions[0].rhs[0].terms, vec![ OptTerm::opt(a1()) ]);
+        assert_eq!(grammar.productions[1].rhs, vec![
+            ExtExpression { terms: vec![ x() ] },
+            ExtExpression { terms: vec![ x(), OptTerm::obl(a1()) ] },
+        ]);
+
+        // `+` over a nullable body may match nothing as well
+        let grammar: ExtGrammar = r#"<a> ::= ( "x"? "y"? )+"#.try_into().unwrap();
+        assert_eq!(grammar.productions[0].rhs[0].terms, vec![ OptTerm::opt(a1()) ]);
+        let y_opt = || OptTerm::opt(Term::Terminal("y".to_string()));
+        assert_eq!(grammar.productions[1].rhs, vec![
+            ExtExpression { terms: vec![ x(), y_opt() ] },
+            ExtExpression { terms: vec![ y() ] },
+            ExtExpression { terms: vec![ x(), y_opt(), OptTerm::obl(a1()) ] },
+            ExtExpression { terms: vec![ y(), OptTerm::obl(a1()) ] },
+        ]);
+
+        for text in [ r#"<a> ::= ( "x" | ε )*"#, r#"<a> ::= ( "x"? "y"? )+"#, r#"<a> ::= ( ε )+"# ] {
+            let grammar: ExtGrammar = text.try_into().unwrap();
+            for p in grammar.flatten().productions {
+                let cycle = [ Term::Nonterminal(p.lhs.clone()) ];
+                assert!(p.rhs.iter().all(|e| e.terms != cycle), "{}: {}", text, p);
+            }
+        }
+    }
+
+    #[test]
+    fn optional_word_test() {
+        // brackets around a bare word are an optional group, char classes are written `<[a-z]>`
+        let grammar: ExtGrammar = r#"
+            <if> ::= "if" [else-if] [a-z] <[a-z]>
+        "#.try_into().unwrap();
+        assert_eq!(grammar.productions[0].rhs[0].terms, vec![
+            OptTerm::obl(Term::Terminal("if".to_string())),
+            OptTerm::opt(Term::Terminal("else-if".to_string())),
+            OptTerm::opt(Term::Terminal("a-z".to_string())),
+            OptTerm::obl(Term::Nonterminal("[a-z]".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn ebnf_error_test() {
+        let unbalanced: Result<ExtGrammar, _> = r#"<a> ::= ("x" | "y""#.try_into();
+        assert_eq!(unbalanced, Err(ParseError::UnbalancedGroup(Location { line: 1, column: 9 })));
+
+        let closing: Result<ExtGrammar, _> = r#"<a> ::= "x" ]"#.try_into();
+        assert_eq!(closing, Err(ParseError::UnbalancedGroup(Location { line: 1, column: 13 })));
+
+        let operator: Result<ExtGrammar, _> = r#"<a> ::= * "x""#.try_into();
+        assert_eq!(operator, Err(ParseError::UnexpectedOperator(Location { line: 1, column: 9 })));
+    }
+
+    #[test]
+    fn multiline_test() {
+        let grammar: ExtGrammar = r#"
+            ; declarations
+            <decl>  ::= "let" <id>   # no initializer
+                      | "let" <id> "=" "\"" <id> "\""
+            <id>    ::= "x" |
+                        "y"
+            <args>  ::= ( <id>
+                          "," )* " "
+        "#.try_into().unwrap();
+
+        let lhs: Vec<_> = grammar.productions.iter().map(|p| p.lhs.as_str()).collect();
+        assert_eq!(lhs, vec![ "decl", "id", "args", "args:1" ]);
+        assert_eq!(grammar.productions[0].rhs.len(), 2);
+        assert_eq!(grammar.productions[0].rhs[1].terms[3], OptTerm::obl(Term::Terminal("\"".to_string())));
+        assert_eq!(grammar.productions[1].rhs.len(), 2);
+        assert_eq!(grammar.productions[2].rhs[0].terms[1], OptTerm::obl(Term::Terminal(" ".to_string())));
+    }
+
+    #[test]
+    fn error_location_test() {
+        let missing_lhs: Result<ExtGrammar, _> = "\n  | <a>".try_into();
+        assert_eq!(missing_lhs, Err(ParseError::LhsNotFound(Location { line: 2, column: 3 })));
+
+        let wrong_lhs: Result<ExtGrammar, _> = "<a> ::= b\nb ::= c".try_into();
+        assert_eq!(wrong_lhs, Err(ParseError::WrongLhs(Location { line: 2, column: 1 }, "b".to_string())));
+
+        let missing_rhs: Result<ExtGrammar, _> = "<a> ::= b\n<c> d".try_into();
+        assert_eq!(missing_rhs, Err(ParseError::RhsNotFound(Location { line: 2, column: 1 })));
+
+        let define: Result<ExtGrammar, _> = "<a> ::= b ::= c".try_into();
+        assert_eq!(define, Err(ParseError::UnexpectedDefine(Location { line: 1, column: 11 })));
+        assert_eq!(format!("{}", define.unwrap_err()), "1:11: unexpected '::=' in rhs");
+    }
+
+    #[test]
+    fn empty_alternative_test() {
+        let trailing: Result<ExtGrammar, _> = "<a> ::= \"x\" |\n<b> ::= \"y\"".try_into();
+        assert_eq!(trailing, Err(ParseError::EmptyAlternative(Location { line: 1, column: 13 })));
+
+        let empty_rhs: Result<ExtGrammar, _> = "<a> ::=\n<b> ::= \"y\"".try_into();
+        assert_eq!(empty_rhs, Err(ParseError::EmptyAlternative(Location { line: 1, column: 5 })));
+
+        let group: Result<ExtGrammar, _> = "<a> ::= \"x\" ( | \"y\")".try_into();
+        assert_eq!(group, Err(ParseError::EmptyAlternative(Location { line: 1, column: 13 })));
+
+        let epsilon: ExtGrammar = "<a> ::= \"x\" | ε\n<b> ::= \"y\"".try_into().unwrap();
+        assert_eq!(epsilon.productions[0].rhs[1].terms, vec![]);
+    }
 }
@@ -0,0 +1,506 @@
+use std::{collections::{HashMap, HashSet}, fmt::Display};
+
+use crate::grammar::*;
+use crate::tree::*;
+use crate::ctx::Token;
+
+
+/// where the children of an original node come from in a transformed tree
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    /// child of the transformed node at this index
+    Child(usize),
+    /// node accumulated from the left recursive prefix, only in tail productions
+    Hole,
+    /// node of the original production which was substituted into this expression.
+    /// `tail` is the index of the child holding the tail of that production
+    Node { production: usize, pieces: Vec<Piece>, tail: Option<usize> },
+}
+
+/// how to rebuild the original node of a transformed expression
+#[derive(Debug, Clone, PartialEq)]
+struct Provenance {
+    pieces: Vec<Piece>,
+    /// index of the child holding the tail to fold onto the node
+    tail: Option<usize>,
+}
+
+impl Provenance {
+    fn identity(len: usize) -> Self {
+        Self { pieces: (0..len).map(Piece::Child).collect(), tail: None }
+    }
+}
+
+/// replaces the leftmost child with `first` and shifts the other children by `shift`
+fn replace_first(pieces: &[Piece], first: &Piece, shift: isize) -> Vec<Piece> {
+    let move_index = |i: usize| (i as isize + shift) as usize;
+    pieces.iter().map(|piece| match piece {
+        Piece::Child(0) => first.clone(),
+        Piece::Child(i) => Piece::Child(move_index(*i)),
+        Piece::Hole => Piece::Hole,
+        Piece::Node { production, pieces, tail } => Piece::Node {
+            production: *production,
+            pieces: replace_first(pieces, first, shift),
+            tail: tail.map(move_index),
+        },
+    }).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LeftRecursionError {
+    /// expression with no terms, which can hide left recursion behind it
+    EmptyExpression(String),
+    /// `<a> =>+ <a>`, there is no equivalent grammar without left recursion
+    Cycle(String),
+}
+
+impl Display for LeftRecursionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LeftRecursionError::EmptyExpression(lhs) => f.write_fmt(format_args!("<{}> has an empty expression", lhs)),
+            LeftRecursionError::Cycle(lhs) => f.write_fmt(format_args!("<{}> derives itself", lhs)),
+        }
+    }
+}
+
+impl std::error::Error for LeftRecursionError {}
+
+/// grammar without left recursion together with what is needed to map its trees back
+#[derive(Debug)]
+pub struct LeftRecursionFree {
+    /// the first production of every lhs in the original order followed by the generated tail productions `<a'>`
+    /// and the wrappers of expressions which would otherwise be indistinguishable in a tree
+    pub grammar: Grammar,
+    /// indexed like `grammar.productions[i].rhs[j]`
+    provenance: Vec<Vec<Provenance>>,
+    is_tail: Vec<bool>,
+    is_wrapper: Vec<bool>,
+    /// productions which were synthetic in the original grammar
+    synthetic: HashSet<String>,
+    lookup: HashMap<String, usize>,
+}
+
+type Expressions = Vec<(Vec<Term>, Provenance)>;
+/// provenance of a node and its children, taken out one by one while building
+type Unpacked<'t, 'g, T> = (&'g Provenance, Vec<Option<ParseTreeNode<'t, 'g, T>>>);
+
+fn first_nonterminal(terms: &[Term], lookup: &HashMap<String, usize>) -> Option<usize> {
+    match terms.first() {
+        Some(Term::Nonterminal(n)) => lookup.get(n).copied(),
+        _ => None,
+    }
+}
+
+/// `result[a][b]` if `a` derives a string starting with `b` in one or more steps
+fn left_corners(productions: &[Expressions], lookup: &HashMap<String, usize>) -> Vec<Vec<bool>> {
+    let len = productions.len();
+    let mut result = vec![vec![false; len]; len];
+    for (a, row) in result.iter_mut().enumerate() {
+        let mut stack = vec![ a ];
+        while let Some(v) = stack.pop() {
+            for (terms, _) in &productions[v] {
+                if let Some(b) = first_nonterminal(terms, lookup) {
+                    if !row[b] {
+                        row[b] = true;
+                        stack.push(b);
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+fn fresh_name(lhs: &str, lookup: &HashMap<String, usize>) -> String {
+    let mut result = format!("{}'", lhs);
+    while lookup.contains_key(&result) {
+        result.push('\'');
+    }
+    result
+}
+
+impl Grammar {
+    /// lhs of the productions deriving a string which starts with themselves
+    pub fn left_recursive(&self) -> Vec<String> {
+        let (names, productions, lookup) = expressions(self);
+        let corners = left_corners(&productions, &lookup);
+        names.into_iter()
+            .enumerate()
+            .filter(|&(i, _)| corners[i][i])
+            .map(|(_, lhs)| lhs)
+            .collect()
+    }
+
+    /// equivalent grammar without direct or indirect left recursion (Paull's algorithm, substituting
+    /// only nonterminals which lead back to the production being fixed). `<a> ::= <a> α | β` becomes
+    /// `<a> ::= β | β <a'>` and `<a'> ::= α | α <a'>`
+    pub fn eliminate_left_recursion(&self) -> Result<LeftRecursionFree, LeftRecursionError> {
+        let (mut names, mut productions, mut lookup) = expressions(self);
+        for (i, expressions) in productions.iter().enumerate() {
+            if expressions.iter().any(|(terms, _)| terms.is_empty()) {
+                return Err(LeftRecursionError::EmptyExpression(names[i].clone()))
+            }
+        }
+
+        let mut is_tail = vec![false; productions.len()];
+        let original_len = productions.len();
+        for i in 0..original_len {
+            let corners = left_corners(&productions, &lookup);
+            loop {
+                let position = productions[i].iter().position(|(terms, _)| {
+                    first_nonterminal(terms, &lookup).is_some_and(|j| j < i && corners[j][i])
+                });
+                let Some(position) = position else { break };
+                let (terms, provenance) = productions[i].remove(position);
+                let j = first_nonterminal(&terms, &lookup).unwrap();
+                let substituted: Expressions = productions[j].iter().map(|(first, first_provenance)| {
+                    let shift = first.len() as isize - 1;
+                    let node = Piece::Node { production: j, pieces: first_provenance.pieces.clone(), tail: first_provenance.tail };
+                    (
+                        first.iter().chain(&terms[1..]).cloned().collect(),
+                        Provenance {
+                            pieces: replace_first(&provenance.pieces, &node, shift),
+                            tail: provenance.tail.map(|t| (t as isize + shift) as usize),
+                        },
+                    )
+                }).collect();
+                productions[i].splice(position..position, substituted);
+            }
+
+            let is_direct = |terms: &Vec<Term>| first_nonterminal(terms, &lookup) == Some(i);
+            if !productions[i].iter().any(|(terms, _)| is_direct(terms)) {
+                continue;
+            }
+            let tail_name = fresh_name(&names[i], &lookup);
+            let tail = productions.len();
+            let mut fixed = vec![];
+            let mut tail_expressions = vec![];
+            for (terms, provenance) in std::mem::take(&mut productions[i]) {
+                if is_direct(&terms) {
+                    if terms.len() == 1 {
+                        return Err(LeftRecursionError::Cycle(names[i].clone()))
+                    }
+                    let pieces = replace_first(&provenance.pieces, &Piece::Hole, -1);
+                    let alpha: Vec<_> = terms[1..].to_vec();
+                    let mut alpha_tail = alpha.clone();
+                    alpha_tail.push(Term::Nonterminal(tail_name.clone()));
+                    tail_expressions.push((alpha, Provenance { pieces: pieces.clone(), tail: None }));
+                    tail_expressions.push((alpha_tail, Provenance { pieces, tail: Some(terms.len() - 1) }));
+                } else {
+                    let mut beta_tail = terms.clone();
+                    beta_tail.push(Term::Nonterminal(tail_name.clone()));
+                    let len = terms.len();
+                    fixed.push((terms, provenance.clone()));
+                    fixed.push((beta_tail, Provenance { pieces: provenance.pieces, tail: Some(len) }));
+                }
+            }
+            productions[i] = fixed;
+            productions.push(tail_expressions);
+            lookup.insert(tail_name.clone(), tail);
+            names.push(tail_name);
+            is_tail.push(true);
+        }
+
+        // substitution can produce equal terms with different provenance, a tree can not tell such expressions
+        // apart so every one after the first gets a production of its own: `<a> ::= <a''>` and `<a''> ::= terms`
+        let mut is_wrapper = vec![false; productions.len()];
+        for i in 0..productions.len() {
+            for k in 0..productions[i].len() {
+                let (terms, provenance) = &productions[i][k];
+                if !productions[i][..k].iter().any(|(other, other_provenance)| other == terms && other_provenance != provenance) {
+                    continue;
+                }
+                let wrapper_name = fresh_name(&names[i], &lookup);
+                let wrapped = std::mem::replace(
+                    &mut productions[i][k],
+                    (vec![ Term::Nonterminal(wrapper_name.clone()) ], Provenance::identity(1)),
+                );
+                lookup.insert(wrapper_name.clone(), productions.len());
+                productions.push(vec![ wrapped ]);
+                names.push(wrapper_name);
+                is_tail.push(false);
+                is_wrapper.push(true);
+            }
+        }
+
+        let provenance = productions
+            .iter()
+            .map(|expressions| expressions.iter().map(|(_, p)| p.clone()).collect())
+            .collect();
+        let grammar = Grammar {
+            productions: productions
+                .into_iter()
+                .zip(names)
+                .map(|(expressions, lhs)| Production {
+                    lhs,
+                    rhs: expressions.into_iter().map(|(terms, _)| Expression { terms }).collect(),
+                })
+                .collect(),
+            // spliced by `restore` once the tree is in terms of the original productions
+            synthetic: HashSet::new(),
+        };
+        Ok(LeftRecursionFree {
+            grammar,
+            provenance,
+            is_tail,
+            is_wrapper,
+            synthetic: self.synthetic.clone(),
+            lookup,
+        })
+    }
+}
+
+/// lhs and expressions of the first production of every lhs (the ones the engines use) with identity provenance.
+/// later productions with the same lhs are unreachable and left out
+fn expressions(grammar: &Grammar) -> (Vec<String>, Vec<Expressions>, HashMap<String, usize>) {
+    let mut lookup = HashMap::new();
+    let mut names = vec![];
+    let mut productions = vec![];
+    for p in &grammar.productions {
+        if lookup.contains_key(&p.lhs) {
+            continue;
+        }
+        lookup.insert(p.lhs.clone(), productions.len());
+        names.push(p.lhs.clone());
+        productions.push(p.rhs.iter().map(|e| (e.terms.clone(), Provenance::identity(e.terms.len()))).collect());
+    }
+    (names, productions, lookup)
+}
+
+impl LeftRecursionFree {
+    /// rebuilds a tree parsed with `self.grammar` in terms of the original productions,
+    /// `None` if the tree does not derive from `self.grammar`
+    pub fn restore<'t, 'g, T: Token>(&'g self, tree: ParseTree<'t, 'g, T>) -> Option<ParseTree<'t, 'g, T>> {
+        let result = self.restore_tree(tree)?;
+        if self.synthetic.is_empty() {
+            Some(result)
+        } else {
+            Some(result.splice(&self.synthetic))
+        }
+    }
+
+    /// expression of `production` matching the children. equal terms within a production have equal provenance
+    fn expression<T: Token>(&self, production: usize, rhs: &[ParseTreeNode<T>]) -> Option<usize> {
+        self.grammar.productions[production].rhs.iter().position(|e| {
+            e.terms.len() == rhs.len() && e.terms.iter().zip(rhs).all(|(term, node)| match (term, node) {
+                (Term::Terminal(name), ParseTreeNode::Terminal(token)) => token.name() == name,
+                (Term::Nonterminal(name), ParseTreeNode::Nonterminal(tree)) => tree.lhs == name,
+                _ => false,
+            })
+        })
+    }
+
+    /// provenance of the tree node and its children with every non tail child already restored
+    fn unpack<'t, 'g, T: Token>(&'g self, mut tree: ParseTree<'t, 'g, T>) -> Option<Unpacked<'t, 'g, T>> {
+        let production = *self.lookup.get(tree.lhs)?;
+        let expression = self.expression(production, &tree.rhs)?;
+        if let [ Term::Nonterminal(name) ] = &self.grammar.productions[production].rhs[expression].terms[..] {
+            if self.is_wrapper[self.lookup[name]] {
+                let Some(ParseTreeNode::Nonterminal(wrapped)) = tree.rhs.pop() else { unreachable!() };
+                return self.unpack(wrapped);
+            }
+        }
+        let provenance = &self.provenance[production][expression];
+        let children = std::mem::take(&mut tree.rhs).into_iter().map(|node| Some(Some(match node {
+            ParseTreeNode::Nonterminal(child) if !self.is_tail[self.lookup[child.lhs]] =>
+                ParseTreeNode::Nonterminal(self.restore_tree(child)?),
+            node => node,
+        }))).collect::<Option<_>>()?;
+        Some((provenance, children))
+    }
+
+    fn restore_tree<'t, 'g, T: Token>(&'g self, tree: ParseTree<'t, 'g, T>) -> Option<ParseTree<'t, 'g, T>> {
+        let production = *self.lookup.get(tree.lhs)?;
+        let begin = tree.range.start;
+        let (provenance, mut children) = self.unpack(tree)?;
+        self.build(production, &provenance.pieces, provenance.tail, &mut children, &mut None, begin)
+    }
+
+    fn build<'t, 'g, T: Token>(
+        &'g self,
+        production: usize,
+        pieces: &[Piece],
+        tail: Option<usize>,
+        children: &mut [Option<ParseTreeNode<'t, 'g, T>>],
+        hole: &mut Option<ParseTree<'t, 'g, T>>,
+        begin: usize
+    ) -> Option<ParseTree<'t, 'g, T>> {
+        let mut rhs = vec![];
+        let mut end = begin;
+        for piece in pieces {
+            let node = match piece {
+                Piece::Child(i) => children[*i].take().unwrap(),
+                Piece::Hole => ParseTreeNode::Nonterminal(hole.take().unwrap()),
+                Piece::Node { production, pieces, tail } =>
+                    ParseTreeNode::Nonterminal(self.build(*production, pieces, *tail, children, hole, end)?),
+            };
+            end += node.token_count();
+            rhs.push(node);
+        }
+        let mut result = ParseTree { lhs: &self.grammar.productions[production].lhs, rhs: rhs.into(), range: begin..end };
+
+        let mut next = tail.map(|i| children[i].take().unwrap());
+        while let Some(ParseTreeNode::Nonterminal(tail_tree)) = next {
+            let (provenance, mut tail_children) = self.unpack(tail_tree)?;
+            result = self.build(production, &provenance.pieces, None, &mut tail_children, &mut Some(result), begin)?;
+            next = provenance.tail.map(|i| tail_children[i].take().unwrap());
+        }
+        Some(result)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use trim_margin::MarginTrimmable;
+
+    use crate::grammar::{Grammar, ExtGrammar};
+    use crate::parse::{make_ctx, parse_with, Engine};
+
+    use super::LeftRecursionError;
+
+    fn grammar(text: &str) -> Grammar {
+        let g: ExtGrammar = text.trim_margin().unwrap().as_str().try_into().unwrap();
+        g.flatten()
+    }
+
+    fn tokens(t: &[&str]) -> Vec<String> {
+        t.iter().map(|t| t.to_string()).collect()
+    }
+
+    /// trees of the original grammar and restored trees of the transformed one, both sorted
+    fn compare(g: &Grammar, t: &Vec<String>) -> (Vec<String>, Vec<String>) {
+        let free = g.eliminate_left_recursion().unwrap();
+        assert_eq!(free.grammar.left_recursive(), Vec::<String>::new());
+
+        let mut expected: Vec<_> = parse_with(make_ctx(g, t, false, true), Engine::Earley)
+            .map(|t| format!("{:#}", t.unwrap()))
+            .collect();
+        let mut actual: Vec<_> = parse_with(make_ctx(&free.grammar, t, false, true), Engine::Earley)
+            .map(|t| format!("{:#}", free.restore(t.unwrap()).unwrap()))
+            .collect();
+        expected.sort();
+        actual.sort();
+        (expected, actual)
+    }
+
+    #[test]
+    fn direct_test() {
+        let g = grammar(r#"
+            |<e> ::= <e> "+" <t> | <e> "-" <t> | <t>
+            |<t> ::= "n"
+        "#);
+        assert_eq!(g.left_recursive(), vec![ "e" ]);
+
+        let free = g.eliminate_left_recursion().unwrap();
+        assert_eq!(format!("{}", free.grammar), r#"
+            |<e> ::= <t> | <t> <e'>
+            |<t> ::= "n"
+            |<e'> ::= "+" <t> | "+" <t> <e'> | "-" <t> | "-" <t> <e'>
+        "#.trim_margin().unwrap());
+
+        let (expected, actual) = compare(&g, &tokens(&[ "n", "+", "n", "-", "n", "+", "n" ]));
+        assert_eq!(actual.len(), 1);
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn indirect_test() {
+        let g = grammar(r#"
+            |<a> ::= <b> "x" | "y"
+            |<b> ::= <c> "z" | "w"
+            |<c> ::= <a> "v" | <b> "u"
+        "#);
+        assert_eq!(g.left_recursive(), vec![ "a", "b", "c" ]);
+
+        for t in [
+            tokens(&[ "y" ]),
+            tokens(&[ "w", "x" ]),
+            tokens(&[ "y", "v", "z", "x" ]),
+            tokens(&[ "w", "u", "z", "u", "z", "x", "v", "z", "x" ]),
+        ] {
+            let (expected, actual) = compare(&g, &t);
+            assert_eq!(actual.len(), 1);
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn ambiguous_test() {
+        let g = grammar(r#"
+            |<s> ::= <l> ";"
+            |<l> ::= <l> <l> | <i>
+            |<i> ::= "i" | <l> "!"
+        "#);
+        let (expected, actual) = compare(&g, &tokens(&[ "i", "i", "!", "i", ";" ]));
+        assert_eq!(actual.len(), 3);
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn equal_terms_test() {
+        let g = grammar(r#"
+            |<s> ::= <a>
+            |<b> ::= "y" | <a> "z"
+            |<a> ::= <b> "x" | "y" "x"
+        "#);
+        let free = g.eliminate_left_recursion().unwrap();
+        assert_eq!(format!("{}", free.grammar), r#"
+            |<s> ::= <a>
+            |<b> ::= "y" | <a> "z"
+            |<a> ::= "y" "x" | "y" "x" <a'> | <a''> | <a'''>
+            |<a'> ::= "z" "x" | "z" "x" <a'>
+            |<a''> ::= "y" "x"
+            |<a'''> ::= "y" "x" <a'>
+        "#.trim_margin().unwrap());
+
+        for t in [ tokens(&[ "y", "x" ]), tokens(&[ "y", "x", "z", "x" ]) ] {
+            let (expected, actual) = compare(&g, &t);
+            assert_eq!(actual.len(), 2);
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn foreign_tree_test() {
+        let g = grammar(r#"
+            |<e> ::= <e> "+" <t> | <t>
+            |<t> ::= "n"
+        "#);
+        let free = g.eliminate_left_recursion().unwrap();
+        let t = tokens(&[ "n", "+", "n" ]);
+        let tree = parse_with(make_ctx(&g, &t, false, true), Engine::Earley).next().unwrap().unwrap();
+        assert!(free.restore(tree).is_none());
+    }
+
+    #[test]
+    fn duplicate_lhs_test() {
+        let g = grammar(r#"
+            |<a> ::= <a> "x" | "y"
+            |<b> ::= "z"
+            |<a> ::= "w"
+        "#);
+        assert_eq!(g.left_recursive(), vec![ "a" ]);
+        let free = g.eliminate_left_recursion().unwrap();
+        assert_eq!(format!("{}", free.grammar), r#"
+            |<a> ::= "y" | "y" <a'>
+            |<b> ::= "z"
+            |<a'> ::= "x" | "x" <a'>
+        "#.trim_margin().unwrap());
+    }
+
+    #[test]
+    fn error_test() {
+        let cycle = grammar(r#"
+            |<a> ::= <b> | "x"
+            |<b> ::= <a>
+        "#);
+        assert_eq!(cycle.eliminate_left_recursion().unwrap_err(), LeftRecursionError::Cycle("b".to_string()));
+
+        let empty = grammar(r#"
+            |<a> ::= <b> <a> "x" | "x"
+            |<b> ::= "y"?
+        "#);
+        assert_eq!(empty.eliminate_left_recursion().unwrap_err(), LeftRecursionError::EmptyExpression("b".to_string()));
+    }
+}
@@ -0,0 +1,280 @@
+use std::{collections::HashSet, fmt::Display};
+
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+
+use crate::ctx::Token;
+use crate::grammar::{Grammar, Term, Location};
+
+
+/// bytes `begin..end` of the source, starting at 1-based `line` and `column` (in chars)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub begin: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn location(&self) -> Location {
+        Location { line: self.line, column: self.column }
+    }
+
+    /// empty span right after `text`, which starts at `begin`
+    pub(crate) fn after(&self, text: &str) -> Span {
+        let mut next = Span { begin: self.begin + text.len(), end: self.begin + text.len(), ..*self };
+        for c in text.chars() {
+            if c == '\n' {
+                next.line += 1;
+                next.column = 1;
+            } else {
+                next.column += 1;
+            }
+        }
+        next
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pattern {
+    Literal(String),
+    /// matched at the current position only, as if it started with `\A`
+    Regex(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenRule {
+    /// name of the emitted tokens, the terminal they match in the grammar
+    pub name: String,
+    pub pattern: Pattern,
+    /// decides between rules matching the same length, higher wins
+    pub priority: i32,
+    /// matched text is dropped, e.g. whitespace or comments
+    pub skip: bool,
+}
+
+impl TokenRule {
+    /// exact text with priority 1, so keywords win over identifier patterns of the same length
+    pub fn literal(name: &str, text: &str) -> Self {
+        Self { name: name.to_string(), pattern: Pattern::Literal(text.to_string()), priority: 1, skip: false }
+    }
+
+    pub fn regex(name: &str, pattern: &str) -> Self {
+        Self { name: name.to_string(), pattern: Pattern::Regex(pattern.to_string()), priority: 0, skip: false }
+    }
+
+    pub fn with_priority(self, priority: i32) -> Self {
+        Self { priority, ..self }
+    }
+
+    pub fn skipped(self) -> Self {
+        Self { skip: true, ..self }
+    }
+}
+
+/// token cut from the source by a `Lexer`. displayed as its text, matched against terminals by its name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LexToken {
+    pub name: String,
+    pub text: String,
+    pub span: Span,
+}
+
+impl Display for LexToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Token for LexToken {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn span(&self) -> Option<Span> {
+        Some(self.span)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexError {
+    /// the pattern of the rule named so is not a valid regex
+    Regex(String, String),
+    /// no rule matches the source at the char starting the span
+    NoMatch(Span, char),
+}
+
+impl Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LexError::Regex(name, message) => f.write_fmt(format_args!("invalid regex of token '{}': {}", name, message)),
+            LexError::NoMatch(span, c) => f.write_fmt(format_args!("{}: no token matches {:?}", span.location(), c)),
+        }
+    }
+}
+
+impl std::error::Error for LexError {}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Literal(String),
+    Regex(Regex),
+}
+
+impl Matcher {
+    /// length of the match at the start of `rest`
+    fn len(&self, rest: &str) -> Option<usize> {
+        match self {
+            Matcher::Literal(text) => rest.starts_with(text.as_str()).then_some(text.len()),
+            Matcher::Regex(regex) => regex.find(rest).map(|m| m.end()),
+        }
+    }
+}
+
+/// splits source text into tokens by the longest match of its rules,
+/// ties are broken by priority and then by the order of the rules
+#[derive(Debug, Clone)]
+pub struct Lexer {
+    rules: Vec<(TokenRule, Matcher)>,
+}
+
+impl Lexer {
+    pub fn new(rules: Vec<TokenRule>) -> Result<Self, LexError> {
+        let rules = rules.into_iter().map(|rule| {
+            let matcher = match &rule.pattern {
+                Pattern::Literal(text) => Matcher::Literal(text.clone()),
+                Pattern::Regex(pattern) => Matcher::Regex(
+                    Regex::new(&format!(r"\A(?:{})", pattern)).map_err(|err| LexError::Regex(rule.name.clone(), err.to_string()))?
+                ),
+            };
+            Ok((rule, matcher))
+        }).collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    /// `rules` followed by a literal rule for every terminal of the grammar that no rule is named after.
+    /// terminals standing for a class of tokens (`"ID"`, `"NUM"`) get their regex rule in `rules`
+    pub fn from_grammar(grammar: &Grammar, rules: Vec<TokenRule>) -> Result<Self, LexError> {
+        let mut named: HashSet<String> = rules.iter().map(|r| r.name.clone()).collect();
+        let mut rules = rules;
+        for term in grammar.productions.iter().flat_map(|p| &p.rhs).flat_map(|e| &e.terms) {
+            if let Term::Terminal(t) = term {
+                if named.insert(t.clone()) {
+                    rules.push(TokenRule::literal(t, t));
+                }
+            }
+        }
+        Self::new(rules)
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &TokenRule> {
+        self.rules.iter().map(|(rule, _)| rule)
+    }
+
+    pub fn tokenize(&self, source: &str) -> Result<Vec<LexToken>, LexError> {
+        let mut tokens = vec![];
+        let mut span = Span { begin: 0, end: 0, line: 1, column: 1 };
+        while span.begin < source.len() {
+            let rest = &source[span.begin..];
+            let best = self.rules
+                .iter()
+                .enumerate()
+                .filter_map(|(i, (rule, matcher))| matcher.len(rest).filter(|&len| len > 0).map(|len| (len, rule.priority, i)))
+                .max_by_key(|&(len, priority, i)| (len, priority, std::cmp::Reverse(i)));
+            let Some((len, _, i)) = best else {
+                return Err(LexError::NoMatch(Span { end: span.begin, ..span }, rest.chars().next().unwrap()))
+            };
+
+            let text = &rest[..len];
+            let rule = &self.rules[i].0;
+            span.end = span.begin + len;
+            if !rule.skip {
+                tokens.push(LexToken { name: rule.name.clone(), text: text.to_string(), span });
+            }
+            span = span.after(text);
+        }
+        Ok(tokens)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::{ExtGrammar, Grammar};
+    use crate::parse::{make_ctx, parse_with, Engine};
+
+    use super::{Lexer, TokenRule, LexError, Span};
+
+    fn grammar(text: &str) -> Grammar {
+        let g: ExtGrammar = text.try_into().unwrap();
+        g.flatten()
+    }
+
+    fn names(lexer: &Lexer, source: &str) -> Vec<String> {
+        lexer.tokenize(source).unwrap().into_iter().map(|t| t.name).collect()
+    }
+
+    #[test]
+    fn match_test() {
+        let lexer = Lexer::new(vec![
+            TokenRule::regex("ws", r"\s+").skipped(),
+            TokenRule::regex("comment", r"//[^\n]*").skipped(),
+            TokenRule::regex("ID", r"[a-z_]\w*"),
+            TokenRule::regex("NUM", r"[0-9]+"),
+            TokenRule::literal("if", "if"),
+            TokenRule::literal("=", "="),
+            TokenRule::literal("==", "=="),
+            TokenRule::literal("/", "/"),
+        ]).unwrap();
+        assert_eq!(names(&lexer, "if iffy == 10 // if\n x=y/2"), vec![ "if", "ID", "==", "NUM", "ID", "=", "ID", "/", "NUM" ]);
+
+        let tokens = lexer.tokenize("a\n  bc = 1").unwrap();
+        assert_eq!(tokens[1].text, "bc");
+        assert_eq!(tokens[1].span, Span { begin: 4, end: 6, line: 2, column: 3 });
+        assert_eq!(format!("{}", tokens[2]), "=");
+
+        assert_eq!(lexer.tokenize("a ?"), Err(LexError::NoMatch(Span { begin: 2, end: 2, line: 1, column: 3 }, '?')));
+        assert_eq!(format!("{}", lexer.tokenize("a\n?").unwrap_err()), "2:1: no token matches '?'");
+        assert!(matches!(Lexer::new(vec![ TokenRule::regex("bad", "(") ]), Err(LexError::Regex(name, _)) if name == "bad"));
+    }
+
+    #[test]
+    fn priority_test() {
+        let lexer = Lexer::new(vec![
+            TokenRule::regex("ID", r"\w+"),
+            TokenRule::regex("TYPE", r"[A-Z]\w*").with_priority(2),
+        ]).unwrap();
+        assert_eq!(names(&lexer, "Foo"), vec![ "TYPE" ]);
+        // a longer match still wins over priority
+        let lexer = Lexer::new(vec![
+            TokenRule::regex("ID", r"\w+"),
+            TokenRule::regex("TYPE", r"[A-Z]").with_priority(2),
+        ]).unwrap();
+        assert_eq!(names(&lexer, "Foo"), vec![ "ID" ]);
+    }
+
+    #[test]
+    fn grammar_test() {
+        let g = grammar(include_str!("../tests/superhard.bnf"));
+        let lexer = Lexer::from_grammar(&g, vec![
+            // the grammar has a `" "` terminal, which would win over a single space otherwise
+            TokenRule::regex("ws", r"\s+").skipped().with_priority(2),
+            TokenRule::regex("ID", r"[A-Za-z_]\w*"),
+            TokenRule::regex("NUM", r"[0-9]+"),
+            TokenRule::regex("STR", r#""([^"\\]|\\.)*""#),
+        ]).unwrap();
+        assert!(lexer.rules().any(|r| r.name == "namespace"));
+        assert_eq!(lexer.rules().filter(|r| r.name == "ID").count(), 1);
+
+        let tokens = lexer.tokenize("x = namespace { y = 1 + \"s\"; };").unwrap();
+        assert_eq!(
+            tokens.iter().map(|t| t.name.as_str()).collect::<Vec<_>>(),
+            vec![ "ID", "=", "namespace", "{", "ID", "=", "NUM", "+", "STR", ";", "}", ";" ]
+        );
+        let tree = parse_with(make_ctx(&g, &tokens, false, true), Engine::Earley).next().unwrap().unwrap();
+        let text = format!("{:#}", tree);
+        assert!(text.contains("\n`````x\n"));
+        assert!(text.contains("\"s\"\n"));
+    }
+}
@@ -61,13 +61,51 @@ struct parser300b_Token {
     const void* data;
 };
 
-void parser300b_parse(const parser300b_Grammar* grammar, const parser300b_Token* tokens, size_t token_count);
+struct parser300b_Result;
+struct parser300b_Node;
+
+enum parser300b_Engine {
+    parser300b_Engine_Backtracking,
+    parser300b_Engine_Earley,
+    parser300b_Engine_Ll1,
+    parser300b_Engine_Lalr,
+    parser300b_Engine_Lr1,
+    parser300b_Engine_Glr,
+    parser300b_Engine_Cyk,
+};
+
+/**
+ * @brief parser300b_parse
+ * @param max_trees - stop after this many trees, 0 for all of them
+ * @return result owning all trees and error messages. never null, free with `parser300b_Result_free`
+ */
+parser300b_Result* parser300b_parse(const parser300b_Grammar* grammar, const parser300b_Token* tokens, size_t token_count, size_t max_trees);
+/// same as `parser300b_parse` with the given engine instead of Earley
+parser300b_Result* parser300b_parse_with(const parser300b_Grammar* grammar, const parser300b_Token* tokens, size_t token_count, size_t max_trees, parser300b_Engine engine);
+
+void parser300b_Result_free(parser300b_Result* result);
+size_t parser300b_Result_tree_count(const parser300b_Result* result);
+const parser300b_Node* parser300b_Result_tree(const parser300b_Result* result, size_t index);
+size_t parser300b_Result_error_count(const parser300b_Result* result);
+const char* parser300b_Result_error(const parser300b_Result* result, size_t index);
+
+bool parser300b_Node_is_terminal(const parser300b_Node* node);
+/// lhs of a nonterminal or token name of a terminal
+const char* parser300b_Node_name(const parser300b_Node* node);
+size_t parser300b_Node_child_count(const parser300b_Node* node);
+const parser300b_Node* parser300b_Node_child(const parser300b_Node* node, size_t index);
+/// index in the token array passed to `parser300b_parse`, SIZE_MAX for nonterminals
+size_t parser300b_Node_token_index(const parser300b_Node* node);
+/// `data` of the token passed to `parser300b_parse`, null for nonterminals
+const void* parser300b_Node_token_data(const parser300b_Node* node);
 
 #ifdef __cplusplus
 }
 
 #include <string.h>
 #include <string>
+#include <string_view>
+#include <utility>
 #include <vector>
 
 namespace parser300b {
@@ -148,14 +186,79 @@ const parser300b_Token* token_vec_to_c_ref(const std::vector<T>& tokens) {
     return result;
 }
 
+/**
+ * @brief view of a tree node, valid while the owning `Result` is alive
+ */
+class Node {
+    const parser300b_Node* m_node;
+public:
+    explicit Node(const parser300b_Node* node) : m_node(node) {}
+
+    bool is_terminal() const { return parser300b_Node_is_terminal(m_node); }
+    std::string_view name() const { return parser300b_Node_name(m_node); }
+    size_t child_count() const { return parser300b_Node_child_count(m_node); }
+    Node child(size_t index) const { return Node(parser300b_Node_child(m_node, index)); }
+
+    std::vector<Node> children() const {
+        std::vector<Node> result;
+        result.reserve(child_count());
+        for(size_t i = 0; i < child_count(); ++i) {
+            result.push_back(child(i));
+        }
+        return result;
+    }
+
+    size_t token_index() const { return parser300b_Node_token_index(m_node); }
+
+    /**
+     * @note refers to the element of the token vector passed to `parse`
+     */
+    template<Token T>
+    const T& token() const { return *static_cast<const T*>(parser300b_Node_token_data(m_node)); }
+};
+
+class Result {
+    parser300b_Result* m_result;
+public:
+    explicit Result(parser300b_Result* result) : m_result(result) {}
+    Result(const Result&) = delete;
+    Result& operator=(const Result&) = delete;
+    Result(Result&& other) noexcept : m_result(std::exchange(other.m_result, nullptr)) {}
+    Result& operator=(Result&& other) noexcept {
+        std::swap(m_result, other.m_result);
+        return *this;
+    }
+    ~Result() { parser300b_Result_free(m_result); }
+
+    bool ok() const { return tree_count() > 0; }
+    size_t tree_count() const { return parser300b_Result_tree_count(m_result); }
+    Node tree(size_t index) const { return Node(parser300b_Result_tree(m_result, index)); }
+
+    std::vector<Node> trees() const {
+        std::vector<Node> result;
+        result.reserve(tree_count());
+        for(size_t i = 0; i < tree_count(); ++i) {
+            result.push_back(tree(i));
+        }
+        return result;
+    }
+
+    size_t error_count() const { return parser300b_Result_error_count(m_result); }
+    std::string_view error(size_t index) const { return parser300b_Result_error(m_result, index); }
+};
+
+/**
+ * @note terminal nodes of the result refer to `tokens`, keep it alive while reading them
+ */
 template<Token T>
-inline void parse(const Grammar& grammar, const std::vector<T>& tokens) {
+inline Result parse(const Grammar& grammar, const std::vector<T>& tokens, size_t max_trees = 0, parser300b_Engine engine = parser300b_Engine_Earley) {
     auto c_grammar = grammar.c_ref();
     auto c_tokens = token_vec_to_c_ref(tokens);
 
-    parser300b_parse(c_grammar, c_tokens, tokens.size());
+    Result result(parser300b_parse_with(c_grammar, c_tokens, tokens.size(), max_trees, engine));
     free((parser300b_Token*)c_tokens);
     parser300b_Grammar_free(c_grammar);
+    return result;
 }
 
 }
@@ -5,24 +5,60 @@
 //mod iterator2d;
 mod assert;
 mod grammar;
+mod analysis;
+mod bnf_lexer;
 mod tree;
 mod combination;
 mod ctx;
+mod stack;
 mod parse;
+mod earley;
+mod sppf;
 mod ffi;
+mod error;
+mod validate;
+mod left_recursion;
+mod ll1;
+mod lr;
+mod glr;
+mod cnf;
+mod lexer;
+mod scannerless;
 
 pub use grammar::*;
 pub use tree::*;
 
 pub use parse::{
     parse,
+    parse_with,
+    parse_with_options,
+    parse_as,
     make_ctx,
-    Error
+    ParseOptions,
+    Engine,
 };
 
+pub use error::*;
+pub use validate::*;
+pub use analysis::{GrammarSets, NonterminalSets};
+pub use left_recursion::*;
+pub use ll1::{parse_ll1, Ll1Table, Ll1Conflict, ConflictKind};
+pub use lr::{parse_lr, LrTable, LrConflict, LrConflictKind, LrMode};
+
+pub use earley::parse_earley;
+pub use sppf::*;
+pub use glr::{parse_glr, parse_glr_forest, parse_glr_forest_with};
+pub use cnf::{parse_cyk_forest, ChomskyNormalForm};
+pub use lexer::*;
+pub use scannerless::{Scannerless, ScannerlessOptions, WHITESPACE};
+
+pub use ctx::{Ctx, MemoStats, Token};
+
 pub use assert::init_assert_contains_tree;
 
 pub use ffi::*;
 
+// Miri can not call into jemalloc
+#[cfg(not(miri))]
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
\ No newline at end of file
@@ -0,0 +1,418 @@
+use std::{collections::{BTreeSet, HashMap}, fmt::Display};
+
+use crate::grammar::*;
+use crate::tree::*;
+use crate::ctx::*;
+use crate::error::*;
+use crate::analysis::GrammarSets;
+use crate::earley::{production_lookup, parse_earley_from};
+use crate::parse::{ParseTreeIter, splice_synthetic, parse_failed};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// both expressions derive strings starting with the lookahead, or both derive the empty string
+    FirstFirst,
+    /// one expression starts with the lookahead and the other is nullable while the lookahead can follow
+    FirstFollow,
+}
+
+/// two expressions of a production predicted on the same lookahead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ll1Conflict {
+    pub kind: ConflictKind,
+    pub nonterminal: String,
+    /// `None` for the end of the input
+    pub lookahead: Option<String>,
+    /// indices into the rhs of the production
+    pub expressions: (usize, usize),
+    /// the two expressions as written in the grammar
+    pub alternatives: (String, String),
+}
+
+impl Display for Ll1Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} conflict in <{}> on {}: {} | {}",
+            match self.kind {
+                ConflictKind::FirstFirst => "FIRST/FIRST",
+                ConflictKind::FirstFollow => "FIRST/FOLLOW",
+            },
+            self.nonterminal,
+            self.lookahead.as_ref().map_or("$".to_string(), |t| format!("\"{}\"", t)),
+            self.alternatives.0,
+            self.alternatives.1,
+        ))
+    }
+}
+
+/// how an expression got into a table cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Prediction {
+    First(usize),
+    Follow(usize),
+}
+
+impl Prediction {
+    fn expression(self) -> usize {
+        match self {
+            Prediction::First(e) | Prediction::Follow(e) => e,
+        }
+    }
+}
+
+/// predictive table: the expression to expand a production with for every lookahead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ll1Table {
+    pub start: usize,
+    /// terminal lookahead to expression, indexed by production
+    rows: Vec<HashMap<String, usize>>,
+    /// expression predicted at the end of the input, indexed by production
+    end: Vec<Option<usize>>,
+    conflicts: Vec<Ll1Conflict>,
+}
+
+impl Ll1Table {
+    fn new(grammar: &Grammar, start: usize, sets: &GrammarSets) -> Self {
+        let lookup = production_lookup(grammar);
+        let nonterminal = |name: &str| sets.get(name);
+        let mut rows = vec![HashMap::new(); grammar.productions.len()];
+        let mut end = vec![None; grammar.productions.len()];
+        let mut conflicts = vec![];
+
+        for (i, p) in grammar.productions.iter().enumerate() {
+            if lookup[p.lhs.as_str()] != i {
+                continue;
+            }
+            let mut cells: Vec<(Option<String>, Vec<Prediction>)> = vec![];
+            let mut predict = |lookahead: Option<String>, prediction: Prediction| {
+                match cells.iter_mut().find(|(l, _)| *l == lookahead) {
+                    Some((_, predictions)) => predictions.push(prediction),
+                    None => cells.push((lookahead, vec![ prediction ])),
+                }
+            };
+
+            for (e, expression) in p.rhs.iter().enumerate() {
+                let mut first = BTreeSet::new();
+                let mut nullable = true;
+                for term in &expression.terms {
+                    match term {
+                        Term::Terminal(t) => {
+                            first.insert(t.clone());
+                            nullable = false;
+                        },
+                        Term::Nonterminal(n) => {
+                            let sets = nonterminal(n);
+                            first.extend(sets.iter().flat_map(|s| s.first.iter().cloned()));
+                            nullable = sets.is_some_and(|s| s.nullable);
+                        },
+                    }
+                    if !nullable {
+                        break;
+                    }
+                }
+                for t in first {
+                    predict(Some(t), Prediction::First(e));
+                }
+                if nullable {
+                    let follow = nonterminal(&p.lhs).unwrap();
+                    for t in &follow.follow {
+                        predict(Some(t.clone()), Prediction::Follow(e));
+                    }
+                    if follow.at_end {
+                        predict(None, Prediction::Follow(e));
+                    }
+                }
+            }
+
+            for (lookahead, predictions) in cells {
+                let chosen = predictions[0].expression();
+                for other in &predictions[1..] {
+                    let kind = match (predictions[0], other) {
+                        (Prediction::First(_), Prediction::Follow(_)) | (Prediction::Follow(_), Prediction::First(_)) =>
+                            ConflictKind::FirstFollow,
+                        _ => ConflictKind::FirstFirst,
+                    };
+                    conflicts.push(Ll1Conflict {
+                        kind,
+                        nonterminal: p.lhs.clone(),
+                        lookahead: lookahead.clone(),
+                        expressions: (chosen, other.expression()),
+                        alternatives: (format!("{}", p.rhs[chosen]), format!("{}", p.rhs[other.expression()])),
+                    });
+                }
+                match lookahead {
+                    Some(t) => { rows[i].insert(t, chosen); },
+                    None => end[i] = Some(chosen),
+                }
+            }
+        }
+
+        Self { start, rows, end, conflicts }
+    }
+
+    /// every cell with more than one expression. the table keeps the first one written in the grammar
+    pub fn conflicts(&self) -> &[Ll1Conflict] {
+        &self.conflicts
+    }
+
+    pub fn is_ll1(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    /// expression of `production` predicted on `lookahead`, `None` being the end of the input
+    pub fn predict(&self, production: usize, lookahead: Option<&str>) -> Option<usize> {
+        match lookahead {
+            Some(t) => self.rows.get(production)?.get(t).copied(),
+            None => *self.end.get(production)?,
+        }
+    }
+
+    /// terminals with an entry in the row of `production`
+    fn expected(&self, production: usize) -> BTreeSet<String> {
+        self.rows[production].keys().cloned().collect()
+    }
+}
+
+impl Grammar {
+    /// predictive table with the first production as the start one
+    pub fn ll1_table(&self) -> Option<Ll1Table> {
+        (!self.productions.is_empty()).then(|| Ll1Table::new(self, 0, &self.sets()))
+    }
+
+    /// same as `ll1_table` for the input parsed as `start`
+    pub fn ll1_table_from(&self, start: &str) -> Option<Ll1Table> {
+        let start = production_lookup(self).get(start).copied()?;
+        Some(Ll1Table::new(self, start, &self.sets_from(&self.productions[start].lhs)))
+    }
+}
+
+/// production being expanded with the tree built so far
+struct Frame<'tg, T> {
+    production: usize,
+    expression: usize,
+    tree: ParseTree<'tg, 'tg, T>,
+}
+
+/// deterministic parse with the predictive table, one tree or a syntax error
+fn parse_table<'tg, T: Token>(ctx: &Ctx<'tg, 'tg, T>, table: &Ll1Table) -> Result<ParseTree<'tg, 'tg, T>, Error> {
+    let grammar = ctx.grammar;
+    let lookup = production_lookup(grammar);
+    let mut position = ctx.begin;
+    let lookahead = |position: usize| (position < ctx.end).then(|| ctx.tokens[position].name());
+    let syntax_error = |position: usize, expected: BTreeSet<String>, stack: &[Frame<'tg, T>], expanding: Option<usize>| Error::Syntax(SyntaxError {
+        position,
+        expected,
+        production_stack: stack
+            .iter()
+            .map(|f| f.production)
+            .chain(expanding)
+            .map(|p| grammar.productions[p].lhs.clone())
+            .collect(),
+        tokens: ctx.tokens.iter().map(|t| t.name().to_string()).collect(),
+    });
+    let expand = |production: usize, position: usize, stack: &[Frame<'tg, T>]| {
+        match table.predict(production, lookahead(position)) {
+            Some(expression) => Ok(Frame {
+                production,
+                expression,
+                tree: ParseTree { lhs: &grammar.productions[production].lhs, rhs: Children::default(), range: position..position },
+            }),
+            None => Err(syntax_error(position, table.expected(production), stack, Some(production))),
+        }
+    };
+
+    let mut stack = vec![];
+    let root = expand(table.start, position, &stack)?;
+    stack.push(root);
+    loop {
+        let top = stack.last_mut().unwrap();
+        let terms = &grammar.productions[top.production].rhs[top.expression].terms;
+        match terms.get(top.tree.rhs.len()) {
+            None => {
+                let mut done = stack.pop().unwrap();
+                done.tree.range.end = position;
+                match stack.last_mut() {
+                    Some(parent) => parent.tree.rhs.push(ParseTreeNode::Nonterminal(done.tree)),
+                    None if position == ctx.end => return Ok(done.tree),
+                    None => return Err(syntax_error(position, BTreeSet::new(), &[], None)),
+                }
+            },
+            Some(Term::Terminal(terminal)) => {
+                if lookahead(position) != Some(terminal.as_str()) {
+                    return Err(syntax_error(position, [ terminal.clone() ].into(), &stack, None))
+                }
+                top.tree.rhs.push(ParseTreeNode::Terminal(&ctx.tokens[position]));
+                position += 1;
+            },
+            Some(Term::Nonterminal(nonterminal)) => {
+                let Some(&production) = lookup.get(nonterminal.as_str()) else {
+                    return Err(Error::ProductionNotFound(nonterminal.clone()))
+                };
+                let frame = expand(production, position, &stack)?;
+                stack.push(frame);
+            },
+        }
+    }
+}
+
+/// linear time parse with a table of `ctx.grammar`, which can be built once for any number of inputs.
+/// a table with conflicts yields `Error::Ll1Conflicts`
+pub fn parse_ll1<'tg, T: Token + 'tg>(ctx: Ctx<'tg, 'tg, T>, table: &Ll1Table) -> ParseTreeIter<'tg, T> {
+    if !table.is_ll1() {
+        return parse_failed(ctx.ignore_errors, Error::Ll1Conflicts(table.conflicts().to_vec()))
+    }
+    match parse_table(&ctx, table) {
+        Ok(tree) => splice_synthetic(ctx.grammar, Box::new(std::iter::once(Ok(tree)))),
+        Err(err) => parse_failed(ctx.ignore_errors, err),
+    }
+}
+
+/// linear time parse when the grammar is LL(1) from `start`. otherwise the Earley engine, unless `strict`
+pub(crate) fn parse_ll1_from<'tg, T: Token + 'tg>(ctx: Ctx<'tg, 'tg, T>, start: usize, strict: bool) -> ParseTreeIter<'tg, T> {
+    let table = Ll1Table::new(ctx.grammar, start, &ctx.grammar.sets_from(&ctx.grammar.productions[start].lhs));
+    if !table.is_ll1() && !strict {
+        return parse_earley_from(ctx, start)
+    }
+    parse_ll1(ctx, &table)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::{ExtGrammar, Grammar};
+    use crate::parse::{make_ctx, parse_with, parse_with_options, Engine, ParseOptions};
+    use crate::error::Error;
+
+    use super::{parse_ll1, ConflictKind, Ll1Conflict};
+
+    fn grammar(text: &str) -> Grammar {
+        let g: ExtGrammar = text.try_into().unwrap();
+        g.flatten()
+    }
+
+    fn tokens(t: &[&str]) -> Vec<String> {
+        t.iter().map(|t| t.to_string()).collect()
+    }
+
+    static EXPR: &str = r#"
+        <expr>   ::= <term> <expr_r>
+        <expr_r> ::= "+" <term> <expr_r> | ε
+        <term>   ::= <factor> <term_r>
+        <term_r> ::= "*" <factor> <term_r> | ε
+        <factor> ::= "(" <expr> ")" | "id"
+    "#;
+
+    #[test]
+    fn table_test() {
+        let g = grammar(EXPR);
+        let table = g.ll1_table().unwrap();
+        assert!(table.is_ll1());
+        assert_eq!(table.predict(1, Some("+")), Some(0));
+        assert_eq!(table.predict(1, Some(")")), Some(1));
+        assert_eq!(table.predict(1, None), Some(1));
+        assert_eq!(table.predict(1, Some("id")), None);
+        assert_eq!(table.predict(4, Some("(")), Some(0));
+    }
+
+    #[test]
+    fn conflicts_test() {
+        let g = grammar(r#"
+            <s> ::= <a> "x" | "y" <s>
+            <a> ::= "x" "z" | "x" | ε
+        "#);
+        let table = g.ll1_table().unwrap();
+        assert_eq!(table.conflicts(), &[
+            Ll1Conflict {
+                kind: ConflictKind::FirstFirst,
+                nonterminal: "a".to_string(),
+                lookahead: Some("x".to_string()),
+                expressions: (0, 1),
+                alternatives: (r#""x" "z""#.to_string(), r#""x""#.to_string()),
+            },
+            Ll1Conflict {
+                kind: ConflictKind::FirstFollow,
+                nonterminal: "a".to_string(),
+                lookahead: Some("x".to_string()),
+                expressions: (0, 2),
+                alternatives: (r#""x" "z""#.to_string(), "ε".to_string()),
+            },
+        ]);
+        assert_eq!(format!("{}", table.conflicts()[1]), r#"FIRST/FOLLOW conflict in <a> on "x": "x" "z" | ε"#);
+
+        // left recursion is never LL(1)
+        let left = grammar(r#"<l> ::= <l> "a" | "a""#);
+        assert_eq!(left.ll1_table().unwrap().conflicts()[0].kind, ConflictKind::FirstFirst);
+    }
+
+    #[test]
+    fn parse_test() {
+        let g = grammar(EXPR);
+        let t = tokens(&[ "id", "+", "(", "id", "*", "id", ")", "*", "id" ]);
+        let ll1: Vec<_> = parse_with(make_ctx(&g, &t, false, true), Engine::Ll1)
+            .map(|t| format!("{:#}", t.unwrap()))
+            .collect();
+        let earley: Vec<_> = parse_with(make_ctx(&g, &t, false, true), Engine::Earley)
+            .map(|t| format!("{:#}", t.unwrap()))
+            .collect();
+        assert_eq!(ll1.len(), 1);
+        assert_eq!(ll1, earley);
+
+        let deep: Vec<_> = std::iter::repeat_n("(", 20000).chain([ "id" ]).chain(std::iter::repeat_n(")", 20000)).collect();
+        let deep = tokens(&deep);
+        assert_eq!(parse_with(make_ctx(&g, &deep, false, true), Engine::Ll1).filter(|t| t.is_ok()).count(), 1);
+    }
+
+    #[test]
+    fn error_test() {
+        let g = grammar(EXPR);
+        let t = tokens(&[ "id", "+", "*", "id" ]);
+        match &parse_with(make_ctx(&g, &t, false, false), Engine::Ll1).collect::<Vec<_>>()[..] {
+            [ Err(Error::Syntax(err)) ] => {
+                assert_eq!(err.position, 2);
+                assert_eq!(err.expected, [ "(".to_string(), "id".to_string() ].into());
+                assert_eq!(err.production_stack, vec![ "expr", "expr_r", "term" ]);
+            },
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let t = tokens(&[ "id", ")" ]);
+        let result: Vec<_> = parse_with(make_ctx(&g, &t, false, false), Engine::Ll1).collect();
+        assert!(matches!(&result[..], [ Err(Error::Syntax(err)) ] if err.position == 1));
+    }
+
+    #[test]
+    fn fallback_test() {
+        let g = grammar(r#"
+            <sum> ::= <sum> "+" "n" | "n"
+        "#);
+        let t = tokens(&[ "n", "+", "n", "+", "n" ]);
+        let trees: Vec<_> = parse_with(make_ctx(&g, &t, false, true), Engine::Ll1).collect();
+        assert_eq!(trees.len(), 1);
+        assert!(trees[0].is_ok());
+
+        let options = ParseOptions { engine: Engine::Ll1, strict: true, ..Default::default() };
+        let conflicts = g.ll1_table().unwrap().conflicts().to_vec();
+        let trees: Vec<_> = parse_with_options(make_ctx(&g, &t, false, true), &options).collect();
+        assert_eq!(trees, vec![ Err(Error::Ll1Conflicts(conflicts)) ]);
+    }
+
+    #[test]
+    fn prebuilt_table_test() {
+        let g = grammar(EXPR);
+        let table = g.ll1_table().unwrap();
+        for t in [
+            tokens(&[ "id" ]),
+            tokens(&[ "id", "+", "(", "id", "*", "id", ")", "*", "id" ]),
+            tokens(&[ "(", "id", "+", "id", ")" ]),
+        ] {
+            let earley: Vec<_> = parse_with(make_ctx(&g, &t, false, true), Engine::Earley)
+                .map(|t| format!("{:#}", t.unwrap()))
+                .collect();
+            let ll1: Vec<_> = parse_ll1(make_ctx(&g, &t, false, true), &table)
+                .map(|t| format!("{:#}", t.unwrap()))
+                .collect();
+            assert_eq!(ll1, earley);
+        }
+    }
+}
@@ -0,0 +1,578 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, fmt::Display};
+
+use crate::grammar::*;
+use crate::tree::*;
+use crate::ctx::*;
+use crate::error::*;
+use crate::earley::{production_lookup, parse_earley_from};
+use crate::parse::{ParseTreeIter, splice_synthetic, parse_failed};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LrMode {
+    /// states with the same items are merged, as small as LR(0) automaton
+    #[default]
+    Lalr,
+    /// canonical LR(1), states are also split by lookaheads
+    Canonical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) enum Symbol {
+    Terminal(usize),
+    Nonterminal(usize),
+}
+
+/// sorted by preference: shift, then the rule written first in the grammar
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Action {
+    Shift(usize),
+    Reduce(usize),
+    Accept,
+}
+
+/// one expression of a production, rule 0 is `start' ::= <start>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Rule {
+    pub production: usize,
+    pub expression: usize,
+    pub lhs: usize,
+    pub rhs: Vec<Symbol>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LrConflictKind {
+    ShiftReduce,
+    ReduceReduce,
+}
+
+/// more than one action of a state on the same lookahead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LrConflict {
+    pub kind: LrConflictKind,
+    pub state: usize,
+    /// `None` for the end of the input
+    pub lookahead: Option<String>,
+    /// items shifting the lookahead, e.g. `<e> ::= <e> . "+" <e>`
+    pub shift: Vec<String>,
+    /// productions reduced on the lookahead, e.g. `<e> ::= <e> "+" <e>`
+    pub reduce: Vec<String>,
+}
+
+impl Display for LrConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} conflict in state {} on {}: ",
+            match self.kind {
+                LrConflictKind::ShiftReduce => "shift/reduce",
+                LrConflictKind::ReduceReduce => "reduce/reduce",
+            },
+            self.state,
+            self.lookahead.as_ref().map_or("$".to_string(), |t| format!("\"{}\"", t)),
+        ))?;
+        let shift = self.shift.iter().map(|s| format!("shift {}", s));
+        let reduce = self.reduce.iter().map(|r| format!("reduce {}", r));
+        f.write_str(&shift.chain(reduce).collect::<Vec<_>>().join(", "))
+    }
+}
+
+/// rule and dot position
+type Item = (usize, usize);
+type Kernel = BTreeMap<Item, BTreeSet<usize>>;
+
+/// nonterminal used but never defined, there is nothing to reduce to it
+const UNDEFINED: usize = usize::MAX;
+
+/// FIRST of a sequence of symbols and whether all of them are nullable
+fn first_of(symbols: &[Symbol], first: &[BTreeSet<usize>], nullable: &[bool]) -> (BTreeSet<usize>, bool) {
+    let mut result = BTreeSet::new();
+    for symbol in symbols {
+        match *symbol {
+            Symbol::Terminal(t) => {
+                result.insert(t);
+                return (result, false)
+            },
+            Symbol::Nonterminal(n) => {
+                if let Some(f) = first.get(n) {
+                    result.extend(f.iter().copied());
+                }
+                if !nullable.get(n).copied().unwrap_or(false) {
+                    return (result, false)
+                }
+            },
+        }
+    }
+    (result, true)
+}
+
+/// LR automaton with all actions of every state, conflicting ones included
+#[derive(Debug, Clone)]
+pub struct LrTable {
+    pub mode: LrMode,
+    terminals: Vec<String>,
+    terminal_ids: HashMap<String, usize>,
+    pub(crate) rules: Vec<Rule>,
+    /// actions on every lookahead, `terminals.len()` being the end of the input
+    actions: Vec<BTreeMap<usize, Vec<Action>>>,
+    /// state after a reduce by nonterminal id
+    gotos: Vec<HashMap<usize, usize>>,
+    /// rules of the kernel items, to tell what a state is in the middle of
+    kernels: Vec<Vec<usize>>,
+    conflicts: Vec<LrConflict>,
+}
+
+impl LrTable {
+    pub(crate) fn new(grammar: &Grammar, start: usize, mode: LrMode) -> Self {
+        let lookup = production_lookup(grammar);
+        let accept = grammar.productions.len();
+        let mut terminals = vec![];
+        let mut terminal_ids = HashMap::new();
+        let mut symbol = |term: &Term| match term {
+            Term::Terminal(t) => Symbol::Terminal(*terminal_ids.entry(t.clone()).or_insert_with(|| {
+                terminals.push(t.clone());
+                terminals.len() - 1
+            })),
+            Term::Nonterminal(n) => Symbol::Nonterminal(lookup.get(n.as_str()).copied().unwrap_or(UNDEFINED)),
+        };
+
+        let start = lookup[grammar.productions[start].lhs.as_str()];
+        let mut rules = vec![ Rule { production: start, expression: 0, lhs: accept, rhs: vec![ Symbol::Nonterminal(start) ] } ];
+        let mut rules_of = vec![ vec![]; accept + 1 ];
+        for (i, p) in grammar.productions.iter().enumerate() {
+            if lookup[p.lhs.as_str()] != i {
+                continue;
+            }
+            for (e, expression) in p.rhs.iter().enumerate() {
+                rules_of[i].push(rules.len());
+                rules.push(Rule { production: i, expression: e, lhs: i, rhs: expression.terms.iter().map(&mut symbol).collect() });
+            }
+        }
+        let end = terminals.len();
+
+        let mut first = vec![ BTreeSet::new(); accept + 1 ];
+        let mut nullable = vec![ false; accept + 1 ];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for rule in &rules {
+                let (f, n) = first_of(&rule.rhs, &first, &nullable);
+                let before = first[rule.lhs].len();
+                first[rule.lhs].extend(f);
+                changed |= first[rule.lhs].len() != before || (n && !nullable[rule.lhs]);
+                nullable[rule.lhs] |= n;
+            }
+        }
+
+        let closure = |kernel: &Kernel| {
+            let mut items = kernel.clone();
+            let mut work: Vec<Item> = items.keys().copied().collect();
+            while let Some((r, dot)) = work.pop() {
+                let Some(&Symbol::Nonterminal(n)) = rules[r].rhs.get(dot) else {
+                    continue
+                };
+                let (mut lookaheads, rest_nullable) = first_of(&rules[r].rhs[dot + 1..], &first, &nullable);
+                if rest_nullable {
+                    lookaheads.extend(items[&(r, dot)].iter().copied());
+                }
+                for &target in rules_of.get(n).into_iter().flatten() {
+                    let is_new = !items.contains_key(&(target, 0));
+                    let entry = items.entry((target, 0)).or_default();
+                    let before = entry.len();
+                    entry.extend(lookaheads.iter().copied());
+                    if is_new || entry.len() != before {
+                        work.push((target, 0));
+                    }
+                }
+            }
+            items
+        };
+        let key = |kernel: &Kernel| -> Vec<(Item, Vec<usize>)> {
+            kernel.iter().map(|(item, lookaheads)| match mode {
+                LrMode::Lalr => (*item, vec![]),
+                LrMode::Canonical => (*item, lookaheads.iter().copied().collect()),
+            }).collect()
+        };
+
+        let mut kernels: Vec<Kernel> = vec![ [ ((0, 0), [ end ].into()) ].into() ];
+        let mut index = HashMap::from([ (key(&kernels[0]), 0) ]);
+        let mut transitions: Vec<BTreeMap<Symbol, usize>> = vec![ BTreeMap::new() ];
+        let mut work = vec![ 0 ];
+        while let Some(state) = work.pop() {
+            let mut next: BTreeMap<Symbol, Kernel> = BTreeMap::new();
+            for ((r, dot), lookaheads) in closure(&kernels[state]) {
+                match rules[r].rhs.get(dot) {
+                    None | Some(Symbol::Nonterminal(UNDEFINED)) => (),
+                    Some(&symbol) => next.entry(symbol).or_default().entry((r, dot + 1)).or_default().extend(lookaheads),
+                }
+            }
+            for (symbol, kernel) in next {
+                let target = match index.get(&key(&kernel)) {
+                    Some(&target) => {
+                        let mut grown = false;
+                        for (item, lookaheads) in kernel {
+                            let entry = kernels[target].get_mut(&item).unwrap();
+                            let before = entry.len();
+                            entry.extend(lookaheads);
+                            grown |= entry.len() != before;
+                        }
+                        if grown && !work.contains(&target) {
+                            work.push(target);
+                        }
+                        target
+                    },
+                    None => {
+                        index.insert(key(&kernel), kernels.len());
+                        work.push(kernels.len());
+                        kernels.push(kernel);
+                        transitions.push(BTreeMap::new());
+                        kernels.len() - 1
+                    },
+                };
+                transitions[state].insert(symbol, target);
+            }
+        }
+
+        let item_string = |r: usize, dot: Option<usize>| {
+            let rule = &rules[r];
+            if rule.lhs == accept {
+                return "accept".to_string()
+            }
+            let p = &grammar.productions[rule.production];
+            let expression = &p.rhs[rule.expression];
+            let mut result = format!("<{}> ::=", p.lhs);
+            for (i, term) in expression.terms.iter().enumerate() {
+                if dot == Some(i) {
+                    result.push_str(" .");
+                }
+                result.push_str(&format!(" {}", term));
+            }
+            if expression.terms.is_empty() {
+                result.push_str(" ε");
+            }
+            result
+        };
+
+        let mut actions = vec![];
+        let mut gotos = vec![];
+        let mut conflicts = vec![];
+        for (state, kernel) in kernels.iter().enumerate() {
+            let items = closure(kernel);
+            let mut cells: BTreeMap<usize, Vec<Action>> = BTreeMap::new();
+            let mut goto = HashMap::new();
+            for (&symbol, &target) in &transitions[state] {
+                match symbol {
+                    Symbol::Terminal(t) => cells.entry(t).or_default().push(Action::Shift(target)),
+                    Symbol::Nonterminal(n) => { goto.insert(n, target); },
+                }
+            }
+            for (&(r, dot), lookaheads) in &items {
+                if dot == rules[r].rhs.len() {
+                    for &l in lookaheads {
+                        cells.entry(l).or_default().push(if r == 0 { Action::Accept } else { Action::Reduce(r) });
+                    }
+                }
+            }
+            for (&lookahead, cell) in cells.iter_mut() {
+                cell.sort();
+                if cell.len() < 2 {
+                    continue;
+                }
+                let shifting = matches!(cell[0], Action::Shift(_));
+                conflicts.push(LrConflict {
+                    kind: if shifting { LrConflictKind::ShiftReduce } else { LrConflictKind::ReduceReduce },
+                    state,
+                    lookahead: terminals.get(lookahead).cloned(),
+                    shift: if shifting {
+                        items
+                            .keys()
+                            .filter(|(r, dot)| rules[*r].rhs.get(*dot) == Some(&Symbol::Terminal(lookahead)))
+                            .map(|&(r, dot)| item_string(r, Some(dot)))
+                            .collect()
+                    } else {
+                        vec![]
+                    },
+                    reduce: cell
+                        .iter()
+                        .filter_map(|a| match a {
+                            Action::Shift(_) => None,
+                            Action::Reduce(r) => Some(item_string(*r, None)),
+                            Action::Accept => Some(item_string(0, None)),
+                        })
+                        .collect(),
+                });
+            }
+            actions.push(cells);
+            gotos.push(goto);
+        }
+
+        Self {
+            mode,
+            terminals,
+            terminal_ids,
+            rules,
+            actions,
+            gotos,
+            kernels: kernels.iter().map(|k| k.keys().map(|(r, _)| *r).collect()).collect(),
+            conflicts,
+        }
+    }
+
+    /// every cell with more than one action. the deterministic driver prefers shift, then the production written first
+    pub fn conflicts(&self) -> &[LrConflict] {
+        &self.conflicts
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    pub fn states(&self) -> usize {
+        self.actions.len()
+    }
+
+    /// id of the token at `position`, the end of the input past the range. `None` for tokens the grammar never mentions
+    pub(crate) fn lookahead<T: Token>(&self, ctx: &Ctx<T>, position: usize) -> Option<usize> {
+        if position < ctx.end {
+            self.terminal_ids.get(ctx.tokens[position].name()).copied()
+        } else {
+            Some(self.terminals.len())
+        }
+    }
+
+    pub(crate) fn actions(&self, state: usize, lookahead: usize) -> &[Action] {
+        self.actions[state].get(&lookahead).map_or(&[], |a| a.as_slice())
+    }
+
+    pub(crate) fn goto(&self, state: usize, nonterminal: usize) -> usize {
+        self.gotos[state][&nonterminal]
+    }
+
+    /// `stack` tells what was being parsed, `frontier` what could follow
+    pub(crate) fn syntax_error<T: Token>(&self, ctx: &Ctx<T>, position: usize, stack: &[usize], frontier: &[usize]) -> Error {
+        let mut production_stack: Vec<String> = vec![];
+        for rule in stack.iter().flat_map(|&s| &self.kernels[s]) {
+            let rule = &self.rules[*rule];
+            if rule.lhs != self.rules[0].lhs {
+                let lhs = &ctx.grammar.productions[rule.production].lhs;
+                if production_stack.last() != Some(lhs) {
+                    production_stack.push(lhs.clone());
+                }
+            }
+        }
+        Error::Syntax(SyntaxError {
+            position,
+            expected: frontier
+                .iter()
+                .flat_map(|&s| self.actions[s].keys())
+                .filter_map(|&t| self.terminals.get(t).cloned())
+                .collect(),
+            production_stack,
+            tokens: ctx.tokens.iter().map(|t| t.name().to_string()).collect(),
+        })
+    }
+}
+
+impl Grammar {
+    /// LR automaton with the first production as the start one
+    pub fn lr_table(&self, mode: LrMode) -> Option<LrTable> {
+        (!self.productions.is_empty()).then(|| LrTable::new(self, 0, mode))
+    }
+
+    /// same as `lr_table` for the input parsed as `start`
+    pub fn lr_table_from(&self, start: &str, mode: LrMode) -> Option<LrTable> {
+        let start = production_lookup(self).get(start).copied()?;
+        Some(LrTable::new(self, start, mode))
+    }
+}
+
+/// shift-reduce parse taking the preferred action of every cell, one tree or a syntax error
+fn parse_table<'tg, T: Token>(ctx: &Ctx<'tg, 'tg, T>, table: &LrTable) -> Result<ParseTree<'tg, 'tg, T>, Error> {
+    let mut states = vec![ 0 ];
+    let mut nodes = vec![];
+    let mut position = ctx.begin;
+    loop {
+        let state = *states.last().unwrap();
+        let action = table.lookahead(ctx, position).and_then(|l| table.actions(state, l).first());
+        match action {
+            None => return Err(table.syntax_error(ctx, position, &states, &states[states.len() - 1..])),
+            Some(Action::Shift(next)) => {
+                nodes.push(ParseTreeNode::Terminal(&ctx.tokens[position]));
+                states.push(*next);
+                position += 1;
+            },
+            Some(Action::Reduce(r)) => {
+                let rule = &table.rules[*r];
+                let rhs = nodes.split_off(nodes.len() - rule.rhs.len());
+                let range = position - rhs.iter().map(|node| node.token_count()).sum::<usize>()..position;
+                states.truncate(states.len() - rule.rhs.len());
+                nodes.push(ParseTreeNode::Nonterminal(ParseTree { lhs: &ctx.grammar.productions[rule.production].lhs, rhs: rhs.into(), range }));
+                states.push(table.goto(*states.last().unwrap(), rule.lhs));
+            },
+            Some(Action::Accept) => match nodes.pop() {
+                Some(ParseTreeNode::Nonterminal(tree)) => return Ok(tree),
+                _ => unreachable!("accept without a tree"),
+            },
+        }
+    }
+}
+
+/// linear time parse with an automaton of `ctx.grammar`, which can be built once for any number of inputs.
+/// an automaton with conflicts yields `Error::LrConflicts`
+pub fn parse_lr<'tg, T: Token + 'tg>(ctx: Ctx<'tg, 'tg, T>, table: &LrTable) -> ParseTreeIter<'tg, T> {
+    if !table.is_deterministic() {
+        return parse_failed(ctx.ignore_errors, Error::LrConflicts(table.conflicts().to_vec()))
+    }
+    match parse_table(&ctx, table) {
+        Ok(tree) => splice_synthetic(ctx.grammar, Box::new(std::iter::once(Ok(tree)))),
+        Err(err) => parse_failed(ctx.ignore_errors, err),
+    }
+}
+
+/// linear time parse when the automaton has no conflicts. otherwise the Earley engine, unless `strict`
+pub(crate) fn parse_lr_from<'tg, T: Token + 'tg>(ctx: Ctx<'tg, 'tg, T>, start: usize, mode: LrMode, strict: bool) -> ParseTreeIter<'tg, T> {
+    let table = LrTable::new(ctx.grammar, start, mode);
+    if !table.is_deterministic() && !strict {
+        return parse_earley_from(ctx, start)
+    }
+    parse_lr(ctx, &table)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::{ExtGrammar, Grammar};
+    use crate::parse::{make_ctx, parse_with, parse_with_options, Engine, ParseOptions};
+    use crate::error::Error;
+
+    use super::{parse_lr, LrConflict, LrConflictKind, LrMode};
+
+    fn grammar(text: &str) -> Grammar {
+        let g: ExtGrammar = text.try_into().unwrap();
+        g.flatten()
+    }
+
+    fn tokens(t: &[&str]) -> Vec<String> {
+        t.iter().map(|t| t.to_string()).collect()
+    }
+
+    static EXPR: &str = r#"
+        <expr>   ::= <expr> "+" <term> | <term>
+        <term>   ::= <term> "*" <factor> | <factor>
+        <factor> ::= "(" <expr> ")" | "id" | "-" <factor>?
+    "#;
+
+    #[test]
+    fn parse_test() {
+        let g = grammar(EXPR);
+        assert!(g.lr_table(LrMode::Lalr).unwrap().is_deterministic());
+        let t = tokens(&[ "id", "+", "(", "id", "*", "-", ")", "*", "id" ]);
+        let earley: Vec<_> = parse_with(make_ctx(&g, &t, false, true), Engine::Earley)
+            .map(|t| format!("{:#}", t.unwrap()))
+            .collect();
+        for engine in [ Engine::Lalr, Engine::Lr1 ] {
+            let lr: Vec<_> = parse_with(make_ctx(&g, &t, false, true), engine)
+                .map(|t| format!("{:#}", t.unwrap()))
+                .collect();
+            assert_eq!(lr, earley);
+        }
+
+        let long: Vec<_> = std::iter::repeat_n([ "id", "+" ], 20000).flatten().chain([ "id" ]).collect();
+        let long = tokens(&long);
+        assert_eq!(parse_with(make_ctx(&g, &long, false, true), Engine::Lalr).filter(|t| t.is_ok()).count(), 1);
+    }
+
+    #[test]
+    fn conflicts_test() {
+        let g = grammar(r#"
+            <e> ::= <e> "+" <e> | "n"
+        "#);
+        let table = g.lr_table(LrMode::Lalr).unwrap();
+        assert_eq!(table.conflicts(), &[
+            LrConflict {
+                kind: LrConflictKind::ShiftReduce,
+                state: 4,
+                lookahead: Some("+".to_string()),
+                shift: vec![ r#"<e> ::= <e> . "+" <e>"#.to_string() ],
+                reduce: vec![ r#"<e> ::= <e> "+" <e>"#.to_string() ],
+            },
+        ]);
+        assert_eq!(
+            format!("{}", table.conflicts()[0]),
+            r#"shift/reduce conflict in state 4 on "+": shift <e> ::= <e> . "+" <e>, reduce <e> ::= <e> "+" <e>"#
+        );
+
+        let g = grammar(r#"
+            <s> ::= <a> | <b>
+            <a> ::= "x"
+            <b> ::= "x"
+        "#);
+        let conflicts = g.lr_table(LrMode::Lalr).unwrap().conflicts().to_vec();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].kind, LrConflictKind::ReduceReduce);
+        assert_eq!(conflicts[0].lookahead, None);
+        assert_eq!(conflicts[0].reduce, vec![ r#"<a> ::= "x""#, r#"<b> ::= "x""# ]);
+    }
+
+    #[test]
+    fn canonical_test() {
+        // LR(1) but not LALR(1): merging the states after "c" mixes the lookaheads of <a> and <b>
+        let g = grammar(r#"
+            <s> ::= "a" <a> "d" | "b" <b> "d" | "a" <b> "e" | "b" <a> "e"
+            <a> ::= "c"
+            <b> ::= "c"
+        "#);
+        let lalr = g.lr_table(LrMode::Lalr).unwrap();
+        let canonical = g.lr_table(LrMode::Canonical).unwrap();
+        assert_eq!(lalr.conflicts().iter().map(|c| c.kind).collect::<Vec<_>>(), vec![ LrConflictKind::ReduceReduce; 2 ]);
+        assert!(canonical.is_deterministic());
+        assert_eq!(canonical.states(), lalr.states() + 1);
+
+        let t = tokens(&[ "b", "c", "e" ]);
+        let trees: Vec<_> = parse_with(make_ctx(&g, &t, false, true), Engine::Lr1).map(|t| format!("{:#}", t.unwrap())).collect();
+        assert_eq!(trees.len(), 1);
+        assert_eq!(trees, vec![ "s\n`b\n`a\n``c\n`e\n".to_string() ]);
+
+        // the LALR(1) automaton falls back to the Earley engine, unless strict
+        assert_eq!(parse_with(make_ctx(&g, &t, false, true), Engine::Lalr).map(|t| format!("{:#}", t.unwrap())).collect::<Vec<_>>(), trees);
+        let options = ParseOptions { engine: Engine::Lalr, strict: true, ..Default::default() };
+        let result: Vec<_> = parse_with_options(make_ctx(&g, &t, false, true), &options).collect();
+        assert_eq!(result, vec![ Err(Error::LrConflicts(lalr.conflicts().to_vec())) ]);
+    }
+
+    #[test]
+    fn prebuilt_table_test() {
+        let g = grammar(EXPR);
+        let table = g.lr_table(LrMode::Canonical).unwrap();
+        for t in [
+            tokens(&[ "id" ]),
+            tokens(&[ "id", "+", "(", "id", "*", "-", ")", "*", "id" ]),
+            tokens(&[ "-", "-", "id" ]),
+        ] {
+            let earley: Vec<_> = parse_with(make_ctx(&g, &t, false, true), Engine::Earley)
+                .map(|t| format!("{:#}", t.unwrap()))
+                .collect();
+            let lr: Vec<_> = parse_lr(make_ctx(&g, &t, false, true), &table)
+                .map(|t| format!("{:#}", t.unwrap()))
+                .collect();
+            assert_eq!(lr, earley);
+        }
+    }
+
+    #[test]
+    fn error_test() {
+        let g = grammar(EXPR);
+        let t = tokens(&[ "id", "+", "*", "id" ]);
+        match &parse_with(make_ctx(&g, &t, false, false), Engine::Lalr).collect::<Vec<_>>()[..] {
+            [ Err(Error::Syntax(err)) ] => {
+                assert_eq!(err.position, 2);
+                assert_eq!(err.expected, [ "(", "-", "id" ].map(String::from).into());
+                assert_eq!(err.production_stack, vec![ "expr" ]);
+            },
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let t = tokens(&[ "id", "?" ]);
+        let result: Vec<_> = parse_with(make_ctx(&g, &t, false, false), Engine::Lalr).collect();
+        assert!(matches!(&result[..], [ Err(Error::Syntax(err)) ] if err.position == 1 && err.expected.contains("+")));
+    }
+}
@@ -1,117 +1,378 @@
-use std::{io::{stdin, stdout, Write}, process::exit, thread, time};
+use std::{io::{stdin, Read}, process::exit};
 
 use colored::Colorize;
-use parser300b::{Grammar, parse, make_ctx, ExtGrammar};
+use parser300b::{parse_with_options, parse_ll1, parse_lr, make_ctx, Ctx, ExtGrammar, Engine, ParseTree, Severity, ParseOptions, Optionals, LrMode, Lexer, TokenRule, Token, Error, ScannerlessOptions, Ll1Table, LrTable};
+use serde::Serialize;
 
 
-fn main() {
-    let grammar: ExtGrammar = r#"
-        <block> ::= <stmt> ";" | <stmt> ";" <block>
-        <namespace> ::= "namespace" "{" <block>? "}"
-        <subs> ::= <lhs> "=" <postfix_rhs>
-        <lhs> ::= <id> | "exp" | "_"
-        <postfix_rhs> ::= <rhs> | <postfix_rhs> "." <id>
-        <rhs> ::= <namespace> | <literal> | <subs> | <callable_or_type_rhs> | <call>
-        <stmt> ::= <subs> | "stmt"
-        <class_decl> ::= "class" "(" <decl_arg_list>? ")" "{" <block>? "}"
-        <arg_list> ::= <arg> | <arg_list> "," <arg>
-        <arg> ::= <postfix_rhs>
-        <decl_arg_list> ::= <decl_arg> | <decl_arg_list> "," <decl_arg>
-        <decl_arg> ::= <id> " " <callable_or_type_rhs>
-        <primitive_type> ::= "int" | "float"
-        <func_decl> ::= "(" <decl_arg_list>? ")" "{" <block>? "}"
-        <callable_or_type_rhs> ::= <id> | <func_decl> | <primitive_type> | <class_decl>
-        <call> ::= <callable_or_type_rhs> "(" <arg_list>? ")"
-        <literal> ::= "STR" | "NUM"
-        <id> ::= "ID"
-    "#.try_into().unwrap();
-
-    let grammar = grammar.flatten();
-
-    println!("grammar: {:#?}", grammar);
-
-    let tokens = vec![
-        "stmt",
-        ";",
-        "exp",
-        "=",
-        "ID",
-        "(",
-        "ID",
-        "(",
-        "int",
-        "(",
-        ")",
-        ".",
-        "ID",
-        ")",
-        ")",
-        ";",
-    ];
-
-    let tokens: Vec<_> = tokens
-        .into_iter()
-        .map(|x| String::from(x))
-        .collect();
+const USAGE: &str = r#"bnf parser
 
-    let ctx = make_ctx(&grammar, &tokens, true, true);
-    let mut a = parse(ctx);
+Usage:
+    parser300b parse --grammar <file.bnf> --tokens <file|-> [options]
+    parser300b sets --grammar <file.bnf> [--start <name>] [--format <fmt>]
+    parser300b help
 
-    println!("q - quit");
-    println!("p - switch print result (default = true)");
-    println!("go - go until success");
-    println!("start");
+Options:
+    -g, --grammar <file>        grammar in bnf
+    -t, --tokens <file|->       tokens to parse, `-` for stdin
+    -i, --input-format <fmt>    whitespace (default) | lines | json | source | chars
+        --token <name>=<regex>  token rule for `source` input, terminals of the grammar are literal rules
+        --skip <regex>          text dropped from `source` input (default: whitespace)
+        --auto-whitespace       skip whitespace before terminals of `chars` input
+        --lexical <name>        production of `chars` input without whitespace inside, e.g. identifiers
+    -s, --start <name>          start nonterminal (default: first production)
+    -f, --format <fmt>          text (default) | json | yaml, trees or FIRST/FOLLOW/nullable sets
+    -n, --max-trees <n>         stop after n trees (default: 1, 0 for all)
+    -e, --engine <engine>       backtracking (default) | earley | ll1 | lalr | lr1 | glr | cyk
+    -d, --max-depth <n>         derivation depth budget of the backtracking engine (default: unlimited)
+        --strict                fail on table conflicts of ll1, lalr and lr1 instead of falling back to earley
+    -o, --optionals <mode>      expand (default) | nullable | explicit, see `Optionals`
+    -l, --logs                  print engine logs
 
-    let mut print_res: bool = true;
-    let mut go_until_success: bool = false;
+Exit codes:
+    0 - at least one tree found
+    1 - input does not match the grammar
+    2 - bad arguments, unreadable files or grammar errors"#;
 
-    loop {
-        let mut line: String = String::new();
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InputFormat {
+    Whitespace,
+    Lines,
+    Json,
+    /// source text cut into tokens by a `Lexer`
+    Source,
+    /// source text parsed char by char, see `Scannerless`
+    Chars,
+}
 
-        if !go_until_success {
-            print!(">> ");
-            let _ = stdout().flush();
-    
-            stdin().read_line(&mut line).unwrap();
-            line.pop();
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+    Yaml,
+}
 
-            if line == "q" {
-                exit(0);
-            }
-            if line == "p" {
-                print_res = !print_res;
-                println!("print_res = {}", print_res);
-                thread::sleep(time::Duration::from_millis(3000));
-            }
-            if line == "go" {
-                go_until_success = true
-            }
+#[derive(Debug)]
+struct ParseArgs {
+    grammar: String,
+    tokens: String,
+    input_format: InputFormat,
+    start: Option<String>,
+    format: OutputFormat,
+    max_trees: usize,
+    engine: Engine,
+    max_depth: Option<usize>,
+    strict: bool,
+    optionals: Optionals,
+    token_rules: Vec<TokenRule>,
+    scannerless: ScannerlessOptions,
+    logs: bool,
+}
+
+fn fail(code: i32, message: &str) -> ! {
+    eprintln!("{}", message.red());
+    exit(code)
+}
+
+fn parse_args(args: &[String], needs_tokens: bool) -> Result<ParseArgs, String> {
+    let mut grammar = None;
+    let mut tokens = None;
+    let mut result = ParseArgs {
+        grammar: String::new(),
+        tokens: String::new(),
+        input_format: InputFormat::Whitespace,
+        start: None,
+        format: OutputFormat::Text,
+        max_trees: 1,
+        engine: Engine::Backtracking,
+        max_depth: None,
+        strict: false,
+        optionals: Optionals::Expand,
+        token_rules: vec![],
+        scannerless: Default::default(),
+        logs: false,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args
+            .next()
+            .cloned()
+            .ok_or_else(|| format!("missing value for '{}'", arg));
+
+        match arg.as_str() {
+            "-g" | "--grammar" => grammar = Some(value()?),
+            "-t" | "--tokens" => tokens = Some(value()?),
+            "-i" | "--input-format" => result.input_format = match value()?.as_str() {
+                "whitespace" => InputFormat::Whitespace,
+                "lines" => InputFormat::Lines,
+                "json" => InputFormat::Json,
+                "source" => InputFormat::Source,
+                "chars" => InputFormat::Chars,
+                other => return Err(format!("unknown input format '{}'", other)),
+            },
+            "-s" | "--start" => result.start = Some(value()?),
+            "-f" | "--format" => result.format = match value()?.as_str() {
+                "text" => OutputFormat::Text,
+                "json" => OutputFormat::Json,
+                "yaml" => OutputFormat::Yaml,
+                other => return Err(format!("unknown format '{}'", other)),
+            },
+            "-n" | "--max-trees" => {
+                let v = value()?;
+                result.max_trees = v.parse().map_err(|_| format!("'{}' is not a number", v))?
+            },
+            "-e" | "--engine" => result.engine = match value()?.as_str() {
+                "backtracking" => Engine::Backtracking,
+                "earley" => Engine::Earley,
+                "ll1" => Engine::Ll1,
+                "lalr" => Engine::Lalr,
+                "lr1" => Engine::Lr1,
+                "glr" => Engine::Glr,
+                "cyk" => Engine::Cyk,
+                other => return Err(format!("unknown engine '{}'", other)),
+            },
+            "-d" | "--max-depth" => {
+                let v = value()?;
+                result.max_depth = Some(v.parse().map_err(|_| format!("'{}' is not a number", v))?)
+            },
+            "--strict" => result.strict = true,
+            "-o" | "--optionals" => result.optionals = match value()?.as_str() {
+                "expand" => Optionals::Expand,
+                "nullable" => Optionals::Nullable,
+                "explicit" => Optionals::Explicit,
+                other => return Err(format!("unknown optionals mode '{}'", other)),
+            },
+            "--token" => {
+                let v = value()?;
+                let (name, pattern) = v.split_once('=').ok_or_else(|| format!("'{}' is not <name>=<regex>", v))?;
+                result.token_rules.push(TokenRule::regex(name, pattern))
+            },
+            "--skip" => result.token_rules.push(TokenRule::regex("skip", &value()?).skipped()),
+            "--auto-whitespace" => result.scannerless.auto_whitespace = true,
+            "--lexical" => result.scannerless.lexical.push(value()?),
+            "-l" | "--logs" => result.logs = true,
+            other => return Err(format!("unknown argument '{}'", other)),
         }
+    }
 
-        println!("going next...");
-        if print_res {
-            match a.next() {
-                Some(tree) => match tree {
-                    Ok(tree) => {
-                        println!("{}", format!("tree: {:#}", tree).green());
-                        go_until_success = false
-                    },
-                    Err(err) => println!("{}", format!("err: {}", err).red()),
-                },
-                None => exit(0),
-            }
-        } else {
-            match a.next() {
-                Some(_) => {},
-                None => exit(0),
+    result.grammar = grammar.ok_or("--grammar is required")?;
+    if needs_tokens {
+        result.tokens = tokens.ok_or("--tokens is required")?;
+    }
+    Ok(result)
+}
+
+fn read_input(path: &str) -> Result<String, String> {
+    if path == "-" {
+        let mut result = String::new();
+        stdin()
+            .read_to_string(&mut result)
+            .map_err(|err| format!("can not read stdin: {}", err))?;
+        Ok(result)
+    } else {
+        std::fs::read_to_string(path).map_err(|err| format!("can not read '{}': {}", path, err))
+    }
+}
+
+fn split_tokens(input: &str, format: InputFormat) -> Result<Vec<String>, String> {
+    match format {
+        InputFormat::Whitespace => Ok(input.split_whitespace().map(String::from).collect()),
+        InputFormat::Lines => Ok(input
+            .lines()
+            .map(|l| l.trim_end_matches('\r'))
+            .filter(|l| !l.is_empty())
+            .map(String::from)
+            .collect()),
+        InputFormat::Json => serde_json::from_str(input).map_err(|err| format!("tokens are not a json string array: {}", err)),
+        InputFormat::Source | InputFormat::Chars => unreachable!("source input is cut by the lexer"),
+    }
+}
+
+fn print_trees<T: Token + Serialize>(trees: &[ParseTree<T>], format: OutputFormat) -> Result<(), String> {
+    match format {
+        OutputFormat::Text => {
+            for tree in trees {
+                print!("{:#}", tree);
             }
+        },
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(trees).map_err(|err| err.to_string())?),
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(trees).map_err(|err| err.to_string())?),
+    }
+    Ok(())
+}
+
+fn run_parse(args: ParseArgs) -> i32 {
+    let grammar_text = read_input(&args.grammar).unwrap_or_else(|err| fail(2, &err));
+    let grammar: ExtGrammar = grammar_text
+        .as_str()
+        .try_into()
+        .unwrap_or_else(|err| fail(2, &format!("{}:{}", args.grammar, err)));
+    let grammar = grammar.flatten_with(args.optionals);
+    let scannerless = (args.input_format == InputFormat::Chars).then(|| grammar.scannerless(&args.scannerless));
+    let grammar = scannerless.as_ref().map_or(&grammar, |s| &s.grammar);
+
+    if let Some(start) = &args.start {
+        if !grammar.productions.iter().any(|p| &p.lhs == start) {
+            fail(2, &format!("start nonterminal '{}' not found", start))
         }
-        println!("OK");
+    }
 
-        //match a {
-        //    Ok(tree) => println!("{:#}", tree),
-        //    Err(err) => println!("{}", err),
-        //}
+    let diagnostics = match &args.start {
+        Some(start) => grammar.validate_from(start),
+        None => grammar.validate(),
+    };
+    for diagnostic in &diagnostics {
+        match diagnostic.severity() {
+            Severity::Error => eprintln!("{}: {}", args.grammar, format!("{}", diagnostic).red()),
+            Severity::Warning => eprintln!("{}: {}", args.grammar, format!("{}", diagnostic).yellow()),
+        }
+    }
+    if diagnostics.iter().any(|d| d.severity() == Severity::Error) {
+        return 2
     }
 
-}
\ No newline at end of file
+    // the table of a deterministic engine is built once, for the conflict report and for the parse
+    let start = || args.start.clone()
+        .or_else(|| grammar.productions.first().map(|p| p.lhs.clone()))
+        .unwrap_or_else(|| fail(2, "grammar is empty"));
+    let ll1 = match args.engine {
+        Engine::Ll1 => grammar.ll1_table_from(&start()),
+        _ => None,
+    };
+    let lr = match args.engine {
+        Engine::Lalr => grammar.lr_table_from(&start(), LrMode::Lalr),
+        Engine::Lr1 => grammar.lr_table_from(&start(), LrMode::Canonical),
+        _ => None,
+    };
+    let conflicts: Vec<String> = ll1.iter().flat_map(|t| t.conflicts()).map(|c| c.to_string())
+        .chain(lr.iter().flat_map(|t| t.conflicts()).map(|c| c.to_string()))
+        .collect();
+    if args.strict && !conflicts.is_empty() {
+        for conflict in conflicts {
+            eprintln!("{}: {}", args.grammar, conflict.red());
+        }
+        return 2
+    }
+    for conflict in &conflicts {
+        eprintln!("{}: {}", args.grammar, format!("{}, falling back to earley", conflict).yellow());
+    }
+
+    let input = read_input(&args.tokens).unwrap_or_else(|err| fail(2, &err));
+    let engine = if conflicts.is_empty() { args.engine } else { Engine::Earley };
+    let options = ParseOptions { start: args.start.clone(), engine, max_depth: args.max_depth, strict: args.strict };
+    let tables = Tables { ll1: ll1.as_ref(), lr: lr.as_ref() };
+    if args.input_format == InputFormat::Source {
+        let mut rules: Vec<TokenRule> = args.token_rules.clone();
+        if !rules.iter().any(|r| r.skip) {
+            rules.push(TokenRule::regex("whitespace", r"\s+").skipped());
+        }
+        let lexer = Lexer::from_grammar(grammar, rules).unwrap_or_else(|err| fail(2, &err.to_string()));
+        let tokens = lexer.tokenize(&input).unwrap_or_else(|err| fail(1, &format!("{}:{}", args.tokens, err)));
+        return report(tables.parse(make_ctx(grammar, &tokens, args.logs, false), &options), &tokens, &args)
+    }
+    if let Some(scannerless) = &scannerless {
+        let tokens = scannerless.tokens(&input);
+        let trees = scannerless.parse_by(make_ctx(grammar, &tokens, args.logs, false), |ctx| tables.parse(ctx, &options));
+        return report(trees, &tokens, &args)
+    }
+    let tokens = split_tokens(&input, args.input_format).unwrap_or_else(|err| fail(2, &err));
+    report(tables.parse(make_ctx(grammar, &tokens, args.logs, false), &options), &tokens, &args)
+}
+
+/// tables of the deterministic engines, built before the parse
+struct Tables<'a> {
+    ll1: Option<&'a Ll1Table>,
+    lr: Option<&'a LrTable>,
+}
+
+impl Tables<'_> {
+    /// parses with the prebuilt table if there is one, as `options` tell otherwise
+    fn parse<'tg, T: Token + 'tg>(
+        &self,
+        ctx: Ctx<'tg, 'tg, T>,
+        options: &ParseOptions
+    ) -> Box<dyn Iterator<Item = Result<ParseTree<'tg, 'tg, T>, Error>> + 'tg> {
+        match (options.engine, self.ll1, self.lr) {
+            (Engine::Ll1, Some(table), _) => parse_ll1(ctx, table),
+            (Engine::Lalr | Engine::Lr1, _, Some(table)) => parse_lr(ctx, table),
+            _ => parse_with_options(ctx, options),
+        }
+    }
+}
+
+fn report<'tg, T: Token + Serialize + 'tg>(
+    trees_found: impl Iterator<Item = Result<ParseTree<'tg, 'tg, T>, Error>>,
+    tokens: &[T],
+    args: &ParseArgs
+) -> i32 {
+    let mut trees = vec![];
+    let mut error = None;
+    for tree in trees_found {
+        match tree {
+            Ok(tree) => {
+                trees.push(tree);
+                if trees.len() == args.max_trees {
+                    break;
+                }
+            },
+            Err(err) => error = Some(err),
+        }
+    }
+
+    if trees.is_empty() {
+        match error {
+            Some(err) => {
+                let span = match &err {
+                    Error::Syntax(syntax) => tokens.get(syntax.position).and_then(Token::span),
+                    _ => None,
+                };
+                match span {
+                    Some(span) => eprintln!("{}", format!("{}:{}: {}", args.tokens, span.location(), err).red()),
+                    None => eprintln!("{}", format!("{}", err).red()),
+                }
+            },
+            None => eprintln!("{}", "no trees".red()),
+        }
+        1
+    } else {
+        print_trees(&trees, args.format).unwrap_or_else(|err| fail(2, &err));
+        0
+    }
+}
+
+fn run_sets(args: ParseArgs) -> Result<(), String> {
+    let grammar_text = read_input(&args.grammar)?;
+    let grammar: ExtGrammar = grammar_text
+        .as_str()
+        .try_into()
+        .map_err(|err| format!("{}:{}", args.grammar, err))?;
+    let sets = match &args.start {
+        Some(start) => grammar.sets_from(start),
+        None => grammar.sets(),
+    };
+    match args.format {
+        OutputFormat::Text => println!("{}", sets),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&sets).map_err(|err| err.to_string())?),
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(&sets).map_err(|err| err.to_string())?),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("parse") => {
+            let args = parse_args(&args[1..], true).unwrap_or_else(|err| fail(2, &format!("{}\n\n{}", err, USAGE)));
+            exit(run_parse(args))
+        },
+        Some("sets") => {
+            let args = parse_args(&args[1..], false).unwrap_or_else(|err| fail(2, &format!("{}\n\n{}", err, USAGE)));
+            run_sets(args).unwrap_or_else(|err| fail(2, &err))
+        },
+        Some("help") | Some("-h") | Some("--help") => println!("{}", USAGE),
+        Some(other) => fail(2, &format!("unknown command '{}'\n\n{}", other, USAGE)),
+        None => fail(2, USAGE),
+    }
+}
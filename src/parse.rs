@@ -1,12 +1,19 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
 use crate::grammar::*;
 use crate::tree::*;
 use crate::combination::*;
 use crate::ctx::*;
+use crate::error::*;
+use crate::earley::{parse_earley_from, Chart};
+use crate::ll1::parse_ll1_from;
+use crate::lr::{parse_lr_from, LrMode};
+use crate::glr::parse_glr_from;
+use crate::cnf::parse_cyk_from;
+use crate::stack;
 
 
-
-pub type Error = String;
-
 pub type ParseTreeIter<'tg, T> = Box<dyn Iterator<Item = Result<ParseTree<'tg, 'tg, T>, Error>> + 'tg>;
 pub type ParseTreeNodeIter<'tg, T> = Box<dyn Iterator<Item = Result<ParseTreeNode<'tg, 'tg, T>, Error>> + 'tg>;
 
@@ -16,8 +23,8 @@ pub fn do_production<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>, production: &'tg Prod
         println!("{:<48}{:#}", format!("ss{}{}", "`".repeat(ctx.level), &ctx.prod_stack), ctx);
     }
     
-    if ctx.prod_stack.head().iter().find(|p| p.lhs == production.lhs).is_some() {
-        return Box::new(vec![ Err(format!("production recursion '{}'", &production.lhs)) ].into_iter()) 
+    if ctx.prod_stack.is_cycle() {
+        return Box::new(vec![ Err(Error::ProductionRecursion(production.lhs.clone())) ].into_iter()) 
             as ParseTreeIter<T>;
     }
 
@@ -32,16 +39,17 @@ pub fn do_production<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>, production: &'tg Prod
         
     //println!("{}", format!("do_production end: {:?}", r).yellow().on_black());
     if ignore_errors {
-        Box::new(r.filter(|f|f.is_ok()))
+        Box::new(stack::Growing(r.filter(|f|f.is_ok())))
     } else {
-        Box::new(r)
+        Box::new(stack::Growing(r))
     }
 }
 
 
 pub fn do_term<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>, term: &'tg Term) -> ParseTreeNodeIter<'tg, T> {
-    if ctx.level >= 128 {
-        return Box::new(vec![ Err(format!("max level reached")) ].into_iter()) 
+    if let Some(limit) = ctx.max_depth.filter(|&limit| ctx.level >= limit) {
+        ctx.memo.borrow_mut().depth_exceeded = true;
+        return Box::new(vec![ Err(Error::MaxLevelReached { limit }) ].into_iter()) 
             as ParseTreeNodeIter<T>;
     }
 
@@ -56,22 +64,37 @@ pub fn do_term<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>, term: &'tg Term) -> ParseTr
                 if ctx.front().name() == terminal {
                     vec![ Ok(ParseTreeNode::Terminal(ctx.front())) ]
                 } else {
-                    vec![ Err(format!("front token '{}' is not given terminal '{}'", ctx.front(), terminal)) ]
+                    vec![ Err(Error::UnexpectedToken {
+                        position: ctx.begin,
+                        expected: terminal.clone(),
+                        found: ctx.front().name().to_string()
+                    }) ]
                 }
             } else {
-                vec![ Err(format!("Ctx len is not 1 on {} != {:#}", term, ctx)) ]
+                vec![ Err(Error::TokenCount { position: ctx.begin, len: ctx.len(), terminal: terminal.clone() }) ]
             }.into_iter())
         },
         Term::Nonterminal(nonterminal) => {
             if let Some(p) = ctx.grammar.productions.iter().find(|p| &p.lhs == nonterminal) {
-                Box::new(
-                    do_production(ctx.next_level(p), p)
-                        .map(|tree| 
-                            tree.map(|tree| ParseTreeNode::Nonterminal(tree))
-                        )
-                )
+                let ctx = ctx.next_level(p);
+                let trees = |ctx| do_production(ctx, p).map(|tree| tree.map(ParseTreeNode::Nonterminal));
+                if ctx.max_depth.is_some() {
+                    // a result memoized at a shallower level could hold trees deeper than the budget allows
+                    return Box::new(trees(ctx))
+                }
+                let key = ctx.memo_key(p);
+                let cached = ctx.memo.borrow_mut().get(&key);
+                if let Some(nodes) = cached {
+                    if ctx.logs_enabled {
+                        println!("{:<48}{:#}", format!("M {}{}", "`".repeat(ctx.level), term), ctx);
+                    }
+                    return Box::new(nodes)
+                }
+                let memo = ctx.memo.clone();
+                let nodes = memo.borrow_mut().insert(key, Box::new(trees(ctx)));
+                Box::new(nodes)
             } else {
-                Box::new(vec![ Err(format!("production '{}' not found", nonterminal)) ].into_iter()) 
+                Box::new(vec![ Err(Error::ProductionNotFound(nonterminal.clone())) ].into_iter()) 
                     as ParseTreeNodeIter<T>
             }
         },
@@ -83,19 +106,22 @@ pub fn do_term<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>, term: &'tg Term) -> ParseTr
 pub fn do_expression<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>, production_name: &'tg String, expression: &'tg Expression) -> ParseTreeIter<'tg, T> {
     if ctx.logs_enabled {
         println!("{:<48}{:#}", format!("E {}{}", "`".repeat(ctx.level), VecDisplay { v: expression.terms.iter().collect() }), ctx);
-        for c in ctx.combinations(expression.terms.len()) {
+        for c in ctx.term_combinations(&expression.terms) {
             println!("{:<48}{:#}", format!("C {}", "`".repeat(ctx.level)), VecDisplay { v: ctx.split(c) });
         }
     }
+    if expression.terms.is_empty() {
+        // ε matches the empty range only
+        return Box::new(
+            ctx.is_empty().then(|| Ok(ParseTree { lhs: production_name, rhs: Children::default(), range: ctx.begin..ctx.end })).into_iter()
+        )
+    }
     //let ctx = &ctx;
-    let ctx = if expression.terms.len() > 1 {
-        ctx.reset_stack()
-    } else {
-        ctx
-    };
     //println!("{}", format!("do_expression: {}, '{}', {:?}", ctx, production_name, expression).blue());
-    let r = ctx.combinations(expression.terms.len()).into_iter().map(move |combination|{
+    let range = ctx.begin..ctx.end;
+    let r = ctx.term_combinations(&expression.terms).map(move |combination|{
         //println!("{}", format!("\tcombination: {:?}, {}", combination, VecDisplay { v: ctx.split(combination.clone()) }).blue().italic());
+        let range = range.clone();
 
         let a = expand_combinations_iter(
             ctx
@@ -103,12 +129,12 @@ pub fn do_expression<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>, production_name: &'tg
                 .into_iter()
                 .zip(expression.terms.iter())
                 .map(|(subctx, term): (Ctx<'tg, 'tg, T>, _)| do_term(subctx, term))
-        ).map(|subcombination| {
+        ).map(move |subcombination| {
             //println!("{}", format!("\t\tsubcombination: {:?}", subcombination).blue().italic());
 
 
 
-            let mut tree = ParseTree { lhs: production_name, rhs: vec![] };
+            let mut tree = ParseTree { lhs: production_name, rhs: Children::default(), range: range.clone() };
             let mut error: Option<Error> = None;
 
             for t in subcombination {        
@@ -151,20 +177,163 @@ pub fn make_ctx<'tg, T: Token>(
         level: 0,
         logs_enabled: logs_enabled,
         ignore_errors: ignore_errors,
-        prod_stack: Default::default()
+        max_depth: None,
+        prod_stack: Default::default(),
+        memo: Default::default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// tries every split of the token range (see `do_expression`)
+    #[default]
+    Backtracking,
+    /// polynomial chart parser, handles left recursion natively
+    Earley,
+    /// linear predictive parser, falls back to `Earley` when the grammar has LL(1) conflicts
+    Ll1,
+    /// linear shift-reduce parser on the LALR(1) automaton, falls back to `Earley` on conflicts
+    Lalr,
+    /// same as `Lalr` on the canonical LR(1) automaton, more states but fewer conflicts
+    Lr1,
+    /// Tomita parser on the LALR(1) automaton, forks the stack on conflicts and yields every tree
+    Glr,
+    /// CYK on the grammar converted to Chomsky normal form, cubic but simple enough to serve as a reference
+    Cyk,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ParseOptions {
+    /// nonterminal the tokens are parsed as. the first production if `None`
+    pub start: Option<String>,
+    pub engine: Engine,
+    /// derivation depth budget of the backtracking engine, unlimited if `None`
+    pub max_depth: Option<usize>,
+    /// `Engine::Ll1`, `Lalr` and `Lr1` yield `Error::Ll1Conflicts` or `Error::LrConflicts` instead of falling
+    /// back to `Earley` when their table has conflicts
+    pub strict: bool,
+}
+
+pub fn parse_with<'tg, T: Token + 'tg>(ctx: Ctx<'tg, 'tg, T>, engine: Engine) -> ParseTreeIter<'tg, T> {
+    parse_with_options(ctx, &ParseOptions { engine, ..Default::default() })
+}
+
+pub fn parse_with_options<'tg, T: Token + 'tg>(mut ctx: Ctx<'tg, 'tg, T>, options: &ParseOptions) -> ParseTreeIter<'tg, T> {
+    ctx.max_depth = options.max_depth;
+    let start = match &options.start {
+        None => 0,
+        Some(name) => match ctx.grammar.productions.iter().position(|p| &p.lhs == name) {
+            Some(start) => start,
+            None => return Box::new(std::iter::once(Err(Error::StartNotFound(name.clone())))),
+        },
+    };
+    match options.engine {
+        Engine::Backtracking => parse_from(ctx, start),
+        Engine::Earley => parse_earley_from(ctx, start),
+        Engine::Ll1 => parse_ll1_from(ctx, start, options.strict),
+        Engine::Lalr => parse_lr_from(ctx, start, LrMode::Lalr, options.strict),
+        Engine::Lr1 => parse_lr_from(ctx, start, LrMode::Canonical, options.strict),
+        Engine::Glr => parse_glr_from(ctx, start),
+        Engine::Cyk => parse_cyk_from(ctx, start),
+    }
+}
+
+/// parses all `tokens` as the nonterminal `name`
+pub fn parse_as<'tg, T: Token + 'tg>(grammar: &'tg Grammar, tokens: &'tg Vec<T>, name: &str) -> ParseTreeIter<'tg, T> {
+    parse_with_options(
+        make_ctx(grammar, tokens, false, true), 
+        &ParseOptions { start: Some(name.to_string()), ..Default::default() }
+    )
+}
+
+/// hides productions generated for ebnf groups and repetitions from the trees. derivations differing
+/// only inside of them become equal trees, which are yielded once
+pub(crate) fn splice_synthetic<'tg, T: 'tg>(grammar: &'tg Grammar, trees: ParseTreeIter<'tg, T>) -> ParseTreeIter<'tg, T> {
+    if grammar.synthetic.is_empty() {
+        return trees
+    }
+    let mut yielded = std::collections::HashSet::new();
+    Box::new(trees
+        .map(|tree| tree.map(|tree| tree.splice(&grammar.synthetic)))
+        .filter(move |tree| tree.as_ref().map_or(true, |tree| yielded.insert(tree.shape()))))
+}
+
+/// the error of a failed parse, nothing if the ctx ignores errors. errors not caused by the input are always yielded
+pub(crate) fn parse_failed<'tg, T: 'tg>(ignore_errors: bool, err: Error) -> ParseTreeIter<'tg, T> {
+    match err {
+        Error::Syntax(_) | Error::MaxLevelReached { .. } if ignore_errors => Box::new(std::iter::empty()),
+        err => Box::new(std::iter::once(Err(err))),
+    }
+}
+
+/// yields the `Error::Syntax` diagnostic once the inner iterator is exhausted without a single tree
+struct ReportSyntaxError<'tg, T> {
+    trees: ParseTreeIter<'tg, T>,
+    ctx: Option<Ctx<'tg, 'tg, T>>,
+    /// memo of the parse, emptied when the trees are no longer needed
+    memo: Rc<RefCell<Memo<'tg, 'tg, T>>>,
+    start: usize,
+    found: bool,
+}
+
+impl<'tg, T> Drop for ReportSyntaxError<'tg, T> {
+    fn drop(&mut self) {
+        let entries = self.memo.borrow_mut().take_entries();
+        drop(entries)
+    }
+}
+
+impl<'tg, T: Token> Iterator for ReportSyntaxError<'tg, T> {
+    type Item = Result<ParseTree<'tg, 'tg, T>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.trees.next() {
+            Some(tree) => {
+                self.found |= tree.is_ok();
+                Some(tree)
+            },
+            None => {
+                let ctx = self.ctx.take()?;
+                if self.found || ctx.ignore_errors {
+                    None
+                } else if let Some(limit) = ctx.max_depth.filter(|_| ctx.memo.borrow().depth_exceeded) {
+                    // the input may still be valid, only deeper than allowed
+                    Some(Err(Error::MaxLevelReached { limit }))
+                } else {
+                    // the backtracking engine can not tell how far it got, the chart can
+                    let chart = Chart::build(&ctx, self.start);
+                    if chart.is_accepted() {
+                        Some(Err(Error::NoTreeFound))
+                    } else {
+                        Some(Err(Error::Syntax(chart.syntax_error())))
+                    }
+                }
+            },
+        }
     }
 }
 
 pub fn parse<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>) -> ParseTreeIter<'tg, T> {
+    parse_from(ctx, 0)
+}
+
+fn parse_from<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>, start: usize) -> ParseTreeIter<'tg, T> {
     if ctx.logs_enabled {
         println!("input: {:?} <- {:#?}", ctx.tokens, ctx.grammar);
     }
 
-    let err = Err("grammar is empty".to_string()) as Result<ParseTree<'tg, 'tg, T>, _>;
+    let err = Err(Error::GrammarIsEmpty) as Result<ParseTree<'tg, 'tg, T>, _>;
 
-    if let Some(first) = ctx.grammar.productions.first() {
-        let a = do_production(ctx, first);
-        a
+    if let Some(production) = ctx.grammar.productions.get(start) {
+        let mut root = ctx.clone();
+        root.prod_stack = root.prod_stack.with(production, ctx.begin..ctx.end);
+        splice_synthetic(ctx.grammar, Box::new(ReportSyntaxError {
+            trees: do_production(root, production),
+            memo: ctx.memo.clone(),
+            ctx: Some(ctx),
+            start,
+            found: false
+        }))
     } else {
         Box::new(std::iter::once(err)) as ParseTreeIter<'tg, T>
     }
@@ -203,6 +372,12 @@ mod tests {
     use crate::parse::make_ctx;
     use crate::{
         parse, 
+        parse_with,
+        parse_with_options,
+        parse_as,
+        ParseOptions,
+        Engine,
+        Optionals,
         Error, 
         assert_contains_tree
     };
@@ -477,6 +652,385 @@ mod tests {
         );
     }
 
+    #[test]
+    fn memo_test() {
+        let g: ExtGrammar = r#"
+            <block> ::= <subs> | <subs> ";" <block>
+            <subs>  ::= <lhs> "=" <rhs>
+            <lhs>   ::= "ID"
+            <rhs>   ::= <expr> | <rhs> "." <expr>
+            <expr>  ::= "W"
+        "#
+            .try_into()
+            .unwrap();
+        let g = g.flatten();
+
+        let t: Vec<_> = [ "ID", "=", "W", ".", "W", ";", "ID", "=", "W", ".", "W", ".", "W" ]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let ctx = make_ctx(&g, &t, false, true);
+        let stats_ctx = ctx.clone();
+        let trees: Vec<_> = parse(ctx).collect();
+
+        assert_eq!(trees.len(), 1);
+        let stats = stats_ctx.memo_stats();
+        assert!(stats.hits > 0);
+        assert!(stats.misses > 0);
+
+        // a depth budget turns the memo off, both ways must find the same trees
+        let g: ExtGrammar = r#"
+            <e> ::= <e> "+" <e> | <e> "*" <e> | <a>
+            <a> ::= "n" | <e>
+        "#
+            .try_into()
+            .unwrap();
+        let g = g.flatten();
+        let t = tokens(&[ "n", "+", "n", "*", "n", "+", "n", "*", "n" ]);
+        let trees = |max_depth| {
+            let ctx = make_ctx(&g, &t, false, true);
+            let stats_ctx = ctx.clone();
+            let mut trees: Vec<_> = parse_with_options(ctx, &ParseOptions { max_depth, ..Default::default() })
+                .map(|t| format!("{:#}", t.unwrap()))
+                .collect();
+            trees.sort();
+            (trees, stats_ctx.memo_stats())
+        };
+        let (memoized, stats) = trees(None);
+        let (plain, plain_stats) = trees(Some(1000));
+        assert_eq!(memoized.len(), 14);
+        assert_eq!(memoized, plain);
+        assert!(stats.hits > 0);
+        assert_eq!(plain_stats, Default::default());
+    }
+
+    #[test]
+    fn first_tree_test() {
+        // catalan(31) trees, the first one must not wait for the others
+        let g: ExtGrammar = r#"
+            <s> ::= <s> <s> | "a"
+        "#
+            .try_into()
+            .unwrap();
+        let g = g.flatten();
+        let t = tokens(&[ "a"; 32 ]);
+
+        let ctx = make_ctx(&g, &t, false, true);
+        let stats_ctx = ctx.clone();
+        let first = parse(ctx).next().unwrap().unwrap();
+        assert_eq!(first.range, 0..32);
+        // one miss per sub-range on the way down, a full enumeration would visit every one of the 32 * 33 / 2
+        let stats = stats_ctx.memo_stats();
+        assert!(stats.misses < 2 * t.len(), "{:?}", stats);
+    }
+
+    #[test]
+    fn production_not_found_test() {
+        let g: ExtGrammar = r#"
+            <s> ::= "a" <missing>
+        "#
+            .try_into()
+            .unwrap();
+        let g = g.flatten();
+        let t = tokens(&[ "a", "b" ]);
+        let errors: Vec<_> = parse(make_ctx(&g, &t, false, false)).filter_map(Result::err).collect();
+        assert!(errors.contains(&Error::ProductionNotFound("missing".to_string())), "{:?}", errors);
+    }
+
+    #[test]
+    fn syntax_error_test() {
+        let g: ExtGrammar = r#"
+            <block> ::= <subs> | <subs> ";" <block>
+            <subs>  ::= "ID" "=" "W"
+        "#
+            .try_into()
+            .unwrap();
+        let g = g.flatten();
+
+        let t: Vec<_> = [ "ID", "=", "W", ";", "ID", "W" ]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        assert_eq!(parse(make_ctx(&g, &t, false, true)).count(), 0);
+        // the failed branches come first, the diagnostic is yielded last
+        let result = parse(make_ctx(&g, &t, false, false)).last();
+        match &result {
+            Some(Err(Error::Syntax(err))) => {
+                assert_eq!(err.position, 5);
+                assert_eq!(err.expected, [ "=".to_string() ].into());
+                assert_eq!(err.production_stack, vec![ "block", "block", "subs" ]);
+            },
+            _ => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn no_tree_found_test() {
+        let g: ExtGrammar = r#"
+            <list> ::= "a" | "a" <list>
+        "#
+            .try_into()
+            .unwrap();
+        let g = g.flatten();
+        let t: Vec<_> = [ "a", "a" ].into_iter().map(String::from).collect();
+
+        // a valid input the engine gave up on is not reported as a syntax error
+        let ctx = make_ctx(&g, &t, false, false);
+        let report = super::ReportSyntaxError { trees: Box::new(std::iter::empty()), memo: ctx.memo.clone(), ctx: Some(ctx), start: 0, found: false };
+        assert_eq!(report.collect::<Vec<_>>(), vec![ Err(Error::NoTreeFound) ]);
+    }
+
+    #[test]
+    fn ebnf_test() {
+        let g: ExtGrammar = r#"
+            <list> ::= "[" <item> ("," <item>)* "]"
+            <item> ::= "a" | "b"
+        "#
+            .try_into()
+            .unwrap();
+        let g = g.flatten();
+
+        let t: Vec<_> = [ "[", "a", ",", "b", ",", "a", "]" ]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        // synthetic repetition nodes are spliced into <list>
+        let expected = r#"
+            |list
+            |`[
+            |`item
+            |``a
+            |`,
+            |`item
+            |``b
+            |`,
+            |`item
+            |``a
+            |`]
+        "#.trim_margin().unwrap();
+
+        for engine in [ Engine::Backtracking, Engine::Earley ] {
+            let trees: Vec<_> = parse_with(make_ctx(&g, &t, false, true), engine)
+                .map(|tree| format!("{:#}", tree.unwrap()))
+                .collect();
+            assert_eq!(trees, vec![ expected.clone() + "\n" ]);
+        }
+    }
+
+    #[test]
+    fn splice_dedup_test() {
+        let g: ExtGrammar = r#"
+            <s> ::= "x"* "x"*
+        "#
+            .try_into()
+            .unwrap();
+        let g = g.flatten();
+        let t: Vec<_> = [ "x", "x" ].into_iter().map(String::from).collect();
+
+        // three derivations split the tokens differently between the repetitions, all of them splice into one tree
+        for engine in [ Engine::Backtracking, Engine::Earley, Engine::Glr ] {
+            let trees: Vec<_> = parse_with(make_ctx(&g, &t, false, true), engine)
+                .map(|tree| format!("{:#}", tree.unwrap()))
+                .collect();
+            assert_eq!(trees, vec![ "s\n`x\n`x\n".to_string() ], "{:?}", engine);
+        }
+    }
+
+    #[test]
+    fn start_test() {
+        let g: ExtGrammar = r#"
+            <block> ::= <subs> | <subs> ";" <block>
+            <subs>  ::= "ID" "=" <rhs>
+            <rhs>   ::= "W" | <rhs> "." "W"
+        "#
+            .try_into()
+            .unwrap();
+        let g = g.flatten();
+
+        let t: Vec<_> = [ "W", ".", "W" ]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        for engine in [ Engine::Backtracking, Engine::Earley ] {
+            let options = ParseOptions { start: Some("rhs".to_string()), engine, ..Default::default() };
+            let trees: Vec<_> = parse_with_options(make_ctx(&g, &t, false, true), &options).collect();
+            assert_eq!(trees.len(), 1);
+            assert_eq!(trees[0].as_ref().unwrap().lhs, "rhs");
+        }
+
+        assert!(parse_as(&g, &t, "block").next().is_none());
+        assert_eq!(
+            parse_as(&g, &t, "expr").collect::<Vec<_>>(), 
+            vec![ Err(Error::StartNotFound("expr".to_string())) ]
+        );
+    }
+
+    #[test]
+    fn deep_test() {
+        let g: ExtGrammar = r#"
+            <list> ::= "a" | "a" <list>
+        "#
+            .try_into()
+            .unwrap();
+        let g = g.flatten();
+
+        // both deeper than the former fixed limit of 128 levels
+        for (engine, len) in [ (Engine::Backtracking, 150), (Engine::Earley, 500) ] {
+            let t: Vec<_> = std::iter::repeat_n("a".to_string(), len).collect();
+            let options = ParseOptions { engine, ..Default::default() };
+            let trees: Vec<_> = parse_with_options(make_ctx(&g, &t, false, true), &options).collect();
+            assert_eq!(trees.len(), 1);
+            assert!(trees[0].is_ok());
+        }
+
+        let t: Vec<_> = std::iter::repeat_n("a".to_string(), 150).collect();
+        let options = ParseOptions { max_depth: Some(100), ..Default::default() };
+        assert_eq!(parse_with_options(make_ctx(&g, &t, false, true), &options).count(), 0);
+        assert_eq!(
+            parse_with_options(make_ctx(&g, &t, false, false), &options).last(),
+            Some(Err(Error::MaxLevelReached { limit: 100 }))
+        );
+    }
+
+    /// sorted trees of both engines, which must agree
+    fn engine_trees(g: &Grammar, t: &Vec<String>) -> Vec<String> {
+        let trees = |engine| {
+            let mut trees: Vec<_> = parse_with(make_ctx(g, t, false, true), engine)
+                .map(|t| format!("{:#}", t.unwrap()))
+                .collect();
+            trees.sort();
+            trees
+        };
+        let backtracking = trees(Engine::Backtracking);
+        assert_eq!(backtracking, trees(Engine::Earley));
+        backtracking
+    }
+
+    fn tokens(t: &[&str]) -> Vec<String> {
+        t.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn indirect_left_recursion_test() {
+        let g: ExtGrammar = r#"
+            <expr> ::= <sum> | <atom>
+            <sum>  ::= <term> "+" <atom>
+            <term> ::= <expr>
+            <atom> ::= "n" | "(" <expr> ")"
+        "#
+            .try_into()
+            .unwrap();
+        let g = g.flatten();
+
+        let trees = engine_trees(&g, &tokens(&[ "n", "+", "(", "n", "+", "n", ")", "+", "n" ]));
+        assert_eq!(trees.len(), 1);
+        assert!(trees[0].starts_with("expr\n`sum\n``term\n```expr\n````sum\n"));
+    }
+
+    #[test]
+    fn unit_cycle_test() {
+        let g: ExtGrammar = r#"
+            <s> ::= <a> "." <b>
+            <a> ::= <b> | "x"
+            <b> ::= <a> | "y"
+        "#
+            .try_into()
+            .unwrap();
+        let g = g.flatten();
+
+        // cyclic derivations like <a> => <b> => <a> => "x" are cut, every acyclic one is kept
+        assert_eq!(engine_trees(&g, &tokens(&[ "x", ".", "x" ])), vec![
+            "s\n`a\n``x\n`.\n`b\n``a\n```x\n".to_string()
+        ]);
+        assert_eq!(engine_trees(&g, &tokens(&[ "y", ".", "y" ])), vec![
+            "s\n`a\n``b\n```y\n`.\n`b\n``y\n".to_string()
+        ]);
+    }
+
+    #[test]
+    fn hard_level_grammar_engines_test() {
+        let g: ExtGrammar = HARD_LVL_GRAMMAR.try_into().unwrap();
+        let g = g.flatten();
+
+        let trees = engine_trees(&g, &tokens(&[ 
+            "<", "K", "0", ">", "::=", "`", "q", "J", "`", "or", "<", "L", "_", ">", "end", "end", "end"
+        ]));
+        // <line_end> ::= <line_end> <line_end> splits three "end"s two ways
+        assert_eq!(trees.len(), 2);
+    }
+
+    #[test]
+    fn epsilon_test() {
+        let g: ExtGrammar = r#"
+            <call> ::= "f" "(" <args> ")"
+            <args> ::= ε | <arg> <more>
+            <more> ::= "" | "," <arg> <more>
+            <arg>  ::= "x" | <call>
+        "#
+            .try_into()
+            .unwrap();
+        let g = g.flatten();
+        assert_eq!(format!("{}", g.productions[1]), "<args> ::= ε | <arg> <more>");
+
+        assert_eq!(engine_trees(&g, &tokens(&[ "f", "(", ")" ])), vec![ r#"
+            |call
+            |`f
+            |`(
+            |`args
+            |`)
+        "#.trim_margin().unwrap() + "\n" ]);
+        assert_eq!(engine_trees(&g, &tokens(&[ "f", "(", "x", ",", "f", "(", ")", ")" ])).len(), 1);
+        let empty: Vec<String> = vec![];
+        for t in [ tokens(&[ "f", "(", "x", "x", ")" ]), empty.clone() ] {
+            for engine in [ Engine::Backtracking, Engine::Earley ] {
+                assert!(parse_with(make_ctx(&g, &t, false, true), engine).all(|tree| tree.is_err()));
+            }
+        }
+        let args: Vec<_> = parse_as(&g, &empty, "args").map(|t| format!("{:#}", t.unwrap())).collect();
+        assert_eq!(args, vec![ "args\n".to_string() ]);
+    }
+
+    #[test]
+    fn optionals_test() {
+        let text = r#"
+            <decl> ::= "pub"? "const"? "static"? "mut"? "unsafe"? "fn" <name>
+            <name> ::= "a"
+        "#;
+        let ext = || -> ExtGrammar { text.try_into().unwrap() };
+        assert_eq!(ext().flatten().productions[0].rhs.len(), 32);
+
+        let nullable = ext().flatten_with(Optionals::Nullable);
+        assert_eq!(nullable.productions[0].rhs.len(), 1);
+        assert_eq!(format!("{}", nullable.productions[2]), r#"<"pub"?> ::= "pub" | ε"#);
+
+        let t = tokens(&[ "pub", "unsafe", "fn", "a" ]);
+        let expanded = engine_trees(&ext().flatten(), &t);
+        assert_eq!(expanded.len(), 1);
+        assert_eq!(engine_trees(&nullable, &t), expanded);
+
+        let forest = crate::parse_forest(make_ctx(&nullable, &t, false, true)).unwrap();
+        assert!(!forest.is_ambiguous());
+
+        let explicit = ext().flatten_with(Optionals::Explicit);
+        assert_eq!(engine_trees(&explicit, &t), vec![ r#"
+            |decl
+            |`"pub"?
+            |``pub
+            |`"const"?
+            |`"static"?
+            |`"mut"?
+            |`"unsafe"?
+            |``unsafe
+            |`fn
+            |`name
+            |``a
+        "#.trim_margin().unwrap() + "\n" ]);
+    }
+
     static HARD_LVL_GRAMMAR: &str = r#"
         <syntax>         ::= <rule> | <rule> <syntax>
         <rule>           ::= "<" <rule_name> ">" "::=" <expression> <line_end>
@@ -0,0 +1,244 @@
+use std::collections::HashSet;
+
+use crate::bnf_lexer::char_class;
+use crate::ctx::Ctx;
+use crate::earley::production_lookup;
+use crate::grammar::{Expression, Grammar, Production, Term};
+use crate::lexer::{LexToken, Span};
+use crate::parse::{parse_with_options, ParseOptions, ParseTreeIter};
+
+
+/// nonterminal deriving the whitespace skipped before terminals, removed from the trees
+pub const WHITESPACE: &str = ":ws";
+
+const WHITESPACE_CHARS: [char; 4] = [ ' ', '\t', '\n', '\r' ];
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ScannerlessOptions {
+    /// optional whitespace before every terminal, leading and trailing whitespace of the input is dropped
+    pub auto_whitespace: bool,
+    /// productions matched char by char without whitespace inside, like identifiers or numbers.
+    /// everything they derive is lexical too
+    pub lexical: Vec<String>,
+}
+
+/// grammar matching raw text one char at a time
+#[derive(Debug)]
+pub struct Scannerless {
+    /// terminals are split into their chars and every char class `<[a-z]>` gets an expression per char.
+    /// with `auto_whitespace` terminals and lexical nonterminals are preceded by `<:ws>`
+    pub grammar: Grammar,
+    auto_whitespace: bool,
+}
+
+/// chars of a class name like `[a-z_]`, `None` for other names
+fn class_chars(name: &str) -> Option<Vec<char>> {
+    let ranges = char_class(name.strip_prefix('[')?.strip_suffix(']')?)?;
+    let mut chars: Vec<char> = ranges.into_iter().flat_map(|(from, to)| from..=to).collect();
+    chars.sort();
+    chars.dedup();
+    Some(chars)
+}
+
+fn char_expression(c: char, rest: &[Term]) -> Expression {
+    Expression { terms: std::iter::once(Term::Terminal(c.to_string())).chain(rest.iter().cloned()).collect() }
+}
+
+impl Grammar {
+    /// char level version of the grammar for `Scannerless::parse`
+    pub fn scannerless(&self, options: &ScannerlessOptions) -> Scannerless {
+        let lookup = production_lookup(self);
+        let mut lexical: HashSet<&str> = HashSet::new();
+        let mut stack: Vec<&str> = options.lexical.iter().map(String::as_str).collect();
+        while let Some(name) = stack.pop() {
+            if !lexical.insert(name) {
+                continue
+            }
+            if let Some(&p) = lookup.get(name) {
+                for term in self.productions[p].rhs.iter().flat_map(|e| &e.terms) {
+                    if let Term::Nonterminal(n) = term {
+                        stack.push(n);
+                    }
+                }
+            }
+        }
+
+        let whitespace = Term::Nonterminal(WHITESPACE.to_string());
+        let mut classes: Vec<(String, Vec<char>)> = vec![];
+        let mut skips = false;
+        let mut productions: Vec<Production> = self.productions.iter().map(|production| {
+            let skip = options.auto_whitespace && !lexical.contains(production.lhs.as_str());
+            let rhs = production.rhs.iter().map(|expression| {
+                let mut terms = vec![];
+                for term in &expression.terms {
+                    match term {
+                        Term::Terminal(t) => {
+                            if skip {
+                                terms.push(whitespace.clone());
+                            }
+                            terms.extend(t.chars().map(|c| Term::Terminal(c.to_string())));
+                        },
+                        Term::Nonterminal(n) => {
+                            let class = if lookup.contains_key(n.as_str()) { None } else { class_chars(n) };
+                            if skip && (class.is_some() || lexical.contains(n.as_str())) {
+                                terms.push(whitespace.clone());
+                            }
+                            if let Some(chars) = class.filter(|_| !classes.iter().any(|(name, _)| name == n)) {
+                                classes.push((n.clone(), chars));
+                            }
+                            terms.push(term.clone());
+                        },
+                    }
+                }
+                skips |= skip && !terms.is_empty();
+                Expression { terms }
+            }).collect();
+            Production { lhs: production.lhs.clone(), rhs }
+        }).collect();
+
+        let mut synthetic = self.synthetic.clone();
+        for (lhs, chars) in classes {
+            synthetic.insert(lhs.clone());
+            productions.push(Production { lhs, rhs: chars.into_iter().map(|c| char_expression(c, &[])).collect() });
+        }
+        if skips {
+            let rhs = std::iter::once(Expression { terms: vec![] })
+                .chain(WHITESPACE_CHARS.into_iter().map(|c| char_expression(c, std::slice::from_ref(&whitespace))))
+                .collect();
+            productions.push(Production { lhs: WHITESPACE.to_string(), rhs });
+        }
+        Scannerless { grammar: Grammar { productions, synthetic }, auto_whitespace: options.auto_whitespace }
+    }
+}
+
+impl Scannerless {
+    /// a token per char of `source` named by the char
+    pub fn tokens(&self, source: &str) -> Vec<LexToken> {
+        let mut span = Span { begin: 0, end: 0, line: 1, column: 1 };
+        let mut tokens = vec![];
+        for (i, c) in source.char_indices() {
+            let text = &source[i..i + c.len_utf8()];
+            tokens.push(LexToken { name: text.to_string(), text: text.to_string(), span: Span { end: span.begin + text.len(), ..span } });
+            span = span.after(text);
+        }
+        tokens
+    }
+
+    /// all trees of the tokens in `ctx`, which is made for `self.grammar`. whitespace nodes are removed
+    pub fn parse<'tg>(&'tg self, ctx: Ctx<'tg, 'tg, LexToken>, options: &ParseOptions) -> ParseTreeIter<'tg, LexToken> {
+        self.parse_by(ctx, |ctx| parse_with_options(ctx, options))
+    }
+
+    /// like `Scannerless::parse` with the trees from `parse`, e.g. `parse_ll1` with a prebuilt table
+    pub fn parse_by<'tg>(
+        &'tg self,
+        mut ctx: Ctx<'tg, 'tg, LexToken>,
+        parse: impl FnOnce(Ctx<'tg, 'tg, LexToken>) -> ParseTreeIter<'tg, LexToken>
+    ) -> ParseTreeIter<'tg, LexToken> {
+        if !self.auto_whitespace {
+            return parse(ctx)
+        }
+        let tokens = &ctx.tokens[ctx.begin..ctx.end];
+        let is_whitespace = |t: &&LexToken| t.text.chars().all(|c| WHITESPACE_CHARS.contains(&c));
+        let leading = tokens.iter().take_while(is_whitespace).count();
+        let trailing = tokens[leading..].iter().rev().take_while(is_whitespace).count();
+        ctx.end -= trailing;
+        ctx.begin += leading;
+        Box::new(parse(ctx).map(|tree| tree.map(|tree| tree.prune(WHITESPACE))))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use crate::grammar::{ExtGrammar, Grammar};
+    use crate::parse::{make_ctx, Engine, ParseOptions};
+    use crate::tree::ParseTreeNode;
+    use crate::error::Error;
+    use crate::lexer::Span;
+
+    use super::{Scannerless, ScannerlessOptions};
+
+    fn grammar(text: &str) -> Grammar {
+        let g: ExtGrammar = text.try_into().unwrap();
+        g.flatten()
+    }
+
+    fn trees(s: &Scannerless, source: &str, engine: Engine) -> BTreeSet<String> {
+        let tokens = s.tokens(source);
+        let options = ParseOptions { engine, ..Default::default() };
+        s.parse(make_ctx(&s.grammar, &tokens, false, true), &options)
+            .filter_map(|t| t.ok())
+            .map(|t| format!("{:#}", t))
+            .collect()
+    }
+
+    const LET: &str = r#"
+        <let> ::= "let" <id> "=" <num> ";"
+        <id>  ::= <[a-z_]>+
+        <num> ::= <[0-9]>+
+    "#;
+
+    #[test]
+    fn grammar_test() {
+        let s = grammar(LET).scannerless(&Default::default());
+        assert_eq!(format!("{}", s.grammar.productions[0]), r#"<let> ::= "l" "e" "t" <id> "=" <num> ";""#);
+        assert!(s.grammar.productions.iter().any(|p| p.lhs == "[0-9]" && p.rhs.len() == 10));
+        assert!(s.grammar.synthetic.contains("[0-9]"));
+
+        let options = ScannerlessOptions { auto_whitespace: true, lexical: vec![ "id".to_string(), "num".to_string() ] };
+        let s = grammar(LET).scannerless(&options);
+        assert_eq!(
+            format!("{}", s.grammar.productions[0]),
+            r#"<let> ::= <:ws> "l" "e" "t" <:ws> <id> <:ws> "=" <:ws> <num> <:ws> ";""#
+        );
+        assert_eq!(format!("{}", s.grammar.productions[1]), "<id> ::= <id:1>");
+        let whitespace = s.grammar.productions.last().unwrap();
+        assert_eq!((whitespace.lhs.as_str(), whitespace.rhs.len()), (":ws", 5));
+        assert!(!s.grammar.synthetic.contains(":ws"));
+    }
+
+    #[test]
+    fn parse_test() {
+        let s = grammar(LET).scannerless(&Default::default());
+        assert_eq!(trees(&s, "letx_1=12;", Engine::Earley).len(), 0);
+        let found = trees(&s, "letx_=12;", Engine::Earley);
+        assert_eq!(found.len(), 1);
+        assert!(found.first().unwrap().starts_with("let\n`l\n`e\n`t\n`id\n``x\n``_\n`=\n"));
+        assert!(trees(&s, "let x=12;", Engine::Earley).is_empty());
+
+        let options = ScannerlessOptions { auto_whitespace: true, lexical: vec![ "id".to_string(), "num".to_string() ] };
+        let s = grammar(LET).scannerless(&options);
+        let source = " let\tab =\n 12 ;\n";
+        let found = trees(&s, source, Engine::Earley);
+        assert_eq!(found.len(), 1);
+        assert!(!found.first().unwrap().contains(":ws"));
+        for engine in [ Engine::Backtracking, Engine::Lalr, Engine::Glr, Engine::Cyk ] {
+            assert_eq!(trees(&s, source, engine), found, "{:?}", engine);
+        }
+        assert!(trees(&s, "let a b = 1;", Engine::Earley).is_empty());
+
+        let tokens = s.tokens(source);
+        let tree = s.parse(make_ctx(&s.grammar, &tokens, false, true), &Default::default()).next().unwrap().unwrap();
+        let ParseTreeNode::Nonterminal(num) = &tree.rhs[5] else { panic!("{:#}", tree) };
+        let ParseTreeNode::Terminal(digit) = num.rhs[1] else { panic!("{:#}", tree) };
+        assert_eq!(digit.span, Span { begin: 12, end: 13, line: 2, column: 3 });
+    }
+
+    #[test]
+    fn error_test() {
+        let options = ScannerlessOptions { auto_whitespace: true, lexical: vec![ "id".to_string(), "num".to_string() ] };
+        let s = grammar(LET).scannerless(&options);
+        let tokens = s.tokens("let a =\n  b;");
+        let result = s.parse(make_ctx(&s.grammar, &tokens, false, false), &ParseOptions { engine: Engine::Earley, ..Default::default() }).next();
+        match result {
+            Some(Err(Error::Syntax(err))) => {
+                assert_eq!(tokens[err.position].span.location().to_string(), "2:3");
+                assert!(err.expected.contains("7"));
+            },
+            other => panic!("unexpected result {:?}", other.map(|t| t.map(|t| format!("{:#}", t)))),
+        }
+    }
+}
@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::grammar::*;
+use crate::tree::*;
+use crate::ctx::*;
+use crate::error::Error;
+use crate::earley::{Chart, Item};
+use crate::parse::ParseTreeIter;
+use crate::stack;
+
+
+pub type NodeId = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Symbol {
+    /// token index
+    Terminal(usize),
+    /// production index
+    Nonterminal(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForestNode {
+    /// terminal or nonterminal deriving tokens `begin..end`, one packed child per derivation
+    Symbol {
+        symbol: Symbol,
+        begin: usize,
+        end: usize,
+        packed: Vec<NodeId>,
+    },
+    /// first `dot` terms of an expression deriving tokens `begin..end`
+    Intermediate {
+        production: usize,
+        expression: usize,
+        dot: usize,
+        begin: usize,
+        end: usize,
+        packed: Vec<NodeId>,
+    },
+    /// one derivation of the parent. children are an optional intermediate or symbol node
+    /// for all terms but the last one followed by the symbol node of the last term,
+    /// or the symbol nodes of every term in forests built by the GLR parser
+    Packed {
+        production: usize,
+        expression: usize,
+        children: Vec<NodeId>,
+    },
+}
+
+impl ForestNode {
+    pub fn packed(&self) -> &[NodeId] {
+        match self {
+            ForestNode::Symbol { packed, .. } => packed,
+            ForestNode::Intermediate { packed, .. } => packed,
+            ForestNode::Packed { .. } => &[],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DerivationCount {
+    /// saturates at `u128::MAX`
+    Finite(u128),
+    /// forest contains a cycle (`<a> ::= <b>`, `<b> ::= <a>` over the same tokens)
+    Infinite,
+}
+
+/// shared packed parse forest: every derivation of the input in a single graph
+/// where identical subtrees are stored once
+#[derive(Debug, Clone)]
+pub struct Forest<'tg, T> {
+    pub grammar: &'tg Grammar,
+    pub tokens: &'tg Vec<T>,
+    pub nodes: Vec<ForestNode>,
+    pub root: NodeId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Symbol(Symbol, usize, usize),
+    Intermediate(usize, usize, usize, usize, usize),
+}
+
+struct Builder<'c, 'tg, T> {
+    chart: &'c Chart<'tg, T>,
+    nodes: Vec<ForestNode>,
+    ids: HashMap<Key, NodeId>,
+    pending: Vec<(NodeId, Key)>,
+}
+
+impl<'c, 'tg, T: Token> Builder<'c, 'tg, T> {
+    fn node(&mut self, key: Key) -> NodeId {
+        if let Some(&id) = self.ids.get(&key) {
+            return id;
+        }
+        let begin = self.chart.begin;
+        let id = self.nodes.len();
+        self.nodes.push(match key {
+            Key::Symbol(symbol, i, j) => ForestNode::Symbol {
+                symbol,
+                begin: begin + i,
+                end: begin + j,
+                packed: vec![]
+            },
+            Key::Intermediate(production, expression, dot, i, j) => ForestNode::Intermediate {
+                production,
+                expression,
+                dot,
+                begin: begin + i,
+                end: begin + j,
+                packed: vec![]
+            },
+        });
+        self.ids.insert(key, id);
+        self.pending.push((id, key));
+        id
+    }
+
+    fn term_node(&mut self, term: &Term, i: usize, j: usize) -> NodeId {
+        match term {
+            Term::Terminal(_) => self.node(Key::Symbol(Symbol::Terminal(self.chart.begin + i), i, j)),
+            Term::Nonterminal(name) => {
+                let p = self.chart.production_of(name).unwrap();
+                self.node(Key::Symbol(Symbol::Nonterminal(p), i, j))
+            },
+        }
+    }
+
+    /// node for the first `dot` terms deriving `i..j`, `None` for an empty prefix
+    fn prefix_node(&mut self, production: usize, expression: usize, dot: usize, i: usize, j: usize) -> Option<NodeId> {
+        match dot {
+            0 => None,
+            1 => Some(self.term_node(&self.chart.terms(production, expression)[0], i, j)),
+            _ => Some(self.node(Key::Intermediate(production, expression, dot, i, j))),
+        }
+    }
+
+    /// packed nodes for all splits of `i..j` between the first `dot - 1` terms and the term at `dot - 1`
+    fn pack(&mut self, production: usize, expression: usize, dot: usize, i: usize, j: usize) -> Vec<NodeId> {
+        if dot == 0 {
+            return if i == j {
+                vec![ self.push_packed(production, expression, vec![]) ]
+            } else {
+                vec![]
+            }
+        }
+        let chart = self.chart;
+        let term = &chart.terms(production, expression)[dot - 1];
+        let prefix = Item { production, expression, dot: dot - 1, origin: i };
+        let mut result = vec![];
+        for m in i..=j {
+            if chart.contains(m, prefix) && chart.derives(term, m, j) {
+                let mut children: Vec<_> = self.prefix_node(production, expression, dot - 1, i, m).into_iter().collect();
+                children.push(self.term_node(term, m, j));
+                result.push(self.push_packed(production, expression, children));
+            }
+        }
+        result
+    }
+
+    fn push_packed(&mut self, production: usize, expression: usize, children: Vec<NodeId>) -> NodeId {
+        self.nodes.push(ForestNode::Packed { production, expression, children });
+        self.nodes.len() - 1
+    }
+
+    fn fill(&mut self, id: NodeId, key: Key) {
+        let packed = match key {
+            Key::Symbol(Symbol::Terminal(_), _, _) => vec![],
+            Key::Symbol(Symbol::Nonterminal(production), i, j) => {
+                let mut packed = vec![];
+                for expression in 0..self.chart.grammar.productions[production].rhs.len() {
+                    let len = self.chart.terms(production, expression).len();
+                    if self.chart.contains(j, Item { production, expression, dot: len, origin: i }) {
+                        packed.extend(self.pack(production, expression, len, i, j));
+                    }
+                }
+                packed
+            },
+            Key::Intermediate(production, expression, dot, i, j) => self.pack(production, expression, dot, i, j),
+        };
+        match &mut self.nodes[id] {
+            ForestNode::Symbol { packed: p, .. } => *p = packed,
+            ForestNode::Intermediate { packed: p, .. } => *p = packed,
+            ForestNode::Packed { .. } => unreachable!(),
+        }
+    }
+}
+
+impl<'tg, T: Token> Forest<'tg, T> {
+    /// `None` if the chart did not accept the input
+    pub(crate) fn from_chart(chart: &Chart<'tg, T>) -> Option<Self> {
+        if !chart.is_accepted() {
+            return None
+        }
+        let mut builder = Builder { chart, nodes: vec![], ids: HashMap::new(), pending: vec![] };
+        let root = builder.node(Key::Symbol(Symbol::Nonterminal(chart.start), 0, chart.sets.len() - 1));
+        while let Some((id, key)) = builder.pending.pop() {
+            builder.fill(id, key);
+        }
+        Some(Forest { grammar: chart.grammar, tokens: chart.tokens, nodes: builder.nodes, root })
+    }
+
+    /// number of distinct parse trees without enumerating them
+    pub fn count_derivations(&self) -> DerivationCount {
+        #[derive(Clone, Copy)]
+        enum State { Unvisited, InProgress, Done(u128) }
+
+        fn count<T>(forest: &Forest<T>, id: NodeId, states: &mut Vec<State>) -> Option<u128> {
+            match states[id] {
+                State::Done(n) => return Some(n),
+                State::InProgress => return None,
+                State::Unvisited => {},
+            }
+            states[id] = State::InProgress;
+            let n = match &forest.nodes[id] {
+                ForestNode::Symbol { symbol: Symbol::Terminal(_), .. } => 1,
+                ForestNode::Packed { children, .. } => {
+                    let mut n: u128 = 1;
+                    for &child in children {
+                        n = n.saturating_mul(stack::grow(|| count(forest, child, states))?);
+                    }
+                    n
+                },
+                node => {
+                    let mut n: u128 = 0;
+                    for &packed in node.packed() {
+                        n = n.saturating_add(stack::grow(|| count(forest, packed, states))?);
+                    }
+                    n
+                },
+            };
+            states[id] = State::Done(n);
+            Some(n)
+        }
+
+        match count(self, self.root, &mut vec![State::Unvisited; self.nodes.len()]) {
+            Some(n) => DerivationCount::Finite(n),
+            None => DerivationCount::Infinite,
+        }
+    }
+
+    pub fn is_ambiguous(&self) -> bool {
+        self.nodes.iter().any(|node| node.packed().len() > 1)
+    }
+
+    /// lazily unpacks the forest into trees. cyclic derivations are cut,
+    /// so a forest with `DerivationCount::Infinite` still yields finitely many trees
+    pub fn into_trees(self) -> ParseTreeIter<'tg, T> where T: 'tg {
+        let root = self.root;
+        Box::new(symbol_trees(Rc::new(self), root, Default::default()).map(Ok))
+    }
+}
+
+/// symbol nodes being unpacked above the current one
+type Guard = Rc<Vec<NodeId>>;
+
+type NodesIter<'tg, T> = Box<dyn Iterator<Item = Vec<ParseTreeNode<'tg, 'tg, T>>> + 'tg>;
+
+fn symbol_trees<'tg, T: Token + 'tg>(forest: Rc<Forest<'tg, T>>, id: NodeId, guard: Guard) -> Box<dyn Iterator<Item = ParseTree<'tg, 'tg, T>> + 'tg> {
+    let (lhs, range) = match &forest.nodes[id] {
+        ForestNode::Symbol { symbol: Symbol::Nonterminal(production), begin, end, .. } =>
+            (&forest.grammar.productions[*production].lhs, *begin..*end),
+        _ => unreachable!(),
+    };
+    let mut inner = (*guard).clone();
+    inner.push(id);
+    let guard: Guard = Rc::new(inner);
+    Box::new(stack::Growing(sequences(forest, id, guard).map(move |rhs| ParseTree { lhs, rhs: rhs.into(), range: range.clone() })))
+}
+
+/// all node sequences derived by a symbol or intermediate node
+fn sequences<'tg, T: Token + 'tg>(forest: Rc<Forest<'tg, T>>, id: NodeId, guard: Guard) -> NodesIter<'tg, T> {
+    let packed = forest.nodes[id].packed().to_vec();
+    Box::new(packed.into_iter().flat_map(move |packed| {
+        let children = match &forest.nodes[packed] {
+            ForestNode::Packed { children, .. } => children.clone(),
+            _ => unreachable!(),
+        };
+        children.into_iter().fold(
+            Box::new(std::iter::once(vec![])) as NodesIter<'tg, T>,
+            |acc, child| {
+                let forest = forest.clone();
+                let guard = guard.clone();
+                Box::new(acc.flat_map(move |prefix| {
+                    child_nodes(forest.clone(), child, guard.clone()).map(move |nodes| {
+                        let mut result = prefix.clone();
+                        result.extend(nodes);
+                        result
+                    })
+                }))
+            }
+        )
+    }))
+}
+
+/// a child of a packed node: a single terminal or nonterminal node, or a sequence for an intermediate node
+fn child_nodes<'tg, T: Token + 'tg>(forest: Rc<Forest<'tg, T>>, id: NodeId, guard: Guard) -> NodesIter<'tg, T> {
+    match &forest.nodes[id] {
+        ForestNode::Symbol { symbol: Symbol::Terminal(index), .. } => {
+            Box::new(std::iter::once(vec![ ParseTreeNode::Terminal(&forest.tokens[*index]) ]))
+        },
+        ForestNode::Symbol { .. } => {
+            if guard.contains(&id) {
+                Box::new(std::iter::empty())
+            } else {
+                Box::new(symbol_trees(forest, id, guard).map(|tree| vec![ ParseTreeNode::Nonterminal(tree) ]))
+            }
+        },
+        ForestNode::Intermediate { .. } => sequences(forest, id, guard),
+        ForestNode::Packed { .. } => unreachable!(),
+    }
+}
+
+/// runs the Earley recognizer and packs all derivations of the first production
+pub fn parse_forest<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>) -> Result<Forest<'tg, T>, Error> {
+    parse_forest_from(ctx, 0)
+}
+
+pub(crate) fn parse_forest_from<'tg, T: Token>(ctx: Ctx<'tg, 'tg, T>, start: usize) -> Result<Forest<'tg, T>, Error> {
+    if ctx.grammar.productions.len() <= start {
+        return Err(Error::GrammarIsEmpty)
+    }
+    let chart = Chart::build(&ctx, start);
+    Forest::from_chart(&chart).ok_or_else(|| Error::Syntax(chart.syntax_error()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use trim_margin::MarginTrimmable;
+
+    use crate::grammar::{Grammar, ExtGrammar};
+    use crate::parse::make_ctx;
+
+    use crate::error::Error;
+
+    use super::{parse_forest, DerivationCount};
+
+    fn grammar(text: &str) -> Grammar {
+        let g: ExtGrammar = text.trim_margin().unwrap().as_str().try_into().unwrap();
+        g.flatten()
+    }
+
+    fn tokens(t: &[&str]) -> Vec<String> {
+        t.iter().map(|x| String::from(*x)).collect()
+    }
+
+    #[test]
+    fn catalan_count_test() {
+        let g = grammar(r#"
+            |<e> ::= <e> "+" <e> | "n"
+        "#);
+        // number of binary trees with 6 leaves
+        let t = tokens(&[ "n", "+", "n", "+", "n", "+", "n", "+", "n", "+", "n" ]);
+        let forest = parse_forest(make_ctx(&g, &t, false, true)).unwrap();
+
+        assert!(forest.is_ambiguous());
+        assert_eq!(forest.count_derivations(), DerivationCount::Finite(42));
+        assert_eq!(forest.into_trees().count(), 42);
+    }
+
+    #[test]
+    fn shared_subtrees_test() {
+        let g = grammar(r#"
+            |<e> ::= <e> "+" <e> | "n"
+        "#);
+        let t: Vec<_> = std::iter::once("n")
+            .chain(std::iter::repeat_n([ "+", "n" ], 30).flatten())
+            .map(String::from)
+            .collect();
+        let forest = parse_forest(make_ctx(&g, &t, false, true)).unwrap();
+
+        // 30th catalan number, the forest itself stays cubic
+        assert_eq!(forest.count_derivations(), DerivationCount::Finite(3814986502092304));
+        assert!(forest.nodes.len() < t.len() * t.len() * t.len());
+        assert!(forest.into_trees().next().unwrap().is_ok());
+    }
+
+    #[test]
+    fn unambiguous_test() {
+        let g = grammar(r#"
+            |<b> ::= <a> | <b> "." <a>
+            |<a> ::= "N"
+        "#);
+        let t = tokens(&[ "N", ".", "N", ".", "N" ]);
+        let forest = parse_forest(make_ctx(&g, &t, false, true)).unwrap();
+
+        assert!(!forest.is_ambiguous());
+        assert_eq!(forest.count_derivations(), DerivationCount::Finite(1));
+        assert_eq!(
+            forest.into_trees().map(|t| format!("{:#}", t.unwrap())).collect::<Vec<_>>(),
+            vec![ "b\n`b\n``b\n```a\n````N\n``.\n``a\n```N\n`.\n`a\n``N\n".to_string() ]
+        );
+    }
+
+    #[test]
+    fn cycle_test() {
+        let g = grammar(r#"
+            |<a> ::= <b> | "x"
+            |<b> ::= <a>
+        "#);
+        let t = tokens(&[ "x" ]);
+        let forest = parse_forest(make_ctx(&g, &t, false, true)).unwrap();
+
+        assert_eq!(forest.count_derivations(), DerivationCount::Infinite);
+        assert_eq!(forest.into_trees().count(), 1);
+    }
+
+    #[test]
+    fn no_parse_test() {
+        let g = grammar(r#"
+            |<a> ::= "x" "y"
+        "#);
+        let t = tokens(&[ "y" ]);
+
+        match parse_forest(make_ctx(&g, &t, false, true)) {
+            Err(Error::Syntax(err)) => assert_eq!((err.position, err.found()), (0, Some("y"))),
+            _ => panic!("syntax error expected"),
+        }
+    }
+}
@@ -0,0 +1,21 @@
+/// remaining native stack below which recursion continues on a new segment
+const RED_ZONE: usize = 128 * 1024;
+/// size of every new stack segment
+const SEGMENT_SIZE: usize = 4 * 1024 * 1024;
+
+/// runs `f` on a fresh heap allocated stack segment if the current one is nearly exhausted,
+/// so derivation depth is limited by memory rather than by the thread stack size
+pub(crate) fn grow<R>(f: impl FnOnce() -> R) -> R {
+    stacker::maybe_grow(RED_ZONE, SEGMENT_SIZE, f)
+}
+
+/// nested lazy iterators recurse on `next`, this adapter grows the stack at every level
+pub(crate) struct Growing<I>(pub I);
+
+impl<I: Iterator> Iterator for Growing<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        grow(|| self.0.next())
+    }
+}
@@ -98,3 +98,53 @@ TEST(main_suite, memleak_test) {
 }
 
 
+
+TEST(main_suite, result_test) {
+    using namespace parser300b;
+
+    Grammar grammar = {
+        .productions = {
+            Production {
+                .lhs = "b",
+                .rhs = {
+                    Expression { .terms = { Term { .value = "a", .is_terminal = false, .is_optional = false } } },
+                    Expression {
+                        .terms = {
+                            Term { .value = "b", .is_terminal = false, .is_optional = false },
+                            Term { .value = ".", .is_terminal = true, .is_optional = false },
+                            Term { .value = "a", .is_terminal = false, .is_optional = false }
+                        }
+                    }
+                }
+            },
+            Production {
+                .lhs = "a",
+                .rhs = { Expression { .terms = { Term { .value = "N", .is_terminal = true, .is_optional = false } } } }
+            }
+        }
+    };
+
+    const std::vector<TestToken> tokens = {
+        TestToken { .name = "N" },
+        TestToken { .name = "." },
+        TestToken { .name = "N" }
+    };
+
+    const auto result = parse(grammar, tokens);
+    ASSERT_EQ(result.tree_count(), 1);
+    ASSERT_EQ(result.error_count(), 0);
+
+    const auto root = result.tree(0);
+    EXPECT_EQ(root.name(), "b");
+    ASSERT_EQ(root.child_count(), 3);
+    EXPECT_EQ(root.child(0).child(0).name(), "a");
+
+    const auto dot = root.child(1);
+    EXPECT_TRUE(dot.is_terminal());
+    EXPECT_EQ(dot.token_index(), 1);
+    EXPECT_EQ(&dot.token<TestToken>(), &tokens[1]);
+
+    const auto failed = parse(grammar, std::vector<TestToken> { TestToken { .name = "." } });
+    EXPECT_FALSE(failed.ok());
+    EXPECT_EQ(failed.error_count(), 1);
+}
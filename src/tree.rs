@@ -1,27 +1,185 @@
 
-use std::{fmt::Display, iter::once};
+use std::{fmt::Display, iter::once, collections::HashSet, ops::{Deref, DerefMut, Range}};
 
 use serde::{Serialize, ser::SerializeSeq};
 
 use crate::ctx::Token;
+use crate::lexer::Span;
+use crate::stack;
 
 
 #[derive(PartialEq, Debug, Clone, Eq)]
-pub enum ParseTreeNode<'t, 'g, T> 
-where
-    T: Sized + Token
-{
+pub enum ParseTreeNode<'t, 'g, T> {
     Terminal(&'t T),
     Nonterminal(ParseTree<'t, 'g, T>),
 }
 
-#[derive(PartialEq, Debug, Clone, Eq)]
-pub struct ParseTree<'t, 'g, T>
-where
-    T: Sized + Token
-{
+#[derive(PartialEq, Debug, Eq)]
+pub struct ParseTree<'t, 'g, T> {
     pub lhs: &'g String,
-    pub rhs: Vec<ParseTreeNode<'t, 'g, T>>,
+    pub rhs: Children<'t, 'g, T>,
+    /// indices of the tokens covered by the tree, empty at the position of an empty derivation
+    pub range: Range<usize>,
+}
+
+/// the nodes below a tree. dropping them works through the levels from a work-list,
+/// the generated drop would recurse once per level and overflow on very deep derivations
+#[derive(PartialEq, Debug, Clone, Eq)]
+pub struct Children<'t, 'g, T>(pub Vec<ParseTreeNode<'t, 'g, T>>);
+
+impl<'t, 'g, T> Drop for Children<'t, 'g, T> {
+    fn drop(&mut self) {
+        let mut nodes = std::mem::take(&mut self.0);
+        while let Some(node) = nodes.pop() {
+            if let ParseTreeNode::Nonterminal(mut tree) = node {
+                nodes.append(&mut tree.rhs.0);
+            }
+        }
+    }
+}
+
+impl<'t, 'g, T> Default for Children<'t, 'g, T> {
+    fn default() -> Self {
+        Children(vec![])
+    }
+}
+
+impl<'t, 'g, T> From<Vec<ParseTreeNode<'t, 'g, T>>> for Children<'t, 'g, T> {
+    fn from(nodes: Vec<ParseTreeNode<'t, 'g, T>>) -> Self {
+        Children(nodes)
+    }
+}
+
+impl<'t, 'g, T> Deref for Children<'t, 'g, T> {
+    type Target = Vec<ParseTreeNode<'t, 'g, T>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'t, 'g, T> DerefMut for Children<'t, 'g, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<'t, 'g, T> IntoIterator for Children<'t, 'g, T> {
+    type Item = ParseTreeNode<'t, 'g, T>;
+    type IntoIter = std::vec::IntoIter<ParseTreeNode<'t, 'g, T>>;
+
+    fn into_iter(mut self) -> Self::IntoIter {
+        std::mem::take(&mut self.0).into_iter()
+    }
+}
+
+impl<'a, 't, 'g, T> IntoIterator for &'a Children<'t, 'g, T> {
+    type Item = &'a ParseTreeNode<'t, 'g, T>;
+    type IntoIter = std::slice::Iter<'a, ParseTreeNode<'t, 'g, T>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+// cloning recurses once per tree level, which deep derivations would overflow
+impl<'t, 'g, T: Clone> Clone for ParseTree<'t, 'g, T> {
+    fn clone(&self) -> Self {
+        stack::grow(|| ParseTree { lhs: self.lhs, rhs: self.rhs.clone(), range: self.range.clone() })
+    }
+}
+
+impl<'t, 'g, T> ParseTree<'t, 'g, T> {
+    /// replaces nodes of the given (synthetic) productions with their children
+    pub(crate) fn splice(self, synthetic: &HashSet<String>) -> Self {
+        stack::grow(|| {
+            let mut rhs = vec![];
+            for node in self.rhs {
+                match node {
+                    ParseTreeNode::Nonterminal(tree) if synthetic.contains(tree.lhs) =>
+                        rhs.extend(tree.splice(synthetic).rhs),
+                    ParseTreeNode::Nonterminal(tree) => rhs.push(ParseTreeNode::Nonterminal(tree.splice(synthetic))),
+                    terminal => rhs.push(terminal),
+                }
+            }
+            ParseTree { lhs: self.lhs, rhs: rhs.into(), range: self.range }
+        })
+    }
+
+    /// preorder list of lhs, range and child count of the nonterminals, `None` for terminals.
+    /// trees of the same tokens are equal exactly if their shapes are
+    pub(crate) fn shape(&self) -> Vec<(Option<&'g str>, Range<usize>, usize)> {
+        let mut shape = vec![ (Some(self.lhs.as_str()), self.range.clone(), self.rhs.len()) ];
+        let mut stack: Vec<&ParseTreeNode<T>> = self.rhs.iter().rev().collect();
+        while let Some(node) = stack.pop() {
+            match node {
+                ParseTreeNode::Terminal(_) => shape.push((None, 0..0, 0)),
+                ParseTreeNode::Nonterminal(tree) => {
+                    shape.push((Some(tree.lhs.as_str()), tree.range.clone(), tree.rhs.len()));
+                    stack.extend(tree.rhs.iter().rev());
+                },
+            }
+        }
+        shape
+    }
+
+    /// drops nodes of the production `lhs` together with everything below them
+    pub(crate) fn prune(self, lhs: &str) -> Self {
+        stack::grow(|| {
+            let rhs = self.rhs
+                .into_iter()
+                .filter(|node| !matches!(node, ParseTreeNode::Nonterminal(tree) if tree.lhs == lhs))
+                .map(|node| match node {
+                    ParseTreeNode::Nonterminal(tree) => ParseTreeNode::Nonterminal(tree.prune(lhs)),
+                    terminal => terminal,
+                })
+                .collect::<Vec<_>>();
+            ParseTree { lhs: self.lhs, rhs: rhs.into(), range: self.range }
+        })
+    }
+}
+
+impl<'t, 'g, T> ParseTreeNode<'t, 'g, T> {
+    /// number of tokens covered by the node
+    pub(crate) fn token_count(&self) -> usize {
+        match self {
+            ParseTreeNode::Terminal(_) => 1,
+            ParseTreeNode::Nonterminal(tree) => tree.range.len(),
+        }
+    }
+}
+
+impl<'t, 'g, T: Token> ParseTreeNode<'t, 'g, T> {
+    /// source span of the node if its tokens have spans, `None` for an empty derivation
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            ParseTreeNode::Terminal(token) => token.span(),
+            ParseTreeNode::Nonterminal(tree) => tree.span(),
+        }
+    }
+}
+
+/// span of the first token with one, popping `stack` depth first. `backwards` finds the last one instead
+fn edge_span<'a, 't: 'a, 'g: 'a, T: Token>(mut stack: Vec<&'a ParseTreeNode<'t, 'g, T>>, backwards: bool) -> Option<Span> {
+    while let Some(node) = stack.pop() {
+        match node {
+            ParseTreeNode::Terminal(token) => if let Some(span) = token.span() {
+                return Some(span)
+            },
+            ParseTreeNode::Nonterminal(tree) if backwards => stack.extend(tree.rhs.iter()),
+            ParseTreeNode::Nonterminal(tree) => stack.extend(tree.rhs.iter().rev()),
+        }
+    }
+    None
+}
+
+impl<'t, 'g, T: Token> ParseTree<'t, 'g, T> {
+    /// from the start of the first token to the end of the last one, located at the first one
+    pub fn span(&self) -> Option<Span> {
+        let first = edge_span(self.rhs.iter().rev().collect(), false)?;
+        let last = edge_span(self.rhs.iter().collect(), true)?;
+        Some(Span { end: last.end, ..first })
+    }
 }
 
 impl<'t, 'g, T: Token + Serialize> Serialize for ParseTreeNode<'t, 'g, T> {
@@ -80,4 +238,121 @@ impl<'t, 'g, T: Token> Display for ParseTree<'t, 'g, T> {
             panic!("can not display ParseTree without #")
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::{ExtGrammar, Grammar};
+    use crate::parse::{make_ctx, parse_with, Engine};
+    use crate::lexer::{Lexer, LexToken, TokenRule, Span};
+
+    use super::{ParseTree, ParseTreeNode};
+
+    fn grammar(text: &str) -> Grammar {
+        let g: ExtGrammar = text.try_into().unwrap();
+        g.flatten()
+    }
+
+    fn tokens(t: &[&str]) -> Vec<String> {
+        t.iter().map(|t| t.to_string()).collect()
+    }
+
+    /// children follow each other without gaps and fill the range of their parent
+    fn check_ranges(tree: &ParseTree<String>) {
+        let mut position = tree.range.start;
+        for node in &tree.rhs {
+            if let ParseTreeNode::Nonterminal(child) = node {
+                assert_eq!(child.range.start, position, "{:#}", tree);
+                check_ranges(child);
+            }
+            position += node.token_count();
+        }
+        assert_eq!(position, tree.range.end, "{:#}", tree);
+    }
+
+    #[test]
+    fn range_test() {
+        let g = grammar(r#"
+            <list>  ::= "[" <items> "]"
+            <items> ::= ε | <item> <more>
+            <more>  ::= ε | "," <item> <more>
+            <item>  ::= "n" | <list>
+        "#);
+        let t = tokens(&[ "[", "n", ",", "[", "]", ",", "n", "]" ]);
+        let expected = parse_with(make_ctx(&g, &t, false, true), Engine::Backtracking).next().unwrap().unwrap();
+        check_ranges(&expected);
+        assert_eq!(expected.range, 0..8);
+        let ParseTreeNode::Nonterminal(items) = &expected.rhs[1] else { panic!("{:#}", expected) };
+        assert_eq!(items.range, 1..7);
+        for engine in [ Engine::Earley, Engine::Ll1, Engine::Lalr, Engine::Lr1, Engine::Glr, Engine::Cyk ] {
+            let tree = parse_with(make_ctx(&g, &t, false, true), engine).next().unwrap().unwrap();
+            assert_eq!(tree, expected, "{:?}", engine);
+        }
+
+        let g = grammar(r#"
+            <e> ::= <e> "+" <t> | <t>
+            <t> ::= "n" | "(" <e> ")"
+        "#);
+        let t = tokens(&[ "n", "+", "(", "n", "+", "n", ")", "+", "n" ]);
+        let free = g.eliminate_left_recursion().unwrap();
+        let restored = free.restore(parse_with(make_ctx(&free.grammar, &t, false, true), Engine::Earley).next().unwrap().unwrap()).unwrap();
+        check_ranges(&restored);
+        assert_eq!(restored, parse_with(make_ctx(&g, &t, false, true), Engine::Earley).next().unwrap().unwrap());
+    }
+
+    #[test]
+    fn span_test() {
+        let g = grammar(r#"
+            <sum> ::= <num> "+" <num> | <num>
+            <num> ::= NUM
+        "#);
+        let lexer = Lexer::from_grammar(&g, vec![
+            TokenRule::regex("ws", r"\s+").skipped(),
+            TokenRule::regex("NUM", "[0-9]+"),
+        ]).unwrap();
+        let t = lexer.tokenize(" 12 +\n  345").unwrap();
+        let tree = parse_with(make_ctx(&g, &t, false, true), Engine::Earley).next().unwrap().unwrap();
+        assert_eq!(tree.span(), Some(Span { begin: 1, end: 11, line: 1, column: 2 }));
+        assert_eq!(tree.rhs[2].span(), Some(Span { begin: 8, end: 11, line: 2, column: 3 }));
+
+        let t = tokens(&[ "NUM" ]);
+        let tree = parse_with(make_ctx(&g, &t, false, true), Engine::Earley).next().unwrap().unwrap();
+        assert_eq!((tree.span(), tree.range.clone()), (None, 0..1));
+
+        // a deep tree is walked without recursion
+        let lhs = "a".to_string();
+        let first = LexToken { name: "x".to_string(), text: "x".to_string(), span: Span { begin: 0, end: 1, line: 1, column: 1 } };
+        let last = LexToken { span: Span { begin: 2, end: 3, line: 1, column: 3 }, ..first.clone() };
+        let mut tree = ParseTree { lhs: &lhs, rhs: vec![ ParseTreeNode::Terminal(&first) ].into(), range: 0..1 };
+        for _ in 0..1_000_000 {
+            tree = ParseTree { lhs: &lhs, rhs: vec![ ParseTreeNode::Nonterminal(tree) ].into(), range: 0..1 };
+        }
+        let tree = ParseTree { lhs: &lhs, rhs: vec![ ParseTreeNode::Nonterminal(tree), ParseTreeNode::Terminal(&last) ].into(), range: 0..2 };
+        assert_eq!(tree.span(), Some(Span { begin: 0, end: 3, line: 1, column: 1 }));
+    }
+
+    #[test]
+    fn drop_test() {
+        let lhs = "a".to_string();
+        let token = "x".to_string();
+        let mut tree = ParseTree { lhs: &lhs, rhs: vec![ ParseTreeNode::Terminal(&token) ].into(), range: 0..1 };
+        for _ in 0..1_000_000 {
+            tree = ParseTree { lhs: &lhs, rhs: vec![ ParseTreeNode::Nonterminal(tree) ].into(), range: 0..1 };
+        }
+        let ParseTree { lhs, rhs, range } = tree;
+        assert_eq!((lhs.as_str(), rhs.len(), range), ("a", 1, 0..1));
+        drop(ParseTree { lhs, rhs, range: 0..1 });
+    }
+
+    #[test]
+    fn deep_lalr_drop_test() {
+        // a 2 MB thread overflows on a drop that recurses once per level
+        std::thread::Builder::new().stack_size(2 << 20).spawn(|| {
+            let g = grammar(r#"<l> ::= "a" <l> | "a""#);
+            let t = vec![ "a".to_string(); 100_000 ];
+            let tree = parse_with(make_ctx(&g, &t, false, true), Engine::Lalr).next().unwrap().unwrap();
+            assert_eq!(tree.range, 0..100_000);
+            drop(tree);
+        }).unwrap().join().unwrap();
+    }
+}
@@ -0,0 +1,323 @@
+use std::{collections::{HashMap, HashSet}, fmt::Display};
+
+use crate::grammar::*;
+use crate::earley::{production_lookup, nullable_productions};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// parsing can not work as intended
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    /// `G001` nonterminal used in a rhs without a production
+    UndefinedNonterminal {
+        name: String,
+        /// lhs of the production referencing it
+        production: String,
+    },
+    /// `G002` more than one production with the same lhs. only the first one is used by the engines
+    DuplicateLhs {
+        name: String,
+        /// index of the ignored production
+        index: usize,
+    },
+    /// `G003` production which can not be reached from the start production
+    Unreachable {
+        name: String,
+    },
+    /// `G004` nonterminal which derives no terminal string
+    Unproductive {
+        name: String,
+    },
+    /// `G005` nonterminals deriving themselves through nullable symbols only, making the grammar infinitely ambiguous
+    NullableCycle {
+        cycle: Vec<String>,
+    },
+}
+
+impl Diagnostic {
+    /// stable identifier of the diagnostic kind
+    pub fn code(&self) -> &'static str {
+        match self {
+            Diagnostic::UndefinedNonterminal { .. } => "G001",
+            Diagnostic::DuplicateLhs { .. } => "G002",
+            Diagnostic::Unreachable { .. } => "G003",
+            Diagnostic::Unproductive { .. } => "G004",
+            Diagnostic::NullableCycle { .. } => "G005",
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            Diagnostic::UndefinedNonterminal { .. } => Severity::Error,
+            _ => Severity::Warning,
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity() {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        f.write_fmt(format_args!("{}[{}]: ", severity, self.code()))?;
+        match self {
+            Diagnostic::UndefinedNonterminal { name, production } =>
+                f.write_fmt(format_args!("<{}> used in <{}> is not defined", name, production)),
+            Diagnostic::DuplicateLhs { name, index } =>
+                f.write_fmt(format_args!("<{}> is defined more than once, production {} is ignored", name, index)),
+            Diagnostic::Unreachable { name } =>
+                f.write_fmt(format_args!("<{}> is not reachable from the start production", name)),
+            Diagnostic::Unproductive { name } =>
+                f.write_fmt(format_args!("<{}> does not derive any terminal string", name)),
+            Diagnostic::NullableCycle { cycle } => f.write_fmt(format_args!(
+                "{} derive themselves through nullable symbols",
+                cycle.iter().map(|n| format!("<{}>", n)).collect::<Vec<_>>().join(" -> ")
+            )),
+        }
+    }
+}
+
+fn nonterminals(expression: &Expression) -> impl Iterator<Item = &String> {
+    expression.terms.iter().filter_map(|t| match t {
+        Term::Terminal(_) => None,
+        Term::Nonterminal(n) => Some(n),
+    })
+}
+
+fn productive_productions(grammar: &Grammar, lookup: &HashMap<&str, usize>) -> Vec<bool> {
+    let mut productive = vec![false; grammar.productions.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, p) in grammar.productions.iter().enumerate() {
+            if productive[i] {
+                continue;
+            }
+            let is_productive = p.rhs.iter().any(|e| nonterminals(e)
+                .all(|n| lookup.get(n.as_str()).is_some_and(|&j| productive[j])));
+            if is_productive {
+                productive[i] = true;
+                changed = true;
+            }
+        }
+    }
+    productive
+}
+
+/// strongly connected components of the graph `a -> b` where `a` derives `α b β` with nullable `α` and `β`
+fn nullable_cycles(grammar: &Grammar, lookup: &HashMap<&str, usize>, nullable: &[bool]) -> Vec<Vec<usize>> {
+    let is_nullable = |t: &Term| match t {
+        Term::Terminal(_) => false,
+        Term::Nonterminal(n) => lookup.get(n.as_str()).is_some_and(|&j| nullable[j]),
+    };
+    let edges: Vec<Vec<usize>> = grammar.productions.iter().map(|p| {
+        let mut result = vec![];
+        for e in &p.rhs {
+            for (i, t) in e.terms.iter().enumerate() {
+                let Term::Nonterminal(n) = t else { continue };
+                let Some(&j) = lookup.get(n.as_str()) else { continue };
+                let rest_nullable = e.terms.iter().enumerate().all(|(k, t)| k == i || is_nullable(t));
+                if rest_nullable && !result.contains(&j) {
+                    result.push(j);
+                }
+            }
+        }
+        result
+    }).collect();
+
+    // tarjan
+    struct State<'e> {
+        edges: &'e [Vec<usize>],
+        index: Vec<Option<usize>>,
+        low: Vec<usize>,
+        stack: Vec<usize>,
+        on_stack: Vec<bool>,
+        next: usize,
+        components: Vec<Vec<usize>>,
+    }
+
+    fn connect(s: &mut State, v: usize) {
+        s.index[v] = Some(s.next);
+        s.low[v] = s.next;
+        s.next += 1;
+        s.stack.push(v);
+        s.on_stack[v] = true;
+        for &w in &s.edges[v] {
+            match s.index[w] {
+                None => {
+                    connect(s, w);
+                    s.low[v] = s.low[v].min(s.low[w]);
+                },
+                Some(i) if s.on_stack[w] => s.low[v] = s.low[v].min(i),
+                _ => {},
+            }
+        }
+        if Some(s.low[v]) == s.index[v] {
+            let mut component = vec![];
+            while let Some(w) = s.stack.pop() {
+                s.on_stack[w] = false;
+                component.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            if component.len() > 1 || s.edges[v].contains(&v) {
+                component.sort();
+                s.components.push(component);
+            }
+        }
+    }
+
+    let len = grammar.productions.len();
+    let mut state = State {
+        edges: &edges,
+        index: vec![None; len],
+        low: vec![0; len],
+        stack: vec![],
+        on_stack: vec![false; len],
+        next: 0,
+        components: vec![],
+    };
+    for v in 0..len {
+        if state.index[v].is_none() && lookup.get(grammar.productions[v].lhs.as_str()) == Some(&v) {
+            connect(&mut state, v);
+        }
+    }
+    state.components.sort();
+    state.components
+}
+
+impl Grammar {
+    /// static checks of the grammar, ordered by diagnostic code.
+    /// the first production is the start production
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        self.diagnostics(if self.productions.is_empty() { None } else { Some(0) })
+    }
+
+    /// same as `validate` with reachability checked from the nonterminal `start`
+    pub fn validate_from(&self, start: &str) -> Vec<Diagnostic> {
+        self.diagnostics(production_lookup(self).get(start).copied())
+    }
+
+    fn diagnostics(&self, start: Option<usize>) -> Vec<Diagnostic> {
+        let lookup = production_lookup(self);
+        let mut result = vec![];
+
+        for p in &self.productions {
+            let mut reported = HashSet::new();
+            for n in p.rhs.iter().flat_map(nonterminals) {
+                if !lookup.contains_key(n.as_str()) && reported.insert(n) {
+                    result.push(Diagnostic::UndefinedNonterminal { name: n.clone(), production: p.lhs.clone() });
+                }
+            }
+        }
+
+        for (index, p) in self.productions.iter().enumerate() {
+            if lookup[p.lhs.as_str()] != index {
+                result.push(Diagnostic::DuplicateLhs { name: p.lhs.clone(), index });
+            }
+        }
+
+        let mut reachable = vec![false; self.productions.len()];
+        let mut stack: Vec<usize> = start.into_iter().collect();
+        while let Some(i) = stack.pop() {
+            if reachable[i] {
+                continue;
+            }
+            reachable[i] = true;
+            stack.extend(self.productions[i].rhs.iter().flat_map(nonterminals).filter_map(|n| lookup.get(n.as_str())));
+        }
+        for (i, p) in self.productions.iter().enumerate() {
+            if start.is_some() && !reachable[i] && lookup[p.lhs.as_str()] == i {
+                result.push(Diagnostic::Unreachable { name: p.lhs.clone() });
+            }
+        }
+
+        let productive = productive_productions(self, &lookup);
+        for (i, p) in self.productions.iter().enumerate() {
+            if !productive[i] && lookup[p.lhs.as_str()] == i {
+                result.push(Diagnostic::Unproductive { name: p.lhs.clone() });
+            }
+        }
+
+        let nullable = nullable_productions(self, &lookup);
+        for cycle in nullable_cycles(self, &lookup, &nullable) {
+            result.push(Diagnostic::NullableCycle {
+                cycle: cycle.into_iter().map(|i| self.productions[i].lhs.clone()).collect()
+            });
+        }
+
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::{ExtGrammar, Grammar};
+
+    use super::{Diagnostic, Severity};
+
+    fn grammar(text: &str) -> Grammar {
+        let g: ExtGrammar = text.try_into().unwrap();
+        g.flatten()
+    }
+
+    #[test]
+    fn valid_test() {
+        let g = grammar(r#"
+            <block> ::= <subs> | <subs> ";" <block>
+            <subs>  ::= "ID" "=" <rhs>?
+            <rhs>   ::= "W" | <rhs> "." "W"
+        "#);
+        assert_eq!(g.validate(), vec![]);
+    }
+
+    #[test]
+    fn diagnostics_test() {
+        let g = grammar(r#"
+            <a> ::= <b> <x> | <c> | "a"
+            <b> ::= "b" | <b> <x> <y>
+            <c> ::= <c> "c"
+            <b> ::= "bb"
+            <d> ::= "d"
+        "#);
+        assert_eq!(g.validate(), vec![
+            Diagnostic::UndefinedNonterminal { name: "x".to_string(), production: "a".to_string() },
+            Diagnostic::UndefinedNonterminal { name: "x".to_string(), production: "b".to_string() },
+            Diagnostic::UndefinedNonterminal { name: "y".to_string(), production: "b".to_string() },
+            Diagnostic::DuplicateLhs { name: "b".to_string(), index: 3 },
+            Diagnostic::Unreachable { name: "d".to_string() },
+            Diagnostic::Unproductive { name: "c".to_string() },
+        ]);
+        assert_eq!(g.validate()[0].severity(), Severity::Error);
+        assert!(!g.validate_from("d").contains(&Diagnostic::Unreachable { name: "d".to_string() }));
+        assert!(g.validate_from("d").contains(&Diagnostic::Unreachable { name: "a".to_string() }));
+        assert_eq!(
+            format!("{}", g.validate()[3]),
+            "warning[G002]: <b> is defined more than once, production 3 is ignored"
+        );
+    }
+
+    #[test]
+    fn nullable_cycle_test() {
+        let g = grammar(r#"
+            <s> ::= <a> "x"
+            <a> ::= <b> <n> | "a"
+            <b> ::= <n> <a> | "b"
+            <n> ::= "n"?
+        "#);
+        let diagnostics = g.validate();
+        assert_eq!(diagnostics, vec![
+            Diagnostic::NullableCycle { cycle: vec![ "a".to_string(), "b".to_string() ] },
+        ]);
+        assert_eq!(diagnostics[0].code(), "G005");
+        assert_eq!(format!("{}", diagnostics[0]), "warning[G005]: <a> -> <b> derive themselves through nullable symbols");
+    }
+}